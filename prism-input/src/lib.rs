@@ -1,25 +1,293 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 pub use winit;
+use winit::event::{ElementState, MouseButton, MouseScrollDelta, WindowEvent};
+use winit::keyboard::{KeyCode, PhysicalKey};
 
+// pixel scroll deltas (touchpads) are turned into lines with this
+const PIXELS_PER_SCROLL_LINE: f32 = 20.0;
+
+/*
+Where a button is in the current frame, relative to the last one.
+Pressed is also given for a press and release both within the frame, Released follows next frame.
+Unknown is for buttons up in both frames.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum InputKeyState {
+  #[default]
   Unknown,
   Pressed,
   Held,
   Released,
 }
 
+impl InputKeyState {
+  fn from_frames(down_last: bool, down_now: bool, pressed_in_frame: bool) -> Self {
+    match (down_last, down_now) {
+      (false, true) => InputKeyState::Pressed,
+      (false, false) if pressed_in_frame => InputKeyState::Pressed,
+      (true, true) => InputKeyState::Held,
+      (true, false) => InputKeyState::Released,
+      (false, false) => InputKeyState::Unknown,
+    }
+  }
+
+  // Pressed or Held
+  pub fn is_down(self) -> bool {
+    matches!(self, InputKeyState::Pressed | InputKeyState::Held)
+  }
+}
+
+// keys are by physical position, so bindings stay put across keyboard layouts
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InputButton {
+  Key(KeyCode),
+  Mouse(MouseButton),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AxisBinding {
+  // 1 while positive is down, -1 while negative is, 0 for both or neither
+  Buttons { positive: InputButton, negative: InputButton },
+  // lines scrolled this frame, right and up are positive
+  ScrollX,
+  ScrollY,
+}
+
+/*
+Turns window events into per frame button states and named actions.
+Feed it every event of the focused window with process_event, read the states, then call
+end_frame once per frame so the next frame's transitions are against this one.
+ */
 pub struct InputManager {
-  keys_state_last: HashMap<winit::event::VirtualKeyCode, InputKeyState>,
-  keys_state_now: HashMap<winit::event::VirtualKeyCode, InputKeyState>,
+  keys_state_last: HashSet<InputButton>,
+  keys_state_now: HashSet<InputButton>,
+  // went down during the frame, kept even if they're already up again
+  keys_pressed_in_frame: HashSet<InputButton>,
+  scroll_lines: (f32, f32),
+  actions: HashMap<String, Vec<InputButton>>,
+  axes: HashMap<String, Vec<AxisBinding>>,
+}
+
+impl Default for InputManager {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl InputManager {
   pub fn new() -> Self {
     Self {
-      keys_state_last: HashMap::with_capacity(256),
-      keys_state_now: HashMap::with_capacity(256),
+      keys_state_last: HashSet::with_capacity(256),
+      keys_state_now: HashSet::with_capacity(256),
+      keys_pressed_in_frame: HashSet::with_capacity(16),
+      scroll_lines: (0.0, 0.0),
+      actions: HashMap::new(),
+      axes: HashMap::new(),
+    }
+  }
+
+  // the action is down while any of its buttons are, replaces earlier bindings of name
+  pub fn bind_action(&mut self, name: &str, buttons: &[InputButton]) {
+    self.actions.insert(name.to_string(), buttons.to_vec());
+  }
+
+  // the axis is the sum of its bindings, replaces earlier bindings of name
+  pub fn bind_axis(&mut self, name: &str, bindings: &[AxisBinding]) {
+    self.axes.insert(name.to_string(), bindings.to_vec());
+  }
+
+  pub fn process_event(&mut self, event: &WindowEvent) {
+    match event {
+      WindowEvent::KeyboardInput { event, .. } => {
+        // repeats come in as more presses of a held key
+        if let PhysicalKey::Code(key_code) = event.physical_key {
+          self.set_button(InputButton::Key(key_code), event.state);
+        }
+      }
+      WindowEvent::MouseInput { state, button, .. } => {
+        self.set_button(InputButton::Mouse(*button), *state);
+      }
+      WindowEvent::MouseWheel { delta, .. } => {
+        self.add_scroll(*delta);
+      }
+      // releases go to the newly focused window, so nothing would lift the held buttons
+      WindowEvent::Focused(false) => self.keys_state_now.clear(),
+      _ => {}
+    }
+  }
+
+  fn set_button(&mut self, button: InputButton, state: ElementState) {
+    match state {
+      ElementState::Pressed => {
+        if self.keys_state_now.insert(button) {
+          self.keys_pressed_in_frame.insert(button);
+        }
+      }
+      ElementState::Released => {
+        self.keys_state_now.remove(&button);
+      }
     }
   }
 
-  pub fn process_event(&mut self, event: winit::event::DeviceEvent) {}
+  fn add_scroll(&mut self, delta: MouseScrollDelta) {
+    let (x, y) = match delta {
+      MouseScrollDelta::LineDelta(x, y) => (x, y),
+      MouseScrollDelta::PixelDelta(position) => {
+        (position.x as f32 / PIXELS_PER_SCROLL_LINE, position.y as f32 / PIXELS_PER_SCROLL_LINE)
+      }
+    };
+    self.scroll_lines.0 += x;
+    self.scroll_lines.1 += y;
+  }
+
+  // the frame's input has been read, transitions from here on are against this frame
+  pub fn end_frame(&mut self) {
+    // a button pressed and released within the frame is reported released next frame
+    self.keys_state_last =
+      self.keys_state_now.union(&self.keys_pressed_in_frame).copied().collect();
+    self.keys_pressed_in_frame.clear();
+    self.scroll_lines = (0.0, 0.0);
+  }
+
+  pub fn button_state(&self, button: InputButton) -> InputKeyState {
+    InputKeyState::from_frames(
+      self.keys_state_last.contains(&button),
+      self.keys_state_now.contains(&button),
+      self.keys_pressed_in_frame.contains(&button),
+    )
+  }
+
+  pub fn key_state(&self, key_code: KeyCode) -> InputKeyState {
+    self.button_state(InputButton::Key(key_code))
+  }
+
+  // all of the action's buttons taken as one, Unknown for unbound actions
+  pub fn action_state(&self, name: &str) -> InputKeyState {
+    let Some(buttons) = self.actions.get(name) else {
+      return InputKeyState::Unknown;
+    };
+    let any = |set: &HashSet<InputButton>| buttons.iter().any(|button| set.contains(button));
+    InputKeyState::from_frames(
+      any(&self.keys_state_last),
+      any(&self.keys_state_now),
+      any(&self.keys_pressed_in_frame),
+    )
+  }
+
+  /*
+  Button bindings together are clamped to -1..1, scroll lines are added on top.
+  0 for unbound axes.
+   */
+  pub fn axis(&self, name: &str) -> f32 {
+    let Some(bindings) = self.axes.get(name) else {
+      return 0.0;
+    };
+    let down = |button: &InputButton| self.keys_state_now.contains(button) as i32 as f32;
+    let (buttons, scroll) =
+      bindings.iter().fold((0.0, 0.0), |(buttons, scroll), binding| match binding {
+        AxisBinding::Buttons { positive, negative } => {
+          (buttons + down(positive) - down(negative), scroll)
+        }
+        AxisBinding::ScrollX => (buttons, scroll + self.scroll_lines.0),
+        AxisBinding::ScrollY => (buttons, scroll + self.scroll_lines.1),
+      });
+    f32::clamp(buttons, -1.0, 1.0) + scroll
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SPACE: InputButton = InputButton::Key(KeyCode::Space);
+  const CLICK: InputButton = InputButton::Mouse(MouseButton::Left);
+
+  #[test]
+  fn press_hold_release_across_frames() {
+    let mut input = InputManager::new();
+    assert_eq!(input.button_state(SPACE), InputKeyState::Unknown);
+    input.set_button(SPACE, ElementState::Pressed);
+    assert_eq!(input.button_state(SPACE), InputKeyState::Pressed);
+    input.end_frame();
+    assert_eq!(input.button_state(SPACE), InputKeyState::Held);
+    // repeats of a held key don't press it again
+    input.set_button(SPACE, ElementState::Pressed);
+    assert_eq!(input.button_state(SPACE), InputKeyState::Held);
+    input.end_frame();
+    input.set_button(SPACE, ElementState::Released);
+    assert_eq!(input.button_state(SPACE), InputKeyState::Released);
+    input.end_frame();
+    assert_eq!(input.button_state(SPACE), InputKeyState::Unknown);
+  }
+
+  #[test]
+  fn press_and_release_within_a_frame() {
+    let mut input = InputManager::new();
+    input.set_button(SPACE, ElementState::Pressed);
+    input.set_button(SPACE, ElementState::Released);
+    assert_eq!(input.button_state(SPACE), InputKeyState::Pressed);
+    input.end_frame();
+    assert_eq!(input.button_state(SPACE), InputKeyState::Released);
+    input.end_frame();
+    assert_eq!(input.button_state(SPACE), InputKeyState::Unknown);
+  }
+
+  #[test]
+  fn focus_loss_releases_held_buttons() {
+    let mut input = InputManager::new();
+    input.set_button(SPACE, ElementState::Pressed);
+    input.set_button(CLICK, ElementState::Pressed);
+    input.end_frame();
+    input.process_event(&WindowEvent::Focused(false));
+    assert_eq!(input.button_state(SPACE), InputKeyState::Released);
+    assert_eq!(input.button_state(CLICK), InputKeyState::Released);
+    input.end_frame();
+    assert_eq!(input.button_state(SPACE), InputKeyState::Unknown);
+  }
+
+  #[test]
+  fn action_takes_its_buttons_as_one() {
+    let mut input = InputManager::new();
+    input.bind_action("jump", &[SPACE, CLICK]);
+    assert_eq!(input.action_state("jump"), InputKeyState::Unknown);
+    input.set_button(SPACE, ElementState::Pressed);
+    assert_eq!(input.action_state("jump"), InputKeyState::Pressed);
+    input.end_frame();
+    // a second button going down keeps the action held
+    input.set_button(CLICK, ElementState::Pressed);
+    input.set_button(SPACE, ElementState::Released);
+    assert_eq!(input.action_state("jump"), InputKeyState::Held);
+    input.end_frame();
+    input.set_button(CLICK, ElementState::Released);
+    assert_eq!(input.action_state("jump"), InputKeyState::Released);
+    assert_eq!(input.action_state("unbound"), InputKeyState::Unknown);
+  }
+
+  #[test]
+  fn axis_clamps_buttons_and_adds_scroll() {
+    let mut input = InputManager::new();
+    let right = InputButton::Key(KeyCode::KeyD);
+    let left = InputButton::Key(KeyCode::KeyA);
+    let arrow_right = InputButton::Key(KeyCode::ArrowRight);
+    input.bind_axis(
+      "move_x",
+      &[
+        AxisBinding::Buttons { positive: right, negative: left },
+        AxisBinding::Buttons { positive: arrow_right, negative: left },
+      ],
+    );
+    input.bind_axis("zoom", &[AxisBinding::ScrollY]);
+    input.set_button(right, ElementState::Pressed);
+    input.set_button(arrow_right, ElementState::Pressed);
+    assert_eq!(input.axis("move_x"), 1.0);
+    input.set_button(left, ElementState::Pressed);
+    assert_eq!(input.axis("move_x"), 0.0);
+
+    input.add_scroll(MouseScrollDelta::LineDelta(0.0, 1.5));
+    input.add_scroll(MouseScrollDelta::PixelDelta((0.0, PIXELS_PER_SCROLL_LINE as f64).into()));
+    assert_eq!(input.axis("zoom"), 2.5);
+    input.end_frame();
+    assert_eq!(input.axis("zoom"), 0.0);
+    assert_eq!(input.axis("unbound"), 0.0);
+  }
 }
@@ -10,16 +10,20 @@ pub struct Camera3D {
 }
 
 impl Camera3D {
+  /*
+  Vulkan clip space: y points down and depth goes 0 at the near plane to 1 at the far one.
+  Up stays up on screen, so counter clockwise faces keep facing the camera.
+   */
   pub fn get_perspective_matrix(&self) -> glam::Mat4 {
     let f = 1f32 / (self.info.z / 2f32).tan();
     glam::Mat4 {
       x_axis: glam::Vec4::new(f / self.info.w, 0f32, 0f32, 0f32),
-      y_axis: glam::Vec4::new(0f32, f, 0f32, 0f32),
+      y_axis: glam::Vec4::new(0f32, -f, 0f32, 0f32),
       z_axis: glam::Vec4::new(
         0f32,
         0f32,
-        (self.info.x + self.info.y) / (self.info.x - self.info.y),
-        (2f32 * self.info.x * self.info.y) / (self.info.x - self.info.y),
+        self.info.y / (self.info.x - self.info.y),
+        (self.info.x * self.info.y) / (self.info.x - self.info.y),
       ),
       w_axis: glam::Vec4::new(0f32, 0f32, -1f32, 0f32),
     }
@@ -49,7 +53,33 @@ impl Camera3D {
   }
 }
 
+// std140 layout of the CameraTransform uniform block in g_buffer.vert
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
 pub struct CameraTransforms {
   pub view: glam::Mat4,
   pub proj: glam::Mat4,
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn perspective_maps_to_vulkan_clip_space() {
+    let camera = Camera3D {
+      eye: glam::Vec4::new(0.0, 0.0, 0.0, 1.0),
+      dir: glam::Vec4::new(0.0, 0.0, -1.0, 0.0),
+      up: glam::Vec4::new(0.0, 1.0, 0.0, 0.0),
+      info: glam::Vec4::new(0.1, 100.0, 1.0, 1.0),
+    };
+    let ndc = |view: glam::Vec3| {
+      let clip = camera.get_perspective_matrix() * view.extend(1.0);
+      clip.truncate() / clip.w
+    };
+    assert!(ndc(glam::Vec3::new(0.0, 0.0, -0.1)).z.abs() < 1e-5);
+    assert!((ndc(glam::Vec3::new(0.0, 0.0, -100.0)).z - 1.0).abs() < 1e-5);
+    // above the view direction is the top of the image
+    assert!(ndc(glam::Vec3::new(0.0, 1.0, -5.0)).y < 0.0);
+  }
+}
@@ -31,7 +31,7 @@ impl Vertex {
         binding: 0,
         location: 1,
         format: vk::Format::R32G32B32A32_SFLOAT,
-        offset: 1 * 4 * 4,
+        offset: 4 * 4,
       },
       vk::VertexInputAttributeDescription {
         binding: 0,
@@ -251,8 +251,7 @@ impl Mesh {
     self
       .faces
       .iter()
-      .map(|x| x.vertices)
-      .flatten()
+      .flat_map(|x| x.vertices)
       .collect::<Vec<u32>>()
   }
 }
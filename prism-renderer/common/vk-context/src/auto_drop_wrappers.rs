@@ -1,5 +1,6 @@
 use ash::vk;
 use gpu_allocator::vulkan::{Allocation, AllocationCreateDesc, AllocationScheme, Allocator};
+use crate::descriptors::{DescriptorAllocator, DescriptorWriteBuilder};
 use std::sync::{Arc, Mutex};
 
 pub struct AdSemaphore {
@@ -94,6 +95,12 @@ impl AdCommandBuffer {
     }
   }
 
+  pub fn next_subpass(&self, subpass_contents: vk::SubpassContents) {
+    unsafe {
+      self.device.cmd_next_subpass(self.inner, subpass_contents);
+    }
+  }
+
   pub fn end_render_pass(&self) {
     unsafe {
       self.device.cmd_end_render_pass(self.inner);
@@ -132,6 +139,82 @@ impl AdCommandBuffer {
     }
   }
 
+  pub fn bind_descriptor_sets(
+    &self,
+    pipeline_bind_point: vk::PipelineBindPoint,
+    layout: vk::PipelineLayout,
+    first_set: u32,
+    descriptor_sets: &[vk::DescriptorSet],
+    dynamic_offsets: &[u32],
+  ) {
+    unsafe {
+      self.device.cmd_bind_descriptor_sets(
+        self.inner,
+        pipeline_bind_point,
+        layout,
+        first_set,
+        descriptor_sets,
+        dynamic_offsets
+      );
+    }
+  }
+
+  pub fn set_viewport(&self, viewports: &[vk::Viewport]) {
+    unsafe {
+      self.device.cmd_set_viewport(self.inner, 0, viewports);
+    }
+  }
+
+  pub fn set_scissor(&self, scissors: &[vk::Rect2D]) {
+    unsafe {
+      self.device.cmd_set_scissor(self.inner, 0, scissors);
+    }
+  }
+
+  pub fn draw(&self, vertex_count: u32, instance_count: u32, first_vertex: u32, first_instance: u32) {
+    unsafe {
+      self.device.cmd_draw(self.inner, vertex_count, instance_count, first_vertex, first_instance);
+    }
+  }
+
+  pub fn push_constants(
+    &self,
+    layout: vk::PipelineLayout,
+    stage_flags: vk::ShaderStageFlags,
+    offset: u32,
+    constants: &[u8],
+  ) {
+    unsafe {
+      self.device.cmd_push_constants(self.inner, layout, stage_flags, offset, constants);
+    }
+  }
+
+  pub fn dispatch(&self, group_count_x: u32, group_count_y: u32, group_count_z: u32) {
+    unsafe {
+      self.device.cmd_dispatch(self.inner, group_count_x, group_count_y, group_count_z);
+    }
+  }
+
+  pub fn draw_indexed(
+    &self,
+    index_count: u32,
+    instance_count: u32,
+    first_index: u32,
+    vertex_offset: i32,
+    first_instance: u32,
+  ) {
+    unsafe {
+      self.device.cmd_draw_indexed(
+        self.inner,
+        index_count,
+        instance_count,
+        first_index,
+        vertex_offset,
+        first_instance
+      );
+    }
+  }
+
   pub fn pipeline_barrier(
     &self,
     src_stage: vk::PipelineStageFlags,
@@ -176,6 +259,29 @@ impl AdCommandBuffer {
     }
   }
 
+  pub fn clear_color_image(
+    &self,
+    image: vk::Image,
+    image_layout: vk::ImageLayout,
+    color: vk::ClearColorValue,
+    ranges: &[vk::ImageSubresourceRange],
+  ) {
+    unsafe {
+      self.device.cmd_clear_color_image(self.inner, image, image_layout, &color, ranges);
+    }
+  }
+
+  pub fn copy_buffer(
+    &self,
+    src_buffer: vk::Buffer,
+    dst_buffer: vk::Buffer,
+    regions: &[vk::BufferCopy],
+  ) {
+    unsafe {
+      self.device.cmd_copy_buffer(self.inner, src_buffer, dst_buffer, regions);
+    }
+  }
+
   pub fn copy_buffer_to_image(
     &self,
     src_buffer: vk::Buffer,
@@ -193,6 +299,41 @@ impl AdCommandBuffer {
       );
     }
   }
+
+  pub fn copy_image_to_buffer(
+    &self,
+    src_image: vk::Image,
+    src_image_layout: vk::ImageLayout,
+    dst_buffer: vk::Buffer,
+    regions: &[vk::BufferImageCopy],
+  ) {
+    unsafe {
+      self.device.cmd_copy_image_to_buffer(
+        self.inner,
+        src_image,
+        src_image_layout,
+        dst_buffer,
+        regions
+      );
+    }
+  }
+
+  pub fn reset_query_pool(&self, query_pool: vk::QueryPool, first_query: u32, query_count: u32) {
+    unsafe {
+      self.device.cmd_reset_query_pool(self.inner, query_pool, first_query, query_count);
+    }
+  }
+
+  pub fn write_timestamp(
+    &self,
+    stage: vk::PipelineStageFlags,
+    query_pool: vk::QueryPool,
+    query: u32,
+  ) {
+    unsafe {
+      self.device.cmd_write_timestamp(self.inner, stage, query_pool, query);
+    }
+  }
 }
 
 impl Drop for AdCommandBuffer {
@@ -203,6 +344,133 @@ impl Drop for AdCommandBuffer {
   }
 }
 
+pub struct AdQueryPool {
+  pub(crate) device: Arc<ash::Device>,
+  pub inner: vk::QueryPool,
+}
+
+impl Drop for AdQueryPool {
+  fn drop(&mut self) {
+    unsafe {
+      self.device.destroy_query_pool(self.inner, None);
+    }
+  }
+}
+
+pub struct AdSampler {
+  pub(crate) device: Arc<ash::Device>,
+  pub inner: vk::Sampler,
+}
+
+impl Drop for AdSampler {
+  fn drop(&mut self) {
+    unsafe {
+      self.device.destroy_sampler(self.inner, None);
+    }
+  }
+}
+
+pub struct AdDescriptorSetLayout {
+  pub(crate) device: Arc<ash::Device>,
+  pub inner: vk::DescriptorSetLayout,
+}
+
+impl AdDescriptorSetLayout {
+  pub fn new(
+    device: Arc<ash::Device>,
+    bindings: &[vk::DescriptorSetLayoutBinding],
+  ) -> Result<Self, String> {
+    let set_layout = unsafe {
+      device
+        .create_descriptor_set_layout(
+          &vk::DescriptorSetLayoutCreateInfo::default().bindings(bindings),
+          None,
+        )
+        .map_err(|e| format!("at descriptor set layout create: {e}"))?
+    };
+    Ok(Self { device, inner: set_layout })
+  }
+}
+
+impl Drop for AdDescriptorSetLayout {
+  fn drop(&mut self) {
+    unsafe {
+      self.device.destroy_descriptor_set_layout(self.inner, None);
+    }
+  }
+}
+
+pub struct AdDescriptorPool {
+  pub(crate) device: Arc<ash::Device>,
+  pub inner: vk::DescriptorPool,
+}
+
+impl AdDescriptorPool {
+  pub fn new(
+    device: Arc<ash::Device>,
+    flags: vk::DescriptorPoolCreateFlags,
+    max_sets: u32,
+    pool_sizes: &[vk::DescriptorPoolSize],
+  ) -> Result<Self, String> {
+    let pool = unsafe {
+      device
+        .create_descriptor_pool(
+          &vk::DescriptorPoolCreateInfo::default()
+            .flags(flags)
+            .max_sets(max_sets)
+            .pool_sizes(pool_sizes),
+          None,
+        )
+        .map_err(|e| format!("at descriptor pool create: {e}"))?
+    };
+    Ok(Self { device, inner: pool })
+  }
+}
+
+impl Drop for AdDescriptorPool {
+  fn drop(&mut self) {
+    unsafe {
+      self.device.destroy_descriptor_pool(self.inner, None);
+    }
+  }
+}
+
+pub struct AdDescriptorSet {
+  pub inner: vk::DescriptorSet,
+  pool: vk::DescriptorPool,
+  device: Arc<ash::Device>,
+  allocator: Arc<Mutex<DescriptorAllocator>>,
+}
+
+impl AdDescriptorSet {
+  pub fn new(
+    allocator: Arc<Mutex<DescriptorAllocator>>,
+    set_layout: &AdDescriptorSetLayout,
+  ) -> Result<Self, String> {
+    let (pool, set) = allocator
+      .lock()
+      .map_err(|e| format!("at getting descriptor allocator lock: {e}"))?
+      .allocate(set_layout.inner)?;
+    Ok(Self { inner: set, pool, device: Arc::clone(&set_layout.device), allocator })
+  }
+
+  // writes are applied when the builder's write is called
+  pub fn write_builder(&self) -> DescriptorWriteBuilder {
+    DescriptorWriteBuilder::new(Arc::clone(&self.device), self.inner)
+  }
+}
+
+impl Drop for AdDescriptorSet {
+  fn drop(&mut self) {
+    // the lock also keeps other threads off the pool while freeing
+    let _ = self
+      .allocator
+      .lock()
+      .map(|_| unsafe { self.device.free_descriptor_sets(self.pool, &[self.inner]) })
+      .inspect_err(|e| eprintln!("at getting descriptor allocator lock while set free: {e}"));
+  }
+}
+
 pub struct ADRenderPass {
   pub(crate) device: Arc<ash::Device>,
   pub inner: vk::RenderPass,
@@ -0,0 +1,213 @@
+use ash::vk;
+use crate::auto_drop_wrappers::AdDescriptorPool;
+use std::sync::Arc;
+
+// descriptors of each type a pool has room for, per set it can allocate
+const DESCRIPTORS_PER_SET: [(vk::DescriptorType, u32); 6] = [
+  (vk::DescriptorType::UNIFORM_BUFFER, 2),
+  (vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, 2),
+  (vk::DescriptorType::STORAGE_BUFFER, 2),
+  (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 4),
+  (vk::DescriptorType::STORAGE_IMAGE, 1),
+  (vk::DescriptorType::INPUT_ATTACHMENT, 2),
+];
+const FIRST_POOL_SETS: u32 = 16;
+const MAX_POOL_SETS: u32 = 1024;
+
+/*
+Hands out descriptor sets from a list of pools, making a new pool twice the size of the last
+one when none of them has room left. Meant to be shared as Arc<Mutex<_>> like the memory
+allocator, sets free themselves back to their pool on drop.
+ */
+pub struct DescriptorAllocator {
+  device: Arc<ash::Device>,
+  pools: Vec<AdDescriptorPool>,
+  next_pool_sets: u32,
+}
+
+impl DescriptorAllocator {
+  pub fn new(device: Arc<ash::Device>) -> Self {
+    Self { device, pools: vec![], next_pool_sets: FIRST_POOL_SETS }
+  }
+
+  fn allocate_from(
+    &self,
+    pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+  ) -> Result<vk::DescriptorSet, vk::Result> {
+    unsafe {
+      self
+        .device
+        .allocate_descriptor_sets(
+          &vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(pool)
+            .set_layouts(&[set_layout]),
+        )
+        .map(|sets| sets[0])
+    }
+  }
+
+  /*
+  Freed sets leave room in older pools, so every pool is tried before growing.
+  A full pool is told apart by ERROR_OUT_OF_POOL_MEMORY, defined from Vulkan 1.1 on.
+   */
+  pub(crate) fn allocate(
+    &mut self,
+    set_layout: vk::DescriptorSetLayout,
+  ) -> Result<(vk::DescriptorPool, vk::DescriptorSet), String> {
+    for pool in self.pools.iter().rev() {
+      match self.allocate_from(pool.inner, set_layout) {
+        Ok(set) => return Ok((pool.inner, set)),
+        Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY | vk::Result::ERROR_FRAGMENTED_POOL) => {}
+        Err(e) => return Err(format!("at descriptor set allocate: {e}")),
+      }
+    }
+
+    let max_sets = self.next_pool_sets;
+    self.next_pool_sets = (max_sets * 2).min(MAX_POOL_SETS);
+    let pool_sizes = DESCRIPTORS_PER_SET.map(|(ty, count)| {
+      vk::DescriptorPoolSize::default().ty(ty).descriptor_count(count * max_sets)
+    });
+    let pool = AdDescriptorPool::new(
+      Arc::clone(&self.device),
+      vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET,
+      max_sets,
+      &pool_sizes,
+    )?;
+    // a fresh pool only fails if the layout needs more than a whole pool has
+    let set = self
+      .allocate_from(pool.inner, set_layout)
+      .map_err(|e| format!("at descriptor set allocate from new pool: {e}"))?;
+    let pool_handle = pool.inner;
+    self.pools.push(pool);
+    Ok((pool_handle, set))
+  }
+}
+
+enum DescriptorInfo {
+  Buffer(vk::DescriptorBufferInfo),
+  Image(vk::DescriptorImageInfo),
+}
+
+/*
+Typed descriptor writes into one set, one descriptor per binding.
+Buffers bind from offset with range bytes, vk::WHOLE_SIZE for the rest of the buffer.
+ */
+pub struct DescriptorWriteBuilder {
+  device: Arc<ash::Device>,
+  set: vk::DescriptorSet,
+  writes: Vec<(u32, vk::DescriptorType, DescriptorInfo)>,
+}
+
+impl DescriptorWriteBuilder {
+  pub(crate) fn new(device: Arc<ash::Device>, set: vk::DescriptorSet) -> Self {
+    Self { device, set, writes: vec![] }
+  }
+
+  fn add_buffer(
+    mut self,
+    binding: u32,
+    descriptor_type: vk::DescriptorType,
+    buffer: vk::Buffer,
+    offset: vk::DeviceSize,
+    range: vk::DeviceSize,
+  ) -> Self {
+    let info = vk::DescriptorBufferInfo::default().buffer(buffer).offset(offset).range(range);
+    self.writes.push((binding, descriptor_type, DescriptorInfo::Buffer(info)));
+    self
+  }
+
+  fn add_image(
+    mut self,
+    binding: u32,
+    descriptor_type: vk::DescriptorType,
+    sampler: vk::Sampler,
+    view: vk::ImageView,
+    layout: vk::ImageLayout,
+  ) -> Self {
+    let info =
+      vk::DescriptorImageInfo::default().sampler(sampler).image_view(view).image_layout(layout);
+    self.writes.push((binding, descriptor_type, DescriptorInfo::Image(info)));
+    self
+  }
+
+  pub fn add_uniform_buffer(
+    self,
+    binding: u32,
+    buffer: vk::Buffer,
+    offset: vk::DeviceSize,
+    range: vk::DeviceSize,
+  ) -> Self {
+    self.add_buffer(binding, vk::DescriptorType::UNIFORM_BUFFER, buffer, offset, range)
+  }
+
+  // range is the size of one element, the offset within the buffer is given at bind time
+  pub fn add_uniform_buffer_dynamic(
+    self,
+    binding: u32,
+    buffer: vk::Buffer,
+    offset: vk::DeviceSize,
+    range: vk::DeviceSize,
+  ) -> Self {
+    self.add_buffer(binding, vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, buffer, offset, range)
+  }
+
+  pub fn add_storage_buffer(
+    self,
+    binding: u32,
+    buffer: vk::Buffer,
+    offset: vk::DeviceSize,
+    range: vk::DeviceSize,
+  ) -> Self {
+    self.add_buffer(binding, vk::DescriptorType::STORAGE_BUFFER, buffer, offset, range)
+  }
+
+  pub fn add_combined_image_sampler(
+    self,
+    binding: u32,
+    sampler: vk::Sampler,
+    view: vk::ImageView,
+    layout: vk::ImageLayout,
+  ) -> Self {
+    self.add_image(binding, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, sampler, view, layout)
+  }
+
+  pub fn add_storage_image(
+    self,
+    binding: u32,
+    view: vk::ImageView,
+    layout: vk::ImageLayout,
+  ) -> Self {
+    self.add_image(binding, vk::DescriptorType::STORAGE_IMAGE, vk::Sampler::null(), view, layout)
+  }
+
+  pub fn add_input_attachment(
+    self,
+    binding: u32,
+    view: vk::ImageView,
+    layout: vk::ImageLayout,
+  ) -> Self {
+    self.add_image(binding, vk::DescriptorType::INPUT_ATTACHMENT, vk::Sampler::null(), view, layout)
+  }
+
+  // the set must not be in use by the GPU while written
+  pub fn write(self) {
+    let writes: Vec<_> = self
+      .writes
+      .iter()
+      .map(|(binding, descriptor_type, info)| {
+        let write = vk::WriteDescriptorSet::default()
+          .dst_set(self.set)
+          .dst_binding(*binding)
+          .descriptor_type(*descriptor_type);
+        match info {
+          DescriptorInfo::Buffer(x) => write.buffer_info(std::slice::from_ref(x)),
+          DescriptorInfo::Image(x) => write.image_info(std::slice::from_ref(x)),
+        }
+      })
+      .collect();
+    unsafe {
+      self.device.update_descriptor_sets(&writes, &[]);
+    }
+  }
+}
@@ -1,20 +1,24 @@
 pub mod auto_drop_wrappers;
 #[cfg(debug_assertions)]
 mod debug_helpers;
+pub mod descriptors;
 pub mod helpers;
+mod samplers;
 mod vk_init_helpers;
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 pub use ash;
 #[cfg(debug_assertions)]
 use ash::ext;
 use ash::khr;
 use ash::vk;
-use auto_drop_wrappers::{AdFence, AdSemaphore};
+use auto_drop_wrappers::{AdFence, AdQueryPool, AdSampler, AdSemaphore};
 pub use gpu_allocator;
 use gpu_allocator::vulkan::{Allocator, AllocatorCreateDesc};
 pub use raw_window_handle::{HasDisplayHandle, HasWindowHandle};
+pub use samplers::SamplerDesc;
 use crate::auto_drop_wrappers::AdCommandPool;
 use crate::helpers::ADRenderPassBuilder;
 
@@ -25,19 +29,36 @@ pub struct VkLoaders {
   #[cfg(debug_assertions)]
   pub dbg_utils_driver: ext::debug_utils::Instance,
   pub vk_driver: ash::Instance,
+  pub headless: bool,
   _loader: ash::Entry,
 }
 
 impl VkLoaders {
   pub fn new() -> Result<Self, String> {
+    Self::init(false)
+  }
+
+  /*
+  Loaders without any of the surface extensions enabled.
+  Only usable with VkContext::new_headless, make_surface will always fail.
+   */
+  pub fn new_headless() -> Result<Self, String> {
+    Self::init(true)
+  }
+
+  fn init(headless: bool) -> Result<Self, String> {
     let layers = vec![
       #[cfg(debug_assertions)]
       c"VK_LAYER_KHRONOS_validation".as_ptr(),
     ];
-    let instance_extensions = vec![
+    let mut instance_extensions = vec![
       #[cfg(debug_assertions)]
       ext::debug_utils::NAME.as_ptr(),
       khr::get_physical_device_properties2::NAME.as_ptr(),
+      #[cfg(target_os = "macos")]
+      khr::portability_enumeration::NAME.as_ptr(),
+    ];
+    let surface_extensions = vec![
       khr::surface::NAME.as_ptr(),
       #[cfg(target_os = "windows")]
       khr::win32_surface::NAME.as_ptr(),
@@ -46,12 +67,13 @@ impl VkLoaders {
       #[cfg(target_os = "linux")]
       khr::wayland_surface::NAME.as_ptr(),
       #[cfg(target_os = "macos")]
-      khr::portability_enumeration::NAME.as_ptr(),
-      #[cfg(target_os = "macos")]
       ext::metal_surface::NAME.as_ptr(),
       #[cfg(target_os = "android")]
       khr::android_surface::NAME.as_ptr(),
     ];
+    if !headless {
+      instance_extensions.extend(surface_extensions);
+    }
     unsafe {
       let loader = ash::Entry::load().map_err(|e| format!("at vulkan load: {e}"))?;
       let vk_driver = vk_init_helpers::make_instance(&loader, layers, instance_extensions)?;
@@ -72,6 +94,7 @@ impl VkLoaders {
         #[cfg(debug_assertions)]
         dbg_utils_driver,
         vk_driver,
+        headless,
         _loader: loader,
       })
     }
@@ -81,6 +104,9 @@ impl VkLoaders {
     &self,
     window: &(impl HasWindowHandle + HasDisplayHandle),
   ) -> Result<vk::SurfaceKHR, String> {
+    if self.headless {
+      return Err("can't make a surface with headless vk loaders".to_string());
+    }
     unsafe {
       ash_window::create_surface(
         &self._loader,
@@ -114,13 +140,22 @@ pub struct VkContext {
   pub device: Arc<ash::Device>,
   pub graphics_q: vk::Queue,
   pub transfer_q: vk::Queue,
-  pub present_q: vk::Queue,
+  pub present_q: Option<vk::Queue>,
   pub compute_q: vk::Queue,
   pub gpu: vk::PhysicalDevice,
   pub graphics_q_idx: u32,
   pub transfer_q_idx: u32,
-  pub present_q_idx: u32,
+  pub present_q_idx: Option<u32>,
   pub compute_q_idx: u32,
+  // None when the gpu has no anisotropic filtering
+  pub max_sampler_anisotropy: Option<f32>,
+  // dynamic uniform offsets have to be multiples of this
+  pub min_uniform_buffer_offset_alignment: vk::DeviceSize,
+  // nanoseconds per timestamp tick, None when the graphics queue can't write timestamps
+  pub timestamp_period: Option<f32>,
+  sampler_cache: Mutex<HashMap<SamplerDesc, Arc<AdSampler>>>,
+  // one per distinct queue, queues of the same family can be the same vk::Queue
+  queue_locks: HashMap<vk::Queue, Mutex<()>>,
   pub vk_loaders: Arc<VkLoaders>,
 }
 
@@ -128,33 +163,34 @@ impl VkContext {
   unsafe fn select_gpu(
     vk_driver: &ash::Instance,
     surface_driver: &khr::surface::Instance,
-    surface: vk::SurfaceKHR,
+    surface: Option<vk::SurfaceKHR>,
     preferred_gpu: Option<(u32, u32)>,
-  ) -> Result<(vk::PhysicalDevice, [u32; 4]), String> {
+  ) -> Result<(vk::PhysicalDevice, vk_init_helpers::QueueIds), String> {
     let gpu_list =
       vk_driver.enumerate_physical_devices().map_err(|e| format!("can't get GPU list: {e}"))?;
     let gpu_infos = gpu_list
       .into_iter()
       .filter_map(|gpu| {
         let gpu_info = vk_driver.get_physical_device_properties(gpu);
+        if gpu_info.api_version < vk_init_helpers::VK_API_VERSION {
+          return None;
+        }
         let gpu_queue_info = vk_driver.get_physical_device_queue_family_properties(gpu);
-        vk_init_helpers::select_g_t_p_c_queue_ids(&gpu_queue_info, &surface_driver, surface, gpu)
+        vk_init_helpers::select_g_t_p_c_queue_ids(&gpu_queue_info, surface_driver, surface, gpu)
           .map(|gpu_queue_ids| (gpu, (gpu_info.vendor_id, gpu_info.device_id), gpu_queue_ids))
       })
       .collect::<Vec<_>>();
 
     match preferred_gpu {
       None => gpu_infos
-        .iter()
-        .next()
+        .first()
         .cloned()
         .ok_or("no supported GPU".to_string())
         .map(|selected_gpu_info| (selected_gpu_info.0, selected_gpu_info.2)),
       Some(preferred_gpu_ids) => {
         gpu_infos.iter().find(|(_, gpu_ids, _)| *gpu_ids == preferred_gpu_ids).cloned().map_or(
           gpu_infos
-            .iter()
-            .next()
+            .first()
             .cloned()
             .ok_or("no supported GPU".to_string())
             .map(|selected_gpu_info| (selected_gpu_info.0, selected_gpu_info.2)),
@@ -169,37 +205,74 @@ impl VkContext {
     surface: vk::SurfaceKHR,
     preferred_gpu: Option<(u32, u32)>,
   ) -> Result<Self, String> {
-    let device_extensions = vec![
-      khr::swapchain::NAME.as_ptr(),
+    Self::init(vk_loaders, Some(surface), preferred_gpu)
+  }
+
+  /*
+  Context with no present queue and no swapchain extension.
+  For offscreen rendering, compute and running on drivers without a display.
+   */
+  pub fn new_headless(
+    vk_loaders: Arc<VkLoaders>,
+    preferred_gpu: Option<(u32, u32)>,
+  ) -> Result<Self, String> {
+    Self::init(vk_loaders, None, preferred_gpu)
+  }
+
+  fn init(
+    vk_loaders: Arc<VkLoaders>,
+    surface: Option<vk::SurfaceKHR>,
+    preferred_gpu: Option<(u32, u32)>,
+  ) -> Result<Self, String> {
+    let mut device_extensions = vec![
       #[cfg(target_os = "macos")]
       khr::portability_subset::NAME.as_ptr(),
     ];
+    if surface.is_some() {
+      device_extensions.push(khr::swapchain::NAME.as_ptr());
+    }
 
     unsafe {
-      let (gpu, queue_ids) = Self::select_gpu(
-        &vk_loaders.vk_driver,
-        &vk_loaders.surface_driver,
-        surface,
-        preferred_gpu,
-      )?;
+      let (gpu, (graphics_q_idx, transfer_q_idx, present_q_idx, compute_q_idx)) =
+        Self::select_gpu(&vk_loaders.vk_driver, &vk_loaders.surface_driver, surface, preferred_gpu)?;
+      let mut queue_ids = vec![graphics_q_idx, transfer_q_idx, compute_q_idx];
+      if let Some(present_q_idx) = present_q_idx {
+        queue_ids.push(present_q_idx);
+      }
+      let sampler_anisotropy =
+        vk_loaders.vk_driver.get_physical_device_features(gpu).sampler_anisotropy == vk::TRUE;
       let (device, queues) = vk_init_helpers::create_device_and_queues(
         &vk_loaders.vk_driver,
         gpu,
         device_extensions,
-        vk::PhysicalDeviceFeatures::default(),
-        queue_ids,
+        vk::PhysicalDeviceFeatures::default().sampler_anisotropy(sampler_anisotropy),
+        &queue_ids,
       )?;
+      let limits = vk_loaders.vk_driver.get_physical_device_properties(gpu).limits;
+      let max_sampler_anisotropy = sampler_anisotropy.then_some(limits.max_sampler_anisotropy);
+      let graphics_q_timestamps = vk_loaders
+        .vk_driver
+        .get_physical_device_queue_family_properties(gpu)[graphics_q_idx as usize]
+        .timestamp_valid_bits
+        > 0;
+      let timestamp_period = graphics_q_timestamps.then_some(limits.timestamp_period);
+      let queue_locks = queues.iter().map(|queue| (*queue, Mutex::new(()))).collect();
       Ok(Self {
         device: Arc::new(device),
         graphics_q: queues[0],
         transfer_q: queues[1],
-        present_q: queues[2],
-        compute_q: queues[3],
+        present_q: present_q_idx.map(|_| queues[3]),
+        compute_q: queues[2],
         gpu,
-        graphics_q_idx: queue_ids[0],
-        transfer_q_idx: queue_ids[1],
-        present_q_idx: queue_ids[2],
-        compute_q_idx: queue_ids[3],
+        graphics_q_idx,
+        transfer_q_idx,
+        present_q_idx,
+        compute_q_idx,
+        max_sampler_anisotropy,
+        min_uniform_buffer_offset_alignment: limits.min_uniform_buffer_offset_alignment,
+        timestamp_period,
+        sampler_cache: Mutex::new(HashMap::new()),
+        queue_locks,
         vk_loaders,
       })
     }
@@ -217,6 +290,23 @@ impl VkContext {
     .map_err(|e| format!("at gpu mem allocator create: {e}"))
   }
 
+  /*
+  First of D32, D24S8 and D16 that the GPU can use as an optimal tiling depth attachment.
+   */
+  pub fn select_depth_format(&self) -> Result<vk::Format, String> {
+    [vk::Format::D32_SFLOAT, vk::Format::D24_UNORM_S8_UINT, vk::Format::D16_UNORM]
+      .into_iter()
+      .find(|format| unsafe {
+        self
+          .vk_loaders
+          .vk_driver
+          .get_physical_device_format_properties(self.gpu, *format)
+          .optimal_tiling_features
+          .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+      })
+      .ok_or("no supported depth format".to_string())
+  }
+
   pub fn create_ad_semaphore(&self) -> Result<AdSemaphore, String> {
     unsafe {
       let semaphore = self
@@ -247,16 +337,65 @@ impl VkContext {
     Ok(AdCommandPool { device: Arc::clone(&self.device), inner: cmd_pool })
   }
 
+  pub fn create_ad_query_pool(&self, info: vk::QueryPoolCreateInfo) -> Result<AdQueryPool, String> {
+    let query_pool = unsafe {
+      self
+        .device
+        .create_query_pool(&info, None)
+        .map_err(|e| format!("at query pool create: {e}"))?
+    };
+    Ok(AdQueryPool { device: Arc::clone(&self.device), inner: query_pool })
+  }
+
+  // the lock guards no data, so a thread panicking while holding it leaves nothing broken
+  fn lock_queue(&self, queue: vk::Queue) -> Option<MutexGuard<'_, ()>> {
+    self.queue_locks.get(&queue).map(|lock| lock.lock().unwrap_or_else(|e| e.into_inner()))
+  }
+
+  /*
+  Submits and presents on a queue have to be externally synchronized, and the context's queues
+  are shared between threads. Every submit and present goes through these.
+   */
+  pub fn queue_submit(
+    &self,
+    queue: vk::Queue,
+    submits: &[vk::SubmitInfo],
+    fence: vk::Fence,
+  ) -> Result<(), vk::Result> {
+    let _queue_lock = self.lock_queue(queue);
+    unsafe { self.device.queue_submit(queue, submits, fence) }
+  }
+
+  // true when the swapchain is suboptimal, like khr::swapchain::Device::queue_present
+  pub fn queue_present(
+    &self,
+    swapchain_device: &khr::swapchain::Device,
+    queue: vk::Queue,
+    present_info: &vk::PresentInfoKHR,
+  ) -> Result<bool, vk::Result> {
+    let _queue_lock = self.lock_queue(queue);
+    unsafe { swapchain_device.queue_present(queue, present_info) }
+  }
+
+  // waiting for the device idle needs every queue, so this takes all their locks
+  pub fn device_wait_idle(&self) -> Result<(), vk::Result> {
+    let _queue_locks: Vec<_> =
+      self.queue_locks.keys().map(|queue| self.lock_queue(*queue)).collect();
+    unsafe { self.device.device_wait_idle() }
+  }
+
   pub fn create_ad_render_pass_builder(
     &self,
     flags: vk::RenderPassCreateFlags,
-  ) -> ADRenderPassBuilder {
+  ) -> ADRenderPassBuilder<'_> {
     ADRenderPassBuilder::new(Arc::clone(&self.device), flags)
   }
 }
 
 impl Drop for VkContext {
   fn drop(&mut self) {
+    // cached samplers have to go before the device
+    self.sampler_cache.get_mut().unwrap_or_else(|e| e.into_inner()).clear();
     unsafe {
       self.device.destroy_device(None);
     }
@@ -0,0 +1,107 @@
+use crate::auto_drop_wrappers::AdSampler;
+use crate::VkContext;
+use ash::vk;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+// anisotropy past this barely shows and costs bandwidth
+const MAX_ANISOTROPY: f32 = 16.0;
+
+/*
+Everything a sampler is created from, identical descriptions share one sampler through
+VkContext::get_sampler. Defaults to trilinear filtering with repeat addressing, anisotropy
+and no LOD clamp.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct SamplerDesc {
+  pub mag_filter: vk::Filter,
+  pub min_filter: vk::Filter,
+  pub mipmap_mode: vk::SamplerMipmapMode,
+  // u, v and w
+  pub address_modes: [vk::SamplerAddressMode; 3],
+  // the highest anisotropy the gpu supports, ignored when it has none
+  pub anisotropy: bool,
+  pub min_lod: f32,
+  pub max_lod: f32,
+}
+
+impl Default for SamplerDesc {
+  fn default() -> Self {
+    Self {
+      mag_filter: vk::Filter::LINEAR,
+      min_filter: vk::Filter::LINEAR,
+      mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+      address_modes: [vk::SamplerAddressMode::REPEAT; 3],
+      anisotropy: true,
+      min_lod: 0.0,
+      max_lod: vk::LOD_CLAMP_NONE,
+    }
+  }
+}
+
+impl SamplerDesc {
+  // lods compared by bits so the description can be a hash map key
+  fn key(&self) -> impl Eq + Hash {
+    (
+      self.mag_filter,
+      self.min_filter,
+      self.mipmap_mode,
+      self.address_modes,
+      self.anisotropy,
+      self.min_lod.to_bits(),
+      self.max_lod.to_bits(),
+    )
+  }
+}
+
+impl PartialEq for SamplerDesc {
+  fn eq(&self, other: &Self) -> bool {
+    self.key() == other.key()
+  }
+}
+
+impl Eq for SamplerDesc {}
+
+impl Hash for SamplerDesc {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.key().hash(state);
+  }
+}
+
+impl VkContext {
+  /*
+  Sampler for the description, created on first use and kept till the context drops.
+  The returned sampler stays valid as long as it is held.
+   */
+  pub fn get_sampler(&self, desc: SamplerDesc) -> Result<Arc<AdSampler>, String> {
+    let mut cache =
+      self.sampler_cache.lock().map_err(|e| format!("at getting sampler cache lock: {e}"))?;
+    if let Some(sampler) = cache.get(&desc) {
+      return Ok(Arc::clone(sampler));
+    }
+
+    let max_anisotropy = self.max_sampler_anisotropy.filter(|_| desc.anisotropy);
+    let sampler = unsafe {
+      self
+        .device
+        .create_sampler(
+          &vk::SamplerCreateInfo::default()
+            .mag_filter(desc.mag_filter)
+            .min_filter(desc.min_filter)
+            .mipmap_mode(desc.mipmap_mode)
+            .address_mode_u(desc.address_modes[0])
+            .address_mode_v(desc.address_modes[1])
+            .address_mode_w(desc.address_modes[2])
+            .anisotropy_enable(max_anisotropy.is_some())
+            .max_anisotropy(max_anisotropy.unwrap_or(1.0).min(MAX_ANISOTROPY))
+            .min_lod(desc.min_lod)
+            .max_lod(desc.max_lod),
+          None,
+        )
+        .map_err(|e| format!("at sampler create: {e}"))?
+    };
+    let sampler = Arc::new(AdSampler { device: Arc::clone(&self.device), inner: sampler });
+    cache.insert(desc, Arc::clone(&sampler));
+    Ok(sampler)
+  }
+}
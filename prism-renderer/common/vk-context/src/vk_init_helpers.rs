@@ -3,6 +3,12 @@ use std::cmp::min;
 use std::collections::HashMap;
 use std::ffi::c_char;
 
+/*
+1.1 for maintenance1 in core, which makes allocating from a full descriptor pool return
+ERROR_OUT_OF_POOL_MEMORY instead of being invalid usage. DescriptorAllocator grows on it.
+ */
+pub const VK_API_VERSION: u32 = vk::API_VERSION_1_1;
+
 pub unsafe fn make_instance(
   driver: &ash::Entry,
   needed_layers: Vec<*const c_char>,
@@ -13,7 +19,7 @@ pub unsafe fn make_instance(
     .application_version(0)
     .engine_name(c"Prism Engine")
     .engine_version(0)
-    .api_version(vk::API_VERSION_1_0);
+    .api_version(VK_API_VERSION);
 
   let instance_create_info = vk::InstanceCreateInfo::default()
     .application_info(&app_info)
@@ -29,7 +35,7 @@ pub unsafe fn make_instance(
     .map_err(|e| format!("at instance create: {e}"))
 }
 
-pub fn select_g_queue(gpu_queue_props: &Vec<vk::QueueFamilyProperties>) -> Option<u32> {
+pub fn select_g_queue(gpu_queue_props: &[vk::QueueFamilyProperties]) -> Option<u32> {
   let mut selected_queue = None;
   let mut selected_queue_count = 0;
   for (queue_idx, queue_props) in gpu_queue_props.iter().enumerate() {
@@ -42,7 +48,7 @@ pub fn select_g_queue(gpu_queue_props: &Vec<vk::QueueFamilyProperties>) -> Optio
   selected_queue
 }
 
-pub fn select_c_queue(gpu_queue_props: &Vec<vk::QueueFamilyProperties>) -> Option<u32> {
+pub fn select_c_queue(gpu_queue_props: &[vk::QueueFamilyProperties]) -> Option<u32> {
   let mut selected_queue = None;
   let mut selected_weight = 0;
   let mut selected_queue_count = 0;
@@ -70,7 +76,7 @@ pub fn select_c_queue(gpu_queue_props: &Vec<vk::QueueFamilyProperties>) -> Optio
   selected_queue
 }
 
-pub fn select_t_queue(gpu_queue_props: &Vec<vk::QueueFamilyProperties>) -> Option<u32> {
+pub fn select_t_queue(gpu_queue_props: &[vk::QueueFamilyProperties]) -> Option<u32> {
   let mut selected_queue = None;
   let mut selected_weight = 0;
   let mut selected_queue_count = 0;
@@ -103,7 +109,7 @@ pub fn select_t_queue(gpu_queue_props: &Vec<vk::QueueFamilyProperties>) -> Optio
 }
 
 pub fn select_p_queue(
-  gpu_queue_props: &Vec<vk::QueueFamilyProperties>,
+  gpu_queue_props: &[vk::QueueFamilyProperties],
   surface_driver: &khr::surface::Instance,
   surface: vk::SurfaceKHR,
   gpu: vk::PhysicalDevice,
@@ -126,22 +132,23 @@ pub fn select_p_queue(
   selected_queue
 }
 
+// graphics, transfer, present (None without a surface) and compute queue family indices
+pub type QueueIds = (u32, u32, Option<u32>, u32);
+
 pub fn select_g_t_p_c_queue_ids(
-  gpu_queue_props: &Vec<vk::QueueFamilyProperties>,
+  gpu_queue_props: &[vk::QueueFamilyProperties],
   surface_driver: &khr::surface::Instance,
-  surface: vk::SurfaceKHR,
+  surface: Option<vk::SurfaceKHR>,
   gpu: vk::PhysicalDevice,
-) -> Option<[u32; 4]> {
-  if let Some(graphics_q_idx) = select_g_queue(&gpu_queue_props) {
-    if let Some(transfer_q_idx) = select_t_queue(&gpu_queue_props) {
-      if let Some(present_q_idx) = select_p_queue(&gpu_queue_props, surface_driver, surface, gpu) {
-        if let Some(compute_q_idx) = select_c_queue(&gpu_queue_props) {
-          return Some([graphics_q_idx, transfer_q_idx, present_q_idx, compute_q_idx]);
-        }
-      }
-    }
-  }
-  None
+) -> Option<QueueIds> {
+  let graphics_q_idx = select_g_queue(gpu_queue_props)?;
+  let transfer_q_idx = select_t_queue(gpu_queue_props)?;
+  let present_q_idx = match surface {
+    Some(surface) => Some(select_p_queue(gpu_queue_props, surface_driver, surface, gpu)?),
+    None => None,
+  };
+  let compute_q_idx = select_c_queue(gpu_queue_props)?;
+  Some((graphics_q_idx, transfer_q_idx, present_q_idx, compute_q_idx))
 }
 
 pub unsafe fn create_device_and_queues(
@@ -149,13 +156,13 @@ pub unsafe fn create_device_and_queues(
   gpu: vk::PhysicalDevice,
   needed_extensions: Vec<*const c_char>,
   features: vk::PhysicalDeviceFeatures,
-  queue_indices: [u32; 4],
-) -> Result<(ash::Device, [vk::Queue; 4]), String> {
+  queue_indices: &[u32],
+) -> Result<(ash::Device, Vec<vk::Queue>), String> {
   let queue_priorities: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
   let gpu_queue_props = instance.get_physical_device_queue_family_properties(gpu);
 
   let mut q_idx_map = HashMap::<u32, u32>::with_capacity(4);
-  for x in queue_indices {
+  for &x in queue_indices {
     if x >= gpu_queue_props.len() as u32 {
       return Err("invalid queue ids requested".to_string());
     }
@@ -183,8 +190,8 @@ pub unsafe fn create_device_and_queues(
     .create_device(gpu, &device_create_info, None)
     .map_err(|e| format!("at logic device init: {e}"))?;
 
-  let mut queues = Vec::with_capacity(4);
-  for x in queue_indices {
+  let mut queues = Vec::with_capacity(queue_indices.len());
+  for &x in queue_indices {
     let cur_q_idx = q_idx_map.get_mut(&x).ok_or("invalid queue".to_string())?;
     queues.push(device.get_device_queue(x, *cur_q_idx - 1));
     if *cur_q_idx != 1 {
@@ -192,5 +199,5 @@ pub unsafe fn create_device_and_queues(
     }
   }
 
-  Ok((device, [queues[0], queues[1], queues[2], queues[3]]))
+  Ok((device, queues))
 }
@@ -0,0 +1,11 @@
+/*
+GPU scenes are #[ignore]d unless PRISM_GOLDEN_REQUIRE_GPU is set at build time, so machines
+without a vulkan device show them as ignored instead of passing without rendering.
+ */
+fn main() {
+  println!("cargo::rustc-check-cfg=cfg(golden_require_gpu)");
+  println!("cargo::rerun-if-env-changed=PRISM_GOLDEN_REQUIRE_GPU");
+  if std::env::var_os("PRISM_GOLDEN_REQUIRE_GPU").is_some() {
+    println!("cargo::rustc-cfg=golden_require_gpu");
+  }
+}
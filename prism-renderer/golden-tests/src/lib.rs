@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+use prism_renderer::image::{Rgba, RgbaImage};
+use prism_renderer::{Renderer, RendererConfig};
+
+pub use prism_renderer;
+
+// set to re-write the committed goldens from the current renderer output
+const UPDATE_GOLDENS_ENV: &str = "PRISM_UPDATE_GOLDENS";
+
+pub struct GoldenConfig {
+  pub golden_dir: PathBuf,
+  pub diff_dir: PathBuf,
+  // max allowed per channel difference for a pixel to still match
+  pub tolerance: u8,
+  // number of pixels allowed to be outside tolerance before failing
+  pub max_mismatched_pixels: u32,
+}
+
+impl Default for GoldenConfig {
+  fn default() -> Self {
+    Self {
+      golden_dir: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("goldens"),
+      diff_dir: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("golden-diffs"),
+      tolerance: 2,
+      max_mismatched_pixels: 0,
+    }
+  }
+}
+
+pub struct ImageComparison {
+  pub mismatched_pixels: u32,
+  pub max_channel_diff: u8,
+  // red where pixels mismatch, dimmed grayscale of the expected image elsewhere
+  pub diff_image: RgbaImage,
+}
+
+pub fn compare_images(
+  actual: &RgbaImage,
+  expected: &RgbaImage,
+  tolerance: u8,
+) -> Result<ImageComparison, String> {
+  if actual.dimensions() != expected.dimensions() {
+    return Err(format!(
+      "image size mismatch: got {:?}, expected {:?}",
+      actual.dimensions(),
+      expected.dimensions()
+    ));
+  }
+  let mut mismatched_pixels = 0;
+  let mut max_channel_diff = 0;
+  let diff_image = RgbaImage::from_fn(actual.width(), actual.height(), |x, y| {
+    let actual_px = actual.get_pixel(x, y);
+    let expected_px = expected.get_pixel(x, y);
+    let pixel_diff =
+      actual_px.0.iter().zip(expected_px.0.iter()).map(|(a, e)| a.abs_diff(*e)).max().unwrap_or(0);
+    max_channel_diff = max_channel_diff.max(pixel_diff);
+    if pixel_diff > tolerance {
+      mismatched_pixels += 1;
+      Rgba([255, 0, 0, 255])
+    } else {
+      let luma = (expected_px[0] as u32 + expected_px[1] as u32 + expected_px[2] as u32) / 12;
+      Rgba([luma as u8, luma as u8, luma as u8, 255])
+    }
+  });
+  Ok(ImageComparison { mismatched_pixels, max_channel_diff, diff_image })
+}
+
+/*
+Compare the image against goldens/<name>.png.
+On failure the actual and diff images are written to the diff dir for inspection.
+With PRISM_UPDATE_GOLDENS set the golden is overwritten instead.
+ */
+pub fn check_golden(name: &str, actual: &RgbaImage, config: &GoldenConfig) -> Result<(), String> {
+  let golden_path = config.golden_dir.join(format!("{name}.png"));
+  if std::env::var_os(UPDATE_GOLDENS_ENV).is_some() {
+    return save_image(actual, &golden_path);
+  }
+
+  let expected = prism_renderer::image::open(&golden_path)
+    .map_err(|e| {
+      format!("at loading golden {golden_path:?}: {e}, run with {UPDATE_GOLDENS_ENV}=1 to create it")
+    })?
+    .to_rgba8();
+  let comparison = match compare_images(actual, &expected, config.tolerance) {
+    Ok(x) => x,
+    Err(e) => {
+      save_image(actual, &config.diff_dir.join(format!("{name}.actual.png")))?;
+      return Err(format!("golden {name}: {e}"));
+    }
+  };
+  if comparison.mismatched_pixels <= config.max_mismatched_pixels {
+    return Ok(());
+  }
+
+  let actual_path = config.diff_dir.join(format!("{name}.actual.png"));
+  let diff_path = config.diff_dir.join(format!("{name}.diff.png"));
+  save_image(actual, &actual_path)?;
+  save_image(&comparison.diff_image, &diff_path)?;
+  Err(format!(
+    "golden {name}: {} pixels differ by more than {} (max diff {}), see {actual_path:?} and {diff_path:?}",
+    comparison.mismatched_pixels, config.tolerance, comparison.max_channel_diff
+  ))
+}
+
+fn save_image(image: &RgbaImage, path: &Path) -> Result<(), String> {
+  if let Some(parent) = path.parent() {
+    std::fs::create_dir_all(parent).map_err(|e| format!("at creating dir {parent:?}: {e}"))?;
+  }
+  image.save(path).map_err(|e| format!("at saving image {path:?}: {e}"))
+}
+
+/*
+Headless renderer for golden tests, meant to run on a software ICD like lavapipe.
+Panics if no vulkan device is available, scenes that need one are #[ignore]d unless
+PRISM_GOLDEN_REQUIRE_GPU is set, see build.rs.
+ */
+pub fn offscreen_renderer(width: u32, height: u32) -> Renderer {
+  // renderer assets live at the repo root, same as running the app from there
+  let config = RendererConfig {
+    asset_dir: Path::new(env!("CARGO_MANIFEST_DIR")).join("../.."),
+    ..Default::default()
+  };
+  Renderer::new_offscreen_with_config(width, height, config)
+    .unwrap_or_else(|e| panic!("can't create offscreen renderer: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn solid_image(color: [u8; 4]) -> RgbaImage {
+    RgbaImage::from_pixel(4, 4, Rgba(color))
+  }
+
+  #[test]
+  fn identical_images_match() {
+    let image = solid_image([10, 20, 30, 255]);
+    let comparison = compare_images(&image, &image, 0).unwrap();
+    assert_eq!(comparison.mismatched_pixels, 0);
+    assert_eq!(comparison.max_channel_diff, 0);
+  }
+
+  #[test]
+  fn differences_within_tolerance_match() {
+    let comparison =
+      compare_images(&solid_image([12, 20, 30, 255]), &solid_image([10, 21, 30, 255]), 2).unwrap();
+    assert_eq!(comparison.mismatched_pixels, 0);
+    assert_eq!(comparison.max_channel_diff, 2);
+  }
+
+  #[test]
+  fn differences_outside_tolerance_are_marked() {
+    let expected = solid_image([0, 0, 0, 255]);
+    let mut actual = expected.clone();
+    actual.put_pixel(1, 2, Rgba([0, 50, 0, 255]));
+    let comparison = compare_images(&actual, &expected, 2).unwrap();
+    assert_eq!(comparison.mismatched_pixels, 1);
+    assert_eq!(comparison.max_channel_diff, 50);
+    assert_eq!(*comparison.diff_image.get_pixel(1, 2), Rgba([255, 0, 0, 255]));
+    assert_eq!(*comparison.diff_image.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+  }
+
+  #[test]
+  fn size_mismatch_is_an_error() {
+    let small = RgbaImage::new(2, 2);
+    assert!(compare_images(&small, &solid_image([0, 0, 0, 255]), 255).is_err());
+  }
+
+  #[test]
+  fn check_golden_writes_diff_on_failure() {
+    let dir = std::env::temp_dir().join(format!("prism-golden-test-{}", std::process::id()));
+    let config = GoldenConfig {
+      golden_dir: dir.join("goldens"),
+      diff_dir: dir.join("diffs"),
+      tolerance: 0,
+      max_mismatched_pixels: 0,
+    };
+    save_image(&solid_image([0, 0, 0, 255]), &config.golden_dir.join("solid.png")).unwrap();
+
+    assert!(check_golden("solid", &solid_image([0, 0, 0, 255]), &config).is_ok());
+    assert!(check_golden("solid", &solid_image([9, 0, 0, 255]), &config).is_err());
+    assert!(config.diff_dir.join("solid.actual.png").exists());
+    assert!(config.diff_dir.join("solid.diff.png").exists());
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
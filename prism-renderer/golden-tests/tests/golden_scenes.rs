@@ -0,0 +1,102 @@
+use golden_tests::prism_renderer::glam::{Mat4, Vec3, Vec4};
+use golden_tests::prism_renderer::{Camera3D, Light, Mesh, Renderer, TriangleFaceInfo, Vertex};
+use golden_tests::{check_golden, offscreen_renderer, GoldenConfig};
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 64;
+
+// looking down -z at the origin from 3 units away
+fn front_camera() -> Camera3D {
+  Camera3D {
+    eye: Vec4::new(0.0, 0.0, 3.0, 1.0),
+    dir: Vec4::new(0.0, 0.0, -1.0, 0.0),
+    up: Vec4::new(0.0, 1.0, 0.0, 0.0),
+    info: Vec4::new(0.1, 100.0, 1.0, WIDTH as f32 / HEIGHT as f32),
+  }
+}
+
+// width by height quad facing +z, the whole texture mapped upright on it
+fn quad(width: f32, height: f32) -> Mesh {
+  let vertex = |x: f32, y: f32, u: f32, v: f32| Vertex {
+    position: Vec4::new(x * width / 2.0, y * height / 2.0, 0.0, 1.0),
+    normal: Vec4::new(0.0, 0.0, 1.0, 0.0),
+    tangent: Vec4::new(1.0, 0.0, 0.0, 0.0),
+    uv_coordinates: Vec4::new(u, v, 0.0, 0.0),
+  };
+  Mesh {
+    vertices: vec![
+      vertex(-1.0, -1.0, 0.0, 1.0),
+      vertex(1.0, -1.0, 1.0, 1.0),
+      vertex(1.0, 1.0, 1.0, 0.0),
+      vertex(-1.0, 1.0, 0.0, 0.0),
+    ],
+    faces: vec![TriangleFaceInfo { vertices: [0, 1, 2] }, TriangleFaceInfo { vertices: [2, 3, 0] }],
+  }
+}
+
+fn draw_and_check(mut renderer: Renderer, golden: &str) {
+  renderer.draw().expect("draw failed");
+  let frame = renderer.read_back().expect("read back failed");
+  check_golden(golden, &frame, &GoldenConfig::default()).unwrap();
+}
+
+#[test]
+#[cfg_attr(not(golden_require_gpu), ignore = "needs a vulkan device, see build.rs")]
+fn clear_scene() {
+  draw_and_check(offscreen_renderer(WIDTH, HEIGHT), "clear");
+}
+
+// turned so three faces show, flat ambient light shows the albedo as is
+#[test]
+#[cfg_attr(not(golden_require_gpu), ignore = "needs a vulkan device, see build.rs")]
+fn cube_scene() {
+  let mut renderer = offscreen_renderer(WIDTH, HEIGHT);
+  renderer.set_camera(&front_camera());
+  renderer.set_ambient_light(Vec3::ONE);
+  let model = Mat4::from_rotation_x(0.5) * Mat4::from_rotation_y(0.7);
+  renderer.add_mesh(&Mesh::new_cube(1.0, 1.0, 1.0), model).unwrap();
+  draw_and_check(renderer, "cube");
+}
+
+#[test]
+#[cfg_attr(not(golden_require_gpu), ignore = "needs a vulkan device, see build.rs")]
+fn textured_quad_scene() {
+  let mut renderer = offscreen_renderer(WIDTH, HEIGHT);
+  renderer.set_camera(&front_camera());
+  renderer.set_ambient_light(Vec3::ONE);
+  renderer.add_mesh(&quad(2.0, 2.0), Mat4::IDENTITY).unwrap();
+  draw_and_check(renderer, "textured_quad");
+}
+
+// directional, point and spot lights on a cube over a floor quad, through the tonemap
+#[test]
+#[cfg_attr(not(golden_require_gpu), ignore = "needs a vulkan device, see build.rs")]
+fn lit_pbr_scene() {
+  let mut renderer = offscreen_renderer(WIDTH, HEIGHT);
+  renderer.set_camera(&front_camera());
+  renderer.set_ambient_light(Vec3::splat(0.05));
+  renderer.add_mesh(&Mesh::new_cube(1.0, 1.0, 1.0), Mat4::from_rotation_y(0.6)).unwrap();
+  renderer
+    .add_mesh(
+      &quad(4.0, 4.0),
+      Mat4::from_translation(Vec3::new(0.0, -0.5, 0.0))
+        * Mat4::from_rotation_x(-std::f32::consts::FRAC_PI_2),
+    )
+    .unwrap();
+  renderer
+    .set_lights(&[
+      Light::directional(Vec3::new(-0.4, -1.0, -0.6), Vec3::new(1.0, 0.95, 0.9), 2.0),
+      Light::point(Vec3::new(1.2, 0.8, 1.2), 5.0, Vec3::new(1.0, 0.4, 0.2), 6.0),
+      Light::spot(
+        Vec3::new(-1.5, 1.5, 1.0),
+        Vec3::new(1.5, -1.5, -1.0),
+        8.0,
+        0.2,
+        0.4,
+        Vec3::new(0.2, 0.5, 1.0),
+        10.0,
+      ),
+    ])
+    .unwrap();
+  draw_and_check(renderer, "lit_pbr");
+}
@@ -1,32 +1,181 @@
 mod presentation;
+mod render_scale;
+mod render_targets;
+mod uniform_ring;
 
 use presentation::PresentManager;
 use presentation::PresentManagerError;
-use vk_context::auto_drop_wrappers::AdAllocatedImage;
-use std::path::PathBuf;
+use render_targets::{
+  create_deferred_render_pass, RenderTargets, GEOMETRY_SUBPASS, LIGHTING_SUBPASS, TONEMAP_SUBPASS,
+};
+use render_scale::{scaled_resolution, FrameTimer};
+use uniform_ring::UniformRing;
+use vk_context::auto_drop_wrappers::{AdAllocatedBuffer, AdDescriptorSet, AdFence, AdSemaphore};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use transfer_manager::TransferManager;
-use vert_mesh_pbr::structs::PbrMaterial;
+use transfer_manager::{EnvironmentMap, TransferManager};
+use vert_mesh_pbr::structs::{PbrMaterial, PbrMaterialFactors, PbrTextures, VertMesh};
+use vert_mesh_pbr::lighting::{make_tonemap_pass, DeferredLightingPass, FullscreenPass};
+use vert_mesh_pbr::{make_vert_mesh_pbr_pipeline, VertMeshPbrPipeline, CAMERA_SET};
 use vk_context::ash::vk;
 use vk_context::auto_drop_wrappers::{AdCommandBuffer, AdCommandPool, ADRenderPass};
+use vk_context::descriptors::DescriptorAllocator;
 use vk_context::gpu_allocator::vulkan::Allocator;
-use vk_context::VkLoaders;
+use vk_context::{VkContext, VkLoaders};
 use vk_context::{HasDisplayHandle, HasWindowHandle};
 use vk_context::gpu_allocator::MemoryLocation;
+use camera_3d::CameraTransforms;
+
+pub use camera_3d::Camera3D;
+pub use mesh_structs::{glam, Mesh, TriangleFaceInfo, Vertex};
+pub use presentation::{PresentConfig, ScalingMode, VSync};
+pub use render_scale::{RenderScale, MAX_RENDER_SCALE, MIN_RENDER_SCALE};
+pub use image;
+pub use vert_mesh_pbr::lighting::{Light, LightKind, MAX_LIGHTS};
+
+/*
+Shading happens on linear values, the tonemapped output is written to an sRGB image that
+encodes them. read_back then returns sRGB pixels ready to display or save.
+ */
+const OUTPUT_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+// each mesh takes one model matrix in the frame uniforms
+pub const MAX_MESHES: usize = 1024;
+
+// settings given at renderer creation, present can be changed later with set_present_config
+#[derive(Clone, Debug)]
+pub struct RendererConfig {
+  // frames recorded while earlier ones are still on the GPU, each with its own command
+  // buffer, sync objects and uniforms
+  pub frames_in_flight: usize,
+  // ignored by offscreen renderers
+  pub present: PresentConfig,
+  // can be changed later with set_render_scale
+  pub render_scale: RenderScale,
+  // renderer assets like tile_tex.png are loaded from here
+  pub asset_dir: PathBuf,
+}
+
+impl Default for RendererConfig {
+  fn default() -> Self {
+    Self {
+      frames_in_flight: 2,
+      present: PresentConfig::default(),
+      render_scale: RenderScale::default(),
+      asset_dir: PathBuf::from("."),
+    }
+  }
+}
+
+/*
+Device, memory allocators and transfer manager, shared by the renderers of all windows.
+Get it from the first window's renderer with render_context.
+ */
+#[derive(Clone)]
+pub struct RenderContext {
+  vk_context: Arc<VkContext>,
+  allocator: Arc<Mutex<Allocator>>,
+  descriptor_allocator: Arc<Mutex<DescriptorAllocator>>,
+  transfer_manager: Arc<TransferManager>,
+  // black, bound till a renderer loads its own so the environment descriptors are always valid
+  default_environment_map: Arc<EnvironmentMap>,
+}
+
+impl RenderContext {
+  fn new(vk_context: Arc<VkContext>) -> Result<Self, String> {
+    let transfer_manager = Arc::new(TransferManager::new(Arc::clone(&vk_context))?);
+    let allocator = Arc::new(Mutex::new(vk_context.create_allocator()?));
+    let descriptor_allocator =
+      Arc::new(Mutex::new(DescriptorAllocator::new(Arc::clone(&vk_context.device))));
+    let default_environment_map = Arc::new(transfer_manager.create_environment_map(
+      Arc::clone(&allocator),
+      &image::Rgba32FImage::new(2, 1),
+      "default_environment",
+    )?);
+    Ok(Self {
+      vk_context,
+      allocator,
+      descriptor_allocator,
+      transfer_manager,
+      default_environment_map,
+    })
+  }
+
+  // surface of window if the present queue can present to it
+  fn make_surface(
+    &self,
+    window: &(impl HasWindowHandle + HasDisplayHandle),
+  ) -> Result<vk::SurfaceKHR, String> {
+    let present_q_idx =
+      self.vk_context.present_q_idx.ok_or("renderer has no present queue".to_string())?;
+    let vk_loaders = &self.vk_context.vk_loaders;
+    let surface = vk_loaders.make_surface(&window)?;
+    let surface_support = unsafe {
+      vk_loaders
+        .surface_driver
+        .get_physical_device_surface_support(self.vk_context.gpu, present_q_idx, surface)
+        .map_err(|e| format!("{e}"))
+    };
+    match surface_support {
+      Ok(true) => Ok(surface),
+      Ok(false) => {
+        vk_loaders.destroy_surface(surface);
+        Err("New surface unsupported by renderer, please restart app".to_string())
+      }
+      Err(e) => {
+        vk_loaders.destroy_surface(surface);
+        Err(format!("at checking surface support: {e}"))
+      }
+    }
+  }
+}
+
+// what one frame in flight records and syncs with
+struct FrameResources {
+  cmd_buffer: AdCommandBuffer,
+  // signaled when the frame's render submission is done, unsignaled till first submitted
+  in_flight_fence: AdFence,
+  // signaled by the render submission for the present blit to wait on
+  render_done: AdSemaphore,
+  submitted: bool,
+}
 
 pub struct Renderer {
+  mesh_pipeline: VertMeshPbrPipeline,
+  lighting_pass: DeferredLightingPass,
+  environment_map: Arc<EnvironmentMap>,
+  default_environment_map: Arc<EnvironmentMap>,
+  tonemap_pass: FullscreenPass,
   mesh_render_pass: ADRenderPass,
   material: PbrMaterial,
-  attachment_image: AdAllocatedImage,
-  attachment_image_view: vk::ImageView,
-  mesh_frame_buffer: vk::Framebuffer,
-  render_cmd_buffer: AdCommandBuffer,
+  meshes: Vec<(VertMesh, glam::Mat4)>,
+  camera_transforms: CameraTransforms,
+  // camera set of each frame, pointing at that frame's uniform buffer
+  frame_sets: Vec<AdDescriptorSet>,
+  frame_uniforms: UniformRing,
+  render_targets: RenderTargets,
+  // the color image is UNDEFINED till the first draw into the current render targets
+  render_targets_drawn: bool,
+  depth_format: vk::Format,
+  // window size, or the requested size offscreen. Render targets are this times scale
+  output_resolution: vk::Extent2D,
+  render_scale: RenderScale,
+  scale: f32,
+  // None when the GPU can't time frames
+  frame_timer: Option<FrameTimer>,
+  // the frame buffers hold command buffers of render_cmd_pool so they have to drop first
+  frames: Vec<FrameResources>,
+  frame_idx: usize,
+  // a zero sized window can't have a swapchain, draws are skipped till the next resize
+  paused: bool,
+  // the surface is gone between suspend and resume, draws are skipped meanwhile
+  suspended: bool,
   render_cmd_pool: AdCommandPool,
-  image: AdAllocatedImage,
   allocator: Arc<Mutex<Allocator>>,
-  transfer_manager: TransferManager,
-  present_manager: PresentManager,
-  vk_context: Arc<vk_context::VkContext>,
+  descriptor_allocator: Arc<Mutex<DescriptorAllocator>>,
+  transfer_manager: Arc<TransferManager>,
+  present_manager: Option<PresentManager>,
+  present_config: PresentConfig,
+  vk_context: Arc<VkContext>,
 }
 
 impl Renderer {
@@ -34,127 +183,175 @@ impl Renderer {
     window: &(impl HasWindowHandle + HasDisplayHandle),
     resolution_x: u32,
     resolution_y: u32,
+  ) -> Result<Self, String> {
+    Self::new_with_config(window, resolution_x, resolution_y, RendererConfig::default())
+  }
+
+  pub fn new_with_config(
+    window: &(impl HasWindowHandle + HasDisplayHandle),
+    resolution_x: u32,
+    resolution_y: u32,
+    config: RendererConfig,
   ) -> Result<Self, String> {
     let vk_loaders = Arc::new(VkLoaders::new()?);
     let surface = vk_loaders.make_surface(&window)?;
-    let vk_context = Arc::new(vk_context::VkContext::new(vk_loaders, surface, None)?);
+    let vk_context = Arc::new(VkContext::new(vk_loaders, surface, None)?);
     let present_manager = PresentManager::new(
       Arc::clone(&vk_context),
       surface,
       vk::Extent2D { width: resolution_x, height: resolution_y },
+      config.present.clone(),
     )
     .map_err(|e| format!("{e}"))?;
 
-    let transfer_manager = TransferManager::new(Arc::clone(&vk_context))?;
+    Self::new_with_context(
+      RenderContext::new(vk_context)?,
+      Some(present_manager),
+      vk::Extent2D { width: resolution_x.max(1), height: resolution_y.max(1) },
+      config,
+    )
+  }
 
-    let allocator = Arc::new(Mutex::new(vk_context.create_allocator()?));
+  /*
+  Renderer for another window, sharing the device, allocators and transfer manager of context.
+  The window gets its own swapchain, meshes and materials aren't shared.
+   */
+  pub fn new_sharing(
+    context: &RenderContext,
+    window: &(impl HasWindowHandle + HasDisplayHandle),
+    resolution_x: u32,
+    resolution_y: u32,
+    config: RendererConfig,
+  ) -> Result<Self, String> {
+    let present_manager = PresentManager::new(
+      Arc::clone(&context.vk_context),
+      context.make_surface(window)?,
+      vk::Extent2D { width: resolution_x, height: resolution_y },
+      config.present.clone(),
+    )
+    .map_err(|e| format!("{e}"))?;
+    Self::new_with_context(
+      context.clone(),
+      Some(present_manager),
+      vk::Extent2D { width: resolution_x.max(1), height: resolution_y.max(1) },
+      config,
+    )
+  }
+
+  /*
+  Renderer without a window or swapchain, draws into its own attachment image.
+  Use read_back to get the last drawn frame on the CPU, it's at the scaled render resolution.
+   */
+  pub fn new_offscreen(width: u32, height: u32) -> Result<Self, String> {
+    Self::new_offscreen_with_config(width, height, RendererConfig::default())
+  }
+
+  pub fn new_offscreen_with_config(
+    width: u32,
+    height: u32,
+    config: RendererConfig,
+  ) -> Result<Self, String> {
+    let vk_loaders = Arc::new(VkLoaders::new_headless()?);
+    let vk_context = Arc::new(VkContext::new_headless(vk_loaders, None)?);
+    let context = RenderContext::new(vk_context)?;
+    Self::new_with_context(context, None, vk::Extent2D { width, height }, config)
+  }
+
+  fn new_with_context(
+    context: RenderContext,
+    present_manager: Option<PresentManager>,
+    output_resolution: vk::Extent2D,
+    config: RendererConfig,
+  ) -> Result<Self, String> {
+    if config.frames_in_flight == 0 {
+      return Err("renderer needs at least one frame in flight".to_string());
+    }
+    config.render_scale.validate()?;
+    let scale = config.render_scale.initial_scale(1.0);
+    let RenderContext {
+      vk_context,
+      allocator,
+      descriptor_allocator,
+      transfer_manager,
+      default_environment_map,
+    } = context;
 
-    let image_path = PathBuf::from("./tile_tex.png");
-    let image =
-      transfer_manager.load_image_from_file(Arc::clone(&allocator), &image_path, "display_img")?;
+    let depth_format = vk_context.select_depth_format()?;
 
+    let mesh_render_pass =
+      create_deferred_render_pass(&vk_context, OUTPUT_FORMAT, depth_format)?;
+
+    let mesh_pipeline = make_vert_mesh_pbr_pipeline(
+      Arc::clone(&vk_context.device),
+      Arc::clone(&descriptor_allocator),
+      mesh_render_pass.inner,
+      GEOMETRY_SUBPASS,
+    )?;
+    let alignment = vk_context.min_uniform_buffer_offset_alignment;
+    let frame_uniforms = UniformRing::new(
+      &vk_context,
+      Arc::clone(&allocator),
+      "frame_uniforms",
+      config.frames_in_flight,
+      UniformRing::aligned_size::<CameraTransforms>(alignment)
+        + MAX_MESHES as vk::DeviceSize * UniformRing::aligned_size::<glam::Mat4>(alignment),
+    )?;
+    let frame_sets = frame_uniforms
+      .buffers()
+      .iter()
+      .map(|buffer| {
+        let set = AdDescriptorSet::new(
+          Arc::clone(&descriptor_allocator),
+          &mesh_pipeline.set_layouts[CAMERA_SET as usize],
+        )?;
+        set
+          .write_builder()
+          .add_uniform_buffer_dynamic(
+            0,
+            buffer.inner,
+            0,
+            size_of::<CameraTransforms>() as vk::DeviceSize,
+          )
+          .add_uniform_buffer_dynamic(1, buffer.inner, 0, size_of::<glam::Mat4>() as vk::DeviceSize)
+          .write();
+        Ok(set)
+      })
+      .collect::<Result<Vec<_>, String>>()
+      .map_err(|e| format!("at frame descriptor sets: {e}"))?;
     let material = PbrMaterial::new(
+      &mesh_pipeline,
+      &vk_context,
       &transfer_manager,
       Arc::clone(&allocator),
       "tile_material",
-      "./tile_tex.png".as_ref()
+      &PbrTextures { albedo: Some(&config.asset_dir.join("tile_tex.png")), ..Default::default() },
+      PbrMaterialFactors { metallic: 0.0, ..Default::default() },
     )?;
-
-    let mesh_render_pass = vk_context
-      .create_ad_render_pass_builder(vk::RenderPassCreateFlags::default())
-      .add_attachment(
-        vk::AttachmentDescription::default()
-          .format(vk::Format::R8G8B8A8_UNORM)
-          .samples(vk::SampleCountFlags::TYPE_1)
-          .initial_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
-          .final_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
-      )
-      .add_sub_pass(
-        vk::SubpassDescription::default()
-          .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-          .color_attachments(&[vk::AttachmentReference::default()
-            .attachment(0)
-            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)]),
-      )
-      .add_sub_pass_dependency(
-        vk::SubpassDependency::default()
-          .src_subpass(vk::SUBPASS_EXTERNAL)
-          .dst_subpass(0)
-          .src_stage_mask(vk::PipelineStageFlags::TRANSFER)
-          .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-          .src_access_mask(vk::AccessFlags::TRANSFER_READ)
-          .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE),
-      )
-      .add_sub_pass_dependency(
-        vk::SubpassDependency::default()
-          .src_subpass(0)
-          .dst_subpass(vk::SUBPASS_EXTERNAL)
-          .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-          .dst_stage_mask(vk::PipelineStageFlags::TRANSFER)
-          .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
-          .dst_access_mask(vk::AccessFlags::TRANSFER_READ),
-      )
-      .build()?;
-
-    let attachment_image = AdAllocatedImage::new(
+    let mut lighting_pass = DeferredLightingPass::new(
       Arc::clone(&vk_context.device),
       Arc::clone(&allocator),
-      "attachment_image_allocation",
-      vk::ImageCreateInfo::default()
-        .image_type(vk::ImageType::TYPE_2D)
-        .format(vk::Format::R8G8B8A8_UNORM)
-        .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
-        .initial_layout(vk::ImageLayout::UNDEFINED)
-        .sharing_mode(vk::SharingMode::EXCLUSIVE)
-        .samples(vk::SampleCountFlags::TYPE_1)
-        .tiling(vk::ImageTiling::OPTIMAL)
-        .mip_levels(1)
-        .array_layers(1)
-        .extent(
-          vk::Extent3D::default()
-            .width(1280)
-            .height(720)
-            .depth(1),
-        ),
-        MemoryLocation::GpuOnly
+      Arc::clone(&descriptor_allocator),
+      mesh_render_pass.inner,
+      LIGHTING_SUBPASS,
+      config.frames_in_flight,
+      alignment,
+    )?;
+    lighting_pass.set_environment_map(&default_environment_map, 1.0);
+    let tonemap_pass = make_tonemap_pass(
+      Arc::clone(&vk_context.device),
+      Arc::clone(&descriptor_allocator),
+      mesh_render_pass.inner,
+      TONEMAP_SUBPASS,
     )?;
 
-    let attachment_image_view = unsafe {
-      vk_context
-        .device
-        .create_image_view(
-          &vk::ImageViewCreateInfo::default()
-            .format(attachment_image.format)
-            .image(attachment_image.inner)
-            .view_type(vk::ImageViewType::TYPE_2D)
-            .components(vk::ComponentMapping::default())
-            .subresource_range(
-              vk::ImageSubresourceRange::default()
-                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                .level_count(1)
-                .layer_count(1)
-                .base_mip_level(0)
-                .base_array_layer(0)
-            ),
-          None
-        )
-        .map_err(|e| format!("at attachment image view create: {e}"))?
-    };
-
-    let mesh_frame_buffer = unsafe {
-      vk_context
-        .device
-        .create_framebuffer(
-          &vk::FramebufferCreateInfo::default()
-            .render_pass(mesh_render_pass.inner)
-            .attachments(&[attachment_image_view])
-            .width(1280)
-            .height(720)
-            .layers(1),
-          None
-        )
-        .map_err(|e| format!("at frame buffer create: {e}"))?
-    };
+    let render_targets = RenderTargets::new(
+      Arc::clone(&vk_context),
+      Arc::clone(&allocator),
+      mesh_render_pass.inner,
+      OUTPUT_FORMAT,
+      depth_format,
+      scaled_resolution(output_resolution, scale),
+    )?;
 
     let render_cmd_pool = vk_context
       .create_ad_command_pool(
@@ -163,24 +360,178 @@ impl Renderer {
           .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER),
       )?;
 
-    let render_cmd_buffer = render_cmd_pool
-      .allocate_command_buffers(vk::CommandBufferLevel::PRIMARY, 1)?
-      .swap_remove(0);
+    let frames = render_cmd_pool
+      .allocate_command_buffers(vk::CommandBufferLevel::PRIMARY, config.frames_in_flight as u32)?
+      .into_iter()
+      .map(|cmd_buffer| {
+        Ok(FrameResources {
+          cmd_buffer,
+          in_flight_fence: vk_context.create_ad_fence()?,
+          render_done: vk_context.create_ad_semaphore()?,
+          submitted: false,
+        })
+      })
+      .collect::<Result<Vec<_>, String>>()
+      .map_err(|e| format!("at frame resources: {e}"))?;
+    let frame_timer = FrameTimer::new(Arc::clone(&vk_context), config.frames_in_flight)?;
 
-    Ok(Self {
+    let renderer = Self {
+      mesh_pipeline,
+      lighting_pass,
+      environment_map: Arc::clone(&default_environment_map),
+      default_environment_map,
+      tonemap_pass,
       mesh_render_pass,
       material,
+      meshes: vec![],
+      camera_transforms: CameraTransforms {
+        view: glam::Mat4::IDENTITY,
+        proj: glam::Mat4::IDENTITY,
+      },
+      frame_sets,
+      frame_uniforms,
       vk_context,
       present_manager,
+      present_config: config.present,
       transfer_manager,
-      image,
       allocator,
-      attachment_image,
-      attachment_image_view,
-      mesh_frame_buffer,
+      descriptor_allocator,
+      render_targets,
+      render_targets_drawn: false,
+      depth_format,
+      output_resolution,
+      render_scale: config.render_scale,
+      scale,
+      frame_timer,
+      frames,
+      frame_idx: 0,
+      paused: false,
+      suspended: false,
       render_cmd_pool,
-      render_cmd_buffer,
-    })
+    };
+    renderer.write_render_target_descriptors();
+    Ok(renderer)
+  }
+
+  // input attachment descriptors of the lighting and tonemap subpasses point at the render targets
+  fn write_render_target_descriptors(&self) {
+    self.lighting_pass.write_input_attachments(
+      self.render_targets.albedo_image_view,
+      self.render_targets.normal_image_view,
+      self.render_targets.metallic_roughness_image_view,
+      self.render_targets.depth_image_view,
+      self.render_targets.emissive_image_view,
+    );
+    self.tonemap_pass.write_input_attachments(
+      0,
+      &[(self.render_targets.hdr_image_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)],
+    );
+  }
+
+  // view the scene through camera from the next draw on
+  pub fn set_camera(&mut self, camera: &Camera3D) {
+    self.camera_transforms = CameraTransforms {
+      view: camera.get_view_matrix(),
+      proj: camera.get_perspective_matrix(),
+    };
+    self.lighting_pass.set_camera(
+      self.camera_transforms.proj * self.camera_transforms.view,
+      camera.eye.truncate(),
+    );
+  }
+
+  /*
+  Upload a mesh to draw with the tile material, placed by the model matrix.
+  Returns the index set_mesh_transform takes.
+   */
+  pub fn add_mesh(&mut self, mesh: &Mesh, model: glam::Mat4) -> Result<usize, String> {
+    if self.meshes.len() == MAX_MESHES {
+      return Err(format!("renderer already has the max of {MAX_MESHES} meshes"));
+    }
+    let mesh_idx = self.meshes.len();
+    let vert_mesh = VertMesh::from_mesh(
+      mesh,
+      &self.transfer_manager,
+      Arc::clone(&self.allocator),
+      &format!("mesh_{mesh_idx}"),
+    )?;
+    self.meshes.push((vert_mesh, model));
+    Ok(mesh_idx)
+  }
+
+  // takes effect from the next draw, frames in flight keep the matrix they were drawn with
+  pub fn set_mesh_transform(&mut self, mesh_idx: usize, model: glam::Mat4) -> Result<(), String> {
+    self
+      .meshes
+      .get_mut(mesh_idx)
+      .map(|(_, mesh_model)| *mesh_model = model)
+      .ok_or(format!("no mesh at index {mesh_idx}"))
+  }
+
+  /*
+  Lights resolved by the lighting subpass, more than MAX_LIGHTS is an error.
+  Takes effect from the next draw.
+   */
+  pub fn set_lights(&mut self, lights: &[Light]) -> Result<(), String> {
+    self.lighting_pass.set_lights(lights)
+  }
+
+  // constant light scaled by material occlusion, for surfaces no light reaches
+  pub fn set_ambient_light(&mut self, color: glam::Vec3) {
+    self.lighting_pass.set_ambient(color);
+  }
+
+  /*
+  Light the scene with an equirectangular .hdr or .exr environment, scaled by intensity.
+  Replaces the previous environment map, waits for this renderer's frames in flight and blocks
+  while baking. Other renderers sharing the render context keep drawing.
+   */
+  pub fn load_environment_map(&mut self, path: &Path, intensity: f32) -> Result<(), String> {
+    let environment_map =
+      self.transfer_manager.load_environment_map(Arc::clone(&self.allocator), path, "environment")?;
+    // the lighting set still points at the old map in the frames in flight
+    self.wait_for_frames_in_flight()?;
+    self.lighting_pass.set_environment_map(&environment_map, intensity);
+    self.environment_map = Arc::new(environment_map);
+    Ok(())
+  }
+
+  /*
+  Destroy the swapchain and surface, for when the app is suspended and the window's surface
+  goes away. GPU resources are kept, draws are skipped till resume.
+   */
+  pub fn suspend(&mut self) {
+    // dropping waits for the presents in flight
+    self.present_manager = None;
+    self.suspended = true;
+  }
+
+  // rebuild the surface and swapchain against the window, at its current size
+  pub fn resume(
+    &mut self,
+    window: &(impl HasWindowHandle + HasDisplayHandle),
+    resolution_x: u32,
+    resolution_y: u32,
+  ) -> Result<(), String> {
+    self.refresh_surface(window, resolution_x, resolution_y)?;
+    self.suspended = false;
+    self.paused = resolution_x == 0 || resolution_y == 0;
+    if self.paused {
+      return Ok(());
+    }
+    self.output_resolution = vk::Extent2D { width: resolution_x, height: resolution_y };
+    self.apply_render_scale()
+  }
+
+  // for creating renderers of other windows with new_sharing
+  pub fn render_context(&self) -> RenderContext {
+    RenderContext {
+      vk_context: Arc::clone(&self.vk_context),
+      allocator: Arc::clone(&self.allocator),
+      descriptor_allocator: Arc::clone(&self.descriptor_allocator),
+      transfer_manager: Arc::clone(&self.transfer_manager),
+      default_environment_map: Arc::clone(&self.default_environment_map),
+    }
   }
 
   pub fn refresh_surface(
@@ -189,60 +540,350 @@ impl Renderer {
     resolution_x: u32,
     resolution_y: u32,
   ) -> Result<(), String> {
-    let surface = self.vk_context.vk_loaders.make_surface(&window)?;
-    let surface_support = unsafe {
-      self
-        .vk_context
-        .vk_loaders
-        .surface_driver
-        .get_physical_device_surface_support(
-          self.vk_context.gpu,
-          self.vk_context.present_q_idx,
-          surface,
-        )
-        .map_err(|e| format!("{e}"))?
-    };
-    if surface_support {
-      self.present_manager = PresentManager::new(
+    let surface = self.render_context().make_surface(window)?;
+    self.present_manager = Some(
+      PresentManager::new(
         Arc::clone(&self.vk_context),
         surface,
         vk::Extent2D { width: resolution_x, height: resolution_y },
+        self.present_config.clone(),
       )
-      .map_err(|e| format!("{e}"))?;
-      Ok(())
-    } else {
-      Err("New surface unsupported by renderer, please restart app".to_string())
+      .map_err(|e| format!("{e}"))?,
+    );
+    Ok(())
+  }
+
+  // vsync, present modes and swapchain image count, the swapchain is recreated right away
+  pub fn set_present_config(&mut self, config: PresentConfig) -> Result<(), String> {
+    self.present_config = config;
+    match self.present_manager.as_mut() {
+      Some(present_manager) => {
+        present_manager.set_config(self.present_config.clone()).map_err(|e| format!("{e}"))
+      }
+      None => Ok(()),
+    }
+  }
+
+  /*
+  Follow a window resize, the swapchain and the render targets take the new size.
+  A zero sized (minimized) window pauses drawing till it's resized again.
+   */
+  pub fn resize(&mut self, resolution_x: u32, resolution_y: u32) -> Result<(), String> {
+    self.paused = resolution_x == 0 || resolution_y == 0;
+    if self.paused {
+      return Ok(());
+    }
+    if self.present_manager.is_some() {
+      self.resize_swapchain(resolution_x, resolution_y)?;
+    }
+    self.output_resolution = vk::Extent2D { width: resolution_x, height: resolution_y };
+    self.apply_render_scale()
+  }
+
+  /*
+  Render at a factor of the window size, validated against MIN_RENDER_SCALE and
+  MAX_RENDER_SCALE. Changing the render resolution waits for the GPU to be idle.
+   */
+  pub fn set_render_scale(&mut self, render_scale: RenderScale) -> Result<(), String> {
+    render_scale.validate()?;
+    self.render_scale = render_scale;
+    self.scale = render_scale.initial_scale(self.scale);
+    if let Some(frame_timer) = self.frame_timer.as_mut() {
+      frame_timer.reset();
+    }
+    self.apply_render_scale()
+  }
+
+  // factor of the window size currently rendered at, moves over time with a dynamic scale
+  pub fn current_render_scale(&self) -> f32 {
+    self.scale
+  }
+
+  pub fn render_resolution(&self) -> vk::Extent2D {
+    self.render_targets.resolution
+  }
+
+  fn apply_render_scale(&mut self) -> Result<(), String> {
+    let resolution = scaled_resolution(self.output_resolution, self.scale);
+    if self.render_targets.resolution != resolution {
+      self.resize_render_targets(resolution.width, resolution.height)?;
     }
+    Ok(())
+  }
+
+  // true while a zero sized window pauses drawing
+  pub fn is_paused(&self) -> bool {
+    self.paused
   }
 
   pub fn resize_swapchain(&mut self, resolution_x: u32, resolution_y: u32) -> Result<(), String> {
     self
       .present_manager
+      .as_mut()
+      .ok_or("offscreen renderer has no swapchain".to_string())?
       .refresh_swapchain(vk::Extent2D { width: resolution_x, height: resolution_y })
       .map_err(|e| format!("{e}"))
   }
 
+  /*
+  Recreate the render targets at a new render resolution.
+  Waits for this renderer's frames and presents in flight as they may still use the old
+  attachments, other renderers on the device keep going.
+   */
+  pub fn resize_render_targets(&mut self, resolution_x: u32, resolution_y: u32) -> Result<(), String> {
+    self.wait_for_frames_in_flight()?;
+    self.render_targets = RenderTargets::new(
+      Arc::clone(&self.vk_context),
+      Arc::clone(&self.allocator),
+      self.mesh_render_pass.inner,
+      self.render_targets.color_image.format,
+      self.depth_format,
+      vk::Extent2D { width: resolution_x, height: resolution_y },
+    )?;
+    self.render_targets_drawn = false;
+    self.write_render_target_descriptors();
+    Ok(())
+  }
+
+  // the frames' fences are left unsignaled, so the next draws don't wait on them again
+  fn wait_for_frames_in_flight(&mut self) -> Result<(), String> {
+    for frame in self.frames.iter_mut().filter(|frame| frame.submitted) {
+      unsafe {
+        self
+          .vk_context
+          .device
+          .wait_for_fences(&[frame.in_flight_fence.inner], true, u64::MAX)
+          .map_err(|e| format!("at waiting for frame fence: {e}"))?;
+        self
+          .vk_context
+          .device
+          .reset_fences(&[frame.in_flight_fence.inner])
+          .map_err(|e| format!("at frame fence reset: {e}"))?;
+      }
+      frame.submitted = false;
+    }
+    if let Some(present_manager) = self.present_manager.as_mut() {
+      present_manager.wait_for_present();
+    }
+    Ok(())
+  }
+
+  fn submit_and_wait(&self, cmd_buffer: &AdCommandBuffer) -> Result<(), String> {
+    unsafe {
+      let fence = self.vk_context.create_ad_fence()?;
+      self
+        .vk_context
+        .queue_submit(
+          self.vk_context.graphics_q,
+          &[vk::SubmitInfo::default().command_buffers(&[cmd_buffer.inner])],
+          fence.inner,
+        )
+        .map_err(|e| format!("at graphics queue submit: {e}"))?;
+      self
+        .vk_context
+        .device
+        .wait_for_fences(&[fence.inner], true, u64::MAX)
+        .map_err(|e| format!("at waiting for fence: {e}"))?;
+    }
+    Ok(())
+  }
+
+  /*
+  Copy the render attachment to the CPU. Only meant for offscreen renderers,
+  blocks till the copy is done.
+   */
+  pub fn read_back(&self) -> Result<image::RgbaImage, String> {
+    if !self.render_targets_drawn {
+      return Err("nothing drawn to read back, draw first".to_string());
+    }
+    let resolution = self.render_targets.color_image.resolution;
+    let read_back_buffer = AdAllocatedBuffer::new(
+      Arc::clone(&self.vk_context.device),
+      Arc::clone(&self.allocator),
+      "read_back_buffer",
+      vk::BufferCreateInfo::default()
+        .usage(vk::BufferUsageFlags::TRANSFER_DST)
+        .size((resolution.width * resolution.height * 4) as vk::DeviceSize),
+      MemoryLocation::GpuToCpu,
+    )?;
+
+    let cmd_buffer = self
+      .render_cmd_pool
+      .allocate_command_buffers(vk::CommandBufferLevel::PRIMARY, 1)?
+      .swap_remove(0);
+    cmd_buffer.begin(vk::CommandBufferBeginInfo::default())?;
+    cmd_buffer.copy_image_to_buffer(
+      self.render_targets.color_image.inner,
+      vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+      read_back_buffer.inner,
+      &[vk::BufferImageCopy::default()
+        .image_subresource(
+          vk::ImageSubresourceLayers::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(1),
+        )
+        .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+        .image_extent(resolution)],
+    );
+    // makes the copied pixels visible to the mapped memory read below
+    cmd_buffer.pipeline_barrier(
+      vk::PipelineStageFlags::TRANSFER,
+      vk::PipelineStageFlags::HOST,
+      vk::DependencyFlags::empty(),
+      &[],
+      &[vk::BufferMemoryBarrier::default()
+        .buffer(read_back_buffer.inner)
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::HOST_READ)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .offset(0)
+        .size(vk::WHOLE_SIZE)],
+      &[],
+    );
+    cmd_buffer.end()?;
+    self.submit_and_wait(&cmd_buffer)?;
+
+    let pixels = read_back_buffer
+      .allocation
+      .as_ref()
+      .ok_or("read back buffer not allocated".to_string())?
+      .mapped_slice()
+      .ok_or("at mapping read back buffer memory to CPU".to_string())?
+      .to_vec();
+    image::RgbaImage::from_raw(resolution.width, resolution.height, pixels)
+      .ok_or("read back buffer too small for attachment".to_string())
+  }
+
+  /*
+  Record and submit the next frame in flight, and present it if there's a swapchain.
+  Returns true when the swapchain is out of date and needs a resize before presenting.
+  Does nothing while paused or suspended.
+   */
   pub fn draw(&mut self) -> Result<bool, String> {
-    self.render_cmd_buffer.begin(vk::CommandBufferBeginInfo::default())?;
-    self.render_cmd_buffer.begin_render_pass(
+    if self.paused || self.suspended {
+      return Ok(false);
+    }
+    let color_clear = vk::ClearValue { color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 1.0] } };
+    // ordered as the render targets attachments, the output is fully overwritten so its value is unused
+    let clear_values = [
+      color_clear,
+      vk::ClearValue { depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 } },
+      color_clear,
+      color_clear,
+      color_clear,
+      color_clear,
+      color_clear,
+    ];
+
+    // the frame's fence is unsignaled till its first submission
+    self.frame_idx = (self.frame_idx + 1) % self.frames.len();
+    let frame = &mut self.frames[self.frame_idx];
+    if frame.submitted {
+      unsafe {
+        self
+          .vk_context
+          .device
+          .wait_for_fences(&[frame.in_flight_fence.inner], true, u64::MAX)
+          .map_err(|e| format!("at waiting for frame fence: {e}"))?;
+        self
+          .vk_context
+          .device
+          .reset_fences(&[frame.in_flight_fence.inner])
+          .map_err(|e| format!("at frame fence reset: {e}"))?;
+      }
+      frame.submitted = false;
+      if let Some(frame_timer) = self.frame_timer.as_mut() {
+        let frame_time = frame_timer.read(self.frame_idx)?;
+        if let Some(scale) = frame_timer.next_scale(self.render_scale, self.scale, frame_time) {
+          self.scale = scale;
+          self.apply_render_scale()?;
+        }
+      }
+    }
+
+    // after the frame timing, which may have changed the render resolution
+    let render_area = vk::Rect2D::default().extent(self.render_targets.resolution);
+
+    self.frame_uniforms.begin_frame(self.frame_idx);
+    let camera_offset = self.frame_uniforms.push(&self.camera_transforms)?;
+    let model_offsets = self
+      .meshes
+      .iter()
+      .map(|(_, model)| self.frame_uniforms.push(model))
+      .collect::<Result<Vec<_>, _>>()?;
+    let lights_offset = self.lighting_pass.write_frame_uniform(self.frame_idx)?;
+    let frame_set = self.frame_sets[self.frame_idx].inner;
+
+    let frame = &self.frames[self.frame_idx];
+    let cmd_buffer = &frame.cmd_buffer;
+    cmd_buffer.begin(vk::CommandBufferBeginInfo::default())?;
+    if let Some(frame_timer) = self.frame_timer.as_ref() {
+      frame_timer.begin(cmd_buffer, self.frame_idx);
+    }
+    cmd_buffer.begin_render_pass(
       vk::RenderPassBeginInfo::default()
         .render_pass(self.mesh_render_pass.inner)
-        .framebuffer(self.mesh_frame_buffer)
-        .render_area(
-          vk::Rect2D::default()
-            .extent(
-              vk::Extent2D::default()
-                .width(self.attachment_image.resolution.width)
-                .height(self.attachment_image.resolution.height)
-            )
-        ),
+        .clear_values(&clear_values)
+        .framebuffer(self.render_targets.frame_buffer)
+        .render_area(render_area),
       vk::SubpassContents::INLINE
     );
-    self.render_cmd_buffer.end_render_pass();
-    self.render_cmd_buffer.end()?;
+    cmd_buffer.set_viewport(&[vk::Viewport::default()
+      .width(render_area.extent.width as f32)
+      .height(render_area.extent.height as f32)
+      .min_depth(0.0)
+      .max_depth(1.0)]);
+    cmd_buffer.set_scissor(&[render_area]);
+    if !self.meshes.is_empty() {
+      cmd_buffer.bind_pipeline(vk::PipelineBindPoint::GRAPHICS, self.mesh_pipeline.pipeline);
+      self.material.bind(cmd_buffer, &self.mesh_pipeline);
+    }
+    for ((mesh, _), model_offset) in self.meshes.iter().zip(model_offsets) {
+      cmd_buffer.bind_descriptor_sets(
+        vk::PipelineBindPoint::GRAPHICS,
+        self.mesh_pipeline.pipeline_layout,
+        CAMERA_SET,
+        &[frame_set],
+        &[camera_offset, model_offset],
+      );
+      mesh.draw(cmd_buffer);
+    }
+    cmd_buffer.next_subpass(vk::SubpassContents::INLINE);
+    self.lighting_pass.draw(cmd_buffer, lights_offset);
+    cmd_buffer.next_subpass(vk::SubpassContents::INLINE);
+    self.tonemap_pass.draw(cmd_buffer, &[]);
+    cmd_buffer.end_render_pass();
+    if let Some(frame_timer) = self.frame_timer.as_ref() {
+      frame_timer.end(cmd_buffer, self.frame_idx);
+    }
+    cmd_buffer.end()?;
+
+    // offscreen nothing waits on the render, read_back waits on the queue instead
+    let render_done = [frame.render_done.inner];
+    let signal_semaphores: &[vk::Semaphore] =
+      if self.present_manager.is_some() { &render_done } else { &[] };
+    self
+      .vk_context
+      .queue_submit(
+        self.vk_context.graphics_q,
+        &[vk::SubmitInfo::default()
+          .command_buffers(&[cmd_buffer.inner])
+          .signal_semaphores(signal_semaphores)],
+        frame.in_flight_fence.inner,
+      )
+      .map_err(|e| format!("at render submit: {e}"))?;
+    self.frames[self.frame_idx].submitted = true;
+    self.render_targets_drawn = true;
 
-    match self.present_manager.present_image_content(
-      &self.image,
+    let Some(present_manager) = self.present_manager.as_mut() else {
+      return Ok(false);
+    };
+    let color_image = &self.render_targets.color_image;
+    match present_manager.present_image_content(
+      color_image,
       vk::ImageSubresourceLayers::default()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
         .mip_level(0)
@@ -251,13 +892,13 @@ impl Renderer {
       [
         vk::Offset3D { x: 0, y: 0, z: 0 },
         vk::Offset3D {
-          x: self.image.resolution.width as i32,
-          y: self.image.resolution.height as i32,
-          z: self.image.resolution.depth as i32,
+          x: color_image.resolution.width as i32,
+          y: color_image.resolution.height as i32,
+          z: color_image.resolution.depth as i32,
         },
       ],
-      vk::Filter::NEAREST,
-      vec![],
+      vk::Filter::LINEAR,
+      render_done.to_vec(),
     ) {
       Ok(_) => {}
       Err(e) => match e {
@@ -273,10 +914,13 @@ impl Renderer {
 
 impl Drop for Renderer {
   fn drop(&mut self) {
-    unsafe {
-      self.present_manager.wait_for_present();
-      self.vk_context.device.destroy_framebuffer(self.mesh_frame_buffer, None);
-      self.vk_context.device.destroy_image_view(self.attachment_image_view, None);
+    if let Some(present_manager) = self.present_manager.as_mut() {
+      present_manager.wait_for_present();
     }
+    // frames in flight still use the command buffers, sets and uniforms dropped after this
+    let _ = self
+      .vk_context
+      .device_wait_idle()
+      .inspect_err(|e| eprintln!("at waiting for device idle on renderer drop: {e}"));
   }
 }
@@ -15,6 +15,102 @@ pub enum PresentManagerError {
   PresentError(String),
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VSync {
+  // waits for vertical blank, never tears
+  #[default]
+  On,
+  // presents as soon as the image is ready, may tear
+  Off,
+  // waits for vertical blank unless the frame is late, late frames may tear
+  Adaptive,
+}
+
+impl VSync {
+  // in order of preference, FIFO is the fallback for all of them
+  fn present_modes(self) -> &'static [vk::PresentModeKHR] {
+    match self {
+      VSync::On => &[vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::FIFO],
+      VSync::Off => &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX],
+      VSync::Adaptive => &[vk::PresentModeKHR::FIFO_RELAXED],
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScalingMode {
+  // the source covers the whole window, distorted when the aspect ratios differ
+  Stretch,
+  // the whole source is shown with its aspect ratio, bars get the letterbox color
+  #[default]
+  Fit,
+  // the window is covered keeping the aspect ratio, the source is cropped at its edges
+  Fill,
+  // largest whole number scale that fits, nearest filtered. Fit if the source doesn't fit
+  IntegerScale,
+}
+
+impl ScalingMode {
+  // source and destination offsets to blit src_range onto a dst sized image with
+  fn blit_offsets(
+    self,
+    src_range: [vk::Offset3D; 2],
+    dst: vk::Extent2D,
+  ) -> ([vk::Offset3D; 2], [vk::Offset3D; 2]) {
+    let src_width = (src_range[1].x - src_range[0].x) as f32;
+    let src_height = (src_range[1].y - src_range[0].y) as f32;
+    let (dst_width, dst_height) = (dst.width as f32, dst.height as f32);
+    let fit_scale = (dst_width / src_width).min(dst_height / src_height);
+    let centered = |width: f32, height: f32| {
+      let x = ((dst_width - width) / 2.0).round() as i32;
+      let y = ((dst_height - height) / 2.0).round() as i32;
+      [
+        vk::Offset3D { x, y, z: 0 },
+        vk::Offset3D { x: x + width.round() as i32, y: y + height.round() as i32, z: 1 },
+      ]
+    };
+    match self {
+      ScalingMode::Stretch => (src_range, centered(dst_width, dst_height)),
+      ScalingMode::Fit => (src_range, centered(src_width * fit_scale, src_height * fit_scale)),
+      ScalingMode::Fill => {
+        let scale = (dst_width / src_width).max(dst_height / src_height);
+        let (crop_width, crop_height) = (dst_width / scale, dst_height / scale);
+        let x = src_range[0].x + ((src_width - crop_width) / 2.0).round() as i32;
+        let y = src_range[0].y + ((src_height - crop_height) / 2.0).round() as i32;
+        let crop = [
+          vk::Offset3D { x, y, z: src_range[0].z },
+          vk::Offset3D {
+            x: x + crop_width.round() as i32,
+            y: y + crop_height.round() as i32,
+            z: src_range[1].z,
+          },
+        ];
+        (crop, centered(dst_width, dst_height))
+      }
+      ScalingMode::IntegerScale if fit_scale < 1.0 => ScalingMode::Fit.blit_offsets(src_range, dst),
+      ScalingMode::IntegerScale => {
+        let scale = fit_scale.floor();
+        (src_range, centered(src_width * scale, src_height * scale))
+      }
+    }
+  }
+}
+
+/*
+Present modes in preferred_present_modes are tried in order, then the ones matching vsync,
+then FIFO which every surface supports.
+image_count is clamped to what the surface supports, None asks for one over its minimum.
+letterbox_color is the linear color of the window outside the blitted source.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct PresentConfig {
+  pub vsync: VSync,
+  pub preferred_present_modes: Vec<vk::PresentModeKHR>,
+  pub image_count: Option<u32>,
+  pub scaling: ScalingMode,
+  pub letterbox_color: [f32; 4],
+}
+
 pub struct PresentManager {
   vk_context: Arc<VkContext>,
   swapchain_device: khr::swapchain::Device,
@@ -23,23 +119,43 @@ pub struct PresentManager {
   cmd_pool: AdCommandPool,
   acquire_image_sem_list: Vec<AdSemaphore>,
   image_blit_sem_list: Vec<AdSemaphore>,
-  image_blit_fences: Vec<AdFence>,
+  // per acquire slot, like cmd_buffers, signaled by the blit that waits on the slot's
+  // acquire semaphore
+  blit_fences: Vec<AdFence>,
   images: Vec<PWImage>,
   swapchain: vk::SwapchainKHR,
   resolution: vk::Extent2D,
   presenting_image: Option<u32>,
-  images_init_done: [bool; 3],
-  cmd_buffer_init_done: [bool; 3],
+  // the acquire semaphores, blit command buffers and fences are cycled through on their own,
+  // the image index is only known after acquiring
+  acquire_idx: usize,
+  images_init_done: Vec<bool>,
+  // the slot's blit was submitted, so its fence will signal
+  cmd_buffer_init_done: Vec<bool>,
+  config: PresentConfig,
 }
 
 impl PresentManager {
+  /*
+  Blits decode sRGB source images and encode into sRGB destinations, only an sRGB format
+  keeps the presented colors as rendered. Other formats of the sRGB color space come out
+  too dark and are a fallback.
+   */
   fn select_surface_format(formats: Vec<vk::SurfaceFormatKHR>) -> vk::SurfaceFormatKHR {
-    for format in &formats {
-      if format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR {
-        return *format;
-      }
-    }
-    formats[0]
+    let srgb_color_space =
+      |format: &&vk::SurfaceFormatKHR| format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR;
+    formats
+      .iter()
+      .filter(srgb_color_space)
+      .find(|format| {
+        matches!(
+          format.format,
+          vk::Format::B8G8R8A8_SRGB | vk::Format::R8G8B8A8_SRGB | vk::Format::A8B8G8R8_SRGB_PACK32
+        )
+      })
+      .or_else(|| formats.iter().find(srgb_color_space))
+      .copied()
+      .unwrap_or(formats[0])
   }
 
   pub fn refresh_swapchain(&mut self, new_size: vk::Extent2D) -> Result<(), PresentManagerError> {
@@ -67,15 +183,22 @@ impl PresentManager {
         .surface_driver
         .get_physical_device_surface_present_modes(self.vk_context.gpu, self.surface)
         .map_err(|e| PresentManagerError::RefreshError(format!("can't get present modes :{e}")))?;
-      let present_mode = present_modes
+      let present_mode = self
+        .config
+        .preferred_present_modes
         .iter()
-        .find(|mode| **mode == vk::PresentModeKHR::MAILBOX)
-        .cloned()
+        .chain(self.config.vsync.present_modes())
+        .find(|mode| present_modes.contains(mode))
+        .copied()
         .unwrap_or(vk::PresentModeKHR::FIFO);
       (surface_format, surface_caps, present_mode)
     };
 
-    let mut desired_image_count = surface_caps.min_image_count + 1;
+    let mut desired_image_count = self
+      .config
+      .image_count
+      .unwrap_or(surface_caps.min_image_count + 1)
+      .max(surface_caps.min_image_count);
     if surface_caps.max_image_count > 0 && desired_image_count > surface_caps.max_image_count {
       desired_image_count = surface_caps.max_image_count;
     }
@@ -106,6 +229,8 @@ impl PresentManager {
       .clipped(true)
       .image_array_layers(1);
 
+    // the old images might still be blitted to, and their count can change
+    self.wait_for_present();
     unsafe {
       let new_swapchain = self
         .swapchain_device
@@ -130,16 +255,48 @@ impl PresentManager {
       self.resolution = new_resolution;
       self.swapchain = new_swapchain;
       self.images = new_images;
-      self.images_init_done = [false; 3];
     }
+    // the driver may give more images than asked for
+    if self.images.len() != self.cmd_buffers.len() {
+      self.create_image_resources(self.images.len()).map_err(PresentManagerError::RefreshError)?;
+    }
+    self.images_init_done = vec![false; self.images.len()];
     Ok(())
   }
 
+  // an acquire slot and blit semaphore per swapchain image, no blit may be in flight
+  fn create_image_resources(&mut self, image_count: usize) -> Result<(), String> {
+    self.cmd_buffers = self
+      .cmd_pool
+      .allocate_command_buffers(vk::CommandBufferLevel::PRIMARY, image_count as u32)?;
+    self.acquire_image_sem_list = (0..image_count)
+      .map(|_| self.vk_context.create_ad_semaphore())
+      .collect::<Result<_, _>>()?;
+    self.image_blit_sem_list = (0..image_count)
+      .map(|_| self.vk_context.create_ad_semaphore())
+      .collect::<Result<_, _>>()?;
+    self.blit_fences =
+      (0..image_count).map(|_| self.vk_context.create_ad_fence()).collect::<Result<_, _>>()?;
+    self.cmd_buffer_init_done = vec![false; image_count];
+    self.acquire_idx = 0;
+    Ok(())
+  }
+
+  // takes effect right away, the swapchain is recreated at the current resolution
+  pub fn set_config(&mut self, config: PresentConfig) -> Result<(), PresentManagerError> {
+    self.config = config;
+    self.refresh_swapchain(self.resolution)
+  }
+
   pub fn new(
     vk_context: Arc<VkContext>,
     surface: vk::SurfaceKHR,
     size: vk::Extent2D,
+    config: PresentConfig,
   ) -> Result<Self, PresentManagerError> {
+    if vk_context.present_q.is_none() {
+      return Err(PresentManagerError::InitError("vk context has no present queue".to_string()));
+    }
     let swapchain_device =
       khr::swapchain::Device::new(&vk_context.vk_loaders.vk_driver, &vk_context.device);
 
@@ -149,58 +306,38 @@ impl PresentManager {
           .queue_family_index(vk_context.graphics_q_idx)
           .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER),
       )
-      .map_err(|e| PresentManagerError::InitError(format!("{e}")))?;
-
-    let cmd_buffers = cmd_pool
-      .allocate_command_buffers(vk::CommandBufferLevel::PRIMARY, 3)
-      .map_err(|e| PresentManagerError::InitError(format!("{e}")))?;
-
-    let mut acquire_image_semaphores = Vec::with_capacity(3);
-    for _ in 0..3 {
-      let new_semaphore =
-        vk_context.create_ad_semaphore().map_err(|e| PresentManagerError::RefreshError(e))?;
-      acquire_image_semaphores.push(new_semaphore);
-    }
-
-    let mut image_blit_semaphores = Vec::with_capacity(3);
-    for _ in 0..3 {
-      let new_semaphore =
-        vk_context.create_ad_semaphore().map_err(|e| PresentManagerError::RefreshError(e))?;
-      image_blit_semaphores.push(new_semaphore);
-    }
-
-    let mut image_blit_fences = Vec::with_capacity(3);
-    for _ in 0..3 {
-      let new_fence =
-        vk_context.create_ad_fence().map_err(|e| PresentManagerError::RefreshError(e))?;
-      image_blit_fences.push(new_fence);
-    }
+      .map_err(PresentManagerError::InitError)?;
 
     let mut out_data = Self {
       vk_context,
       swapchain_device,
       surface,
       cmd_pool,
-      cmd_buffers,
-      acquire_image_sem_list: acquire_image_semaphores,
-      image_blit_sem_list: image_blit_semaphores,
-      image_blit_fences,
+      cmd_buffers: vec![],
+      acquire_image_sem_list: vec![],
+      image_blit_sem_list: vec![],
+      blit_fences: vec![],
       images: vec![],
       swapchain: vk::SwapchainKHR::null(),
       resolution: vk::Extent2D::default(),
       presenting_image: None,
-      images_init_done: [false; 3],
-      cmd_buffer_init_done: [false; 3],
+      acquire_idx: 0,
+      images_init_done: vec![],
+      cmd_buffer_init_done: vec![],
+      config,
     };
     out_data.refresh_swapchain(size)?;
     Ok(out_data)
   }
 
   /*
-  Blit the source region specified to the entirety of the next present Image and present.
+  Blit the source region specified to the next present Image as the scaling mode says,
+  and present. This is where a render at a scaled resolution gets scaled to the window.
+  Integer scaling always blits with nearest filtering.
   Uses its own command buffer for the blit.
   So use only once per frame, unless swapchain needs refresh.
   Make sure Image in is Transfer Src Optimal layout before calling.
+  The blit waits on wait_for, they are waited on even if no image could be acquired.
    */
   pub fn present_image_content(
     &mut self,
@@ -210,15 +347,48 @@ impl PresentManager {
     filter: vk::Filter,
     mut wait_for: Vec<vk::Semaphore>,
   ) -> Result<(), PresentManagerError> {
+    // the slot's last blit waited on its acquire semaphore, which can't be reused before that
+    let slot = self.acquire_idx;
+    if self.cmd_buffer_init_done[slot] {
+      unsafe {
+        self
+          .vk_context
+          .device
+          .wait_for_fences(&[self.blit_fences[slot].inner], true, u64::MAX)
+          .map_err(|e| PresentManagerError::PresentError(format!("at fence wait: {e}")))?;
+        self
+          .vk_context
+          .device
+          .reset_fences(&[self.blit_fences[slot].inner])
+          .map_err(|e| PresentManagerError::PresentError(format!("at fence reset: {e}")))?;
+      }
+      self.cmd_buffer_init_done[slot] = false;
+    }
+    let acquire_semaphore = self.acquire_image_sem_list[slot].inner;
     let image_idx = unsafe {
       match self.swapchain_device.acquire_next_image(
         self.swapchain,
         999999999,
-        self.acquire_image_sem_list[self.presenting_image.unwrap_or(0) as usize].inner,
+        acquire_semaphore,
         vk::Fence::null(),
       ) {
         Ok(x) => x.0 as usize,
         Err(e) => {
+          // unwaited semaphores would stay signaled and can't be signaled again next frame
+          if !wait_for.is_empty() {
+            self
+              .vk_context
+              .queue_submit(
+                self.vk_context.graphics_q,
+                &[vk::SubmitInfo::default()
+                  .wait_semaphores(&wait_for)
+                  .wait_dst_stage_mask(&vec![vk::PipelineStageFlags::TRANSFER; wait_for.len()])],
+                vk::Fence::null(),
+              )
+              .map_err(|e| {
+                PresentManagerError::PresentError(format!("at waiting on skipped frame: {e}"))
+              })?;
+          }
           return if e == vk::Result::SUBOPTIMAL_KHR || e == vk::Result::ERROR_OUT_OF_DATE_KHR {
             Err(PresentManagerError::RefreshNeeded)
           } else {
@@ -247,9 +417,19 @@ impl PresentManager {
           .level_count(1)
           .base_mip_level(0),
       );
+    let dst_resolution = self.images[image_idx].resolution;
+    let (src_offsets, dst_offsets) = self.config.scaling.blit_offsets(
+      src_image_range,
+      vk::Extent2D { width: dst_resolution.width, height: dst_resolution.height },
+    );
+    let letterboxed = dst_offsets[0].x > 0 || dst_offsets[0].y > 0;
+    let filter = match self.config.scaling {
+      ScalingMode::IntegerScale => vk::Filter::NEAREST,
+      _ => filter,
+    };
     let blit_region = vk::ImageBlit::default()
       .src_subresource(src_subresource)
-      .src_offsets(src_image_range)
+      .src_offsets(src_offsets)
       .dst_subresource(
         vk::ImageSubresourceLayers::default()
           .aspect_mask(vk::ImageAspectFlags::COLOR)
@@ -257,13 +437,13 @@ impl PresentManager {
           .base_array_layer(0)
           .layer_count(1),
       )
-      .dst_offsets([
-        vk::Offset3D::default(),
-        vk::Offset3D::default()
-          .x(self.images[image_idx].resolution.width as i32)
-          .y(self.images[image_idx].resolution.height as i32)
-          .z(1),
-      ]);
+      .dst_offsets(dst_offsets);
+    let color_range = vk::ImageSubresourceRange::default()
+      .aspect_mask(vk::ImageAspectFlags::COLOR)
+      .layer_count(1)
+      .base_array_layer(0)
+      .level_count(1)
+      .base_mip_level(0);
     let barrier_after_blit = vk::ImageMemoryBarrier::default()
       .image(self.images[image_idx].inner)
       .src_queue_family_index(self.vk_context.graphics_q_idx)
@@ -280,105 +460,111 @@ impl PresentManager {
           .level_count(1)
           .base_mip_level(0),
       );
-    unsafe {
-      if self.cmd_buffer_init_done[image_idx] {
-        self
-          .vk_context
-          .device
-          .wait_for_fences(&[self.image_blit_fences[image_idx].inner], true, 15000000)
-          .map_err(|e| PresentManagerError::PresentError(format!("at fence wait: {e}")))?;
-        self
-          .vk_context
-          .device
-          .reset_fences(&[self.image_blit_fences[image_idx].inner])
-          .map_err(|e| PresentManagerError::PresentError(format!("at fence reset: {e}")))?;
-      }
-
-      self
-        .cmd_buffers[image_idx]
-        .begin(vk::CommandBufferBeginInfo::default())
-        .map_err(|e| PresentManagerError::PresentError(format!("at blit cmd record begin: {e}")))?;
-      self
-        .cmd_buffers[image_idx]
-        .pipeline_barrier(
-          vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-          vk::PipelineStageFlags::TRANSFER,
-          vk::DependencyFlags::BY_REGION,
-          &[],
-          &[],
-          &[barrier_before_blit],
-        );
-      self
-        .cmd_buffers[image_idx]
-        .blit_image(
-          src_image.inner,
-          vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-          self.images[image_idx].inner,
-          vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-          &[blit_region],
-          filter,
-        );
-      self
-        .cmd_buffers[image_idx]
-        .pipeline_barrier(
-          vk::PipelineStageFlags::TRANSFER,
-          vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-          vk::DependencyFlags::BY_REGION,
-          &[],
-          &[],
-          &[barrier_after_blit],
-        );
-      self
-        .cmd_buffers[image_idx]
-        .end()
-        .map_err(|e| PresentManagerError::PresentError(format!("at blit cmd record end: {e}")))?;
+    let cmd_buffer = &self.cmd_buffers[slot];
+    cmd_buffer
+      .begin(vk::CommandBufferBeginInfo::default())
+      .map_err(|e| PresentManagerError::PresentError(format!("at blit cmd record begin: {e}")))?;
+    cmd_buffer
+      .pipeline_barrier(
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::DependencyFlags::BY_REGION,
+        &[],
+        &[],
+        &[barrier_before_blit],
+      );
+    // the bars are cleared along with the rest, the blit then overwrites the middle
+    if letterboxed {
+      cmd_buffer.clear_color_image(
+        self.images[image_idx].inner,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        vk::ClearColorValue { float32: self.config.letterbox_color },
+        &[color_range],
+      );
+      cmd_buffer.pipeline_barrier(
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[vk::ImageMemoryBarrier::default()
+          .image(self.images[image_idx].inner)
+          .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+          .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+          .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+          .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+          .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+          .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+          .subresource_range(color_range)],
+      );
+    }
+    cmd_buffer
+      .blit_image(
+        src_image.inner,
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        self.images[image_idx].inner,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        &[blit_region],
+        filter,
+      );
+    cmd_buffer
+      .pipeline_barrier(
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        vk::DependencyFlags::BY_REGION,
+        &[],
+        &[],
+        &[barrier_after_blit],
+      );
+    cmd_buffer
+      .end()
+      .map_err(|e| PresentManagerError::PresentError(format!("at blit cmd record end: {e}")))?;
 
-      wait_for.push(self.acquire_image_sem_list[self.presenting_image.unwrap_or(0) as usize].inner);
+    wait_for.push(acquire_semaphore);
 
-      self
-        .vk_context
-        .device
-        .queue_submit(
-          self.vk_context.graphics_q,
-          &[vk::SubmitInfo::default()
-            .command_buffers(&[self.cmd_buffers[image_idx].inner])
-            .wait_semaphores(&wait_for[..])
-            .signal_semaphores(&[self.image_blit_sem_list[image_idx].inner])
-            .wait_dst_stage_mask(
-              &vec![vk::PipelineStageFlags::BOTTOM_OF_PIPE; wait_for.len()][..],
-            )],
-          self.image_blit_fences[image_idx].inner,
-        )
-        .map_err(|e| PresentManagerError::PresentError(format!("at blit cmd submit: {e}")))?;
-      self
-        .swapchain_device
-        .queue_present(
-          self.vk_context.present_q,
-          &vk::PresentInfoKHR::default()
-            .wait_semaphores(&[self.image_blit_sem_list[image_idx].inner])
-            .swapchains(&[self.swapchain])
-            .image_indices(&[image_idx as u32]),
-        )
-        .map_err(|e| PresentManagerError::PresentError(format!("at present: {e}")))?;
-    };
+    self
+      .vk_context
+      .queue_submit(
+        self.vk_context.graphics_q,
+        &[vk::SubmitInfo::default()
+          .command_buffers(&[cmd_buffer.inner])
+          .wait_semaphores(&wait_for[..])
+          .signal_semaphores(&[self.image_blit_sem_list[image_idx].inner])
+          .wait_dst_stage_mask(&vec![vk::PipelineStageFlags::TRANSFER; wait_for.len()][..])],
+        self.blit_fences[slot].inner,
+      )
+      .map_err(|e| PresentManagerError::PresentError(format!("at blit cmd submit: {e}")))?;
+    let present_q = self
+      .vk_context
+      .present_q
+      .ok_or(PresentManagerError::PresentError("vk context has no present queue".to_string()))?;
+    self
+      .vk_context
+      .queue_present(
+        &self.swapchain_device,
+        present_q,
+        &vk::PresentInfoKHR::default()
+          .wait_semaphores(&[self.image_blit_sem_list[image_idx].inner])
+          .swapchains(&[self.swapchain])
+          .image_indices(&[image_idx as u32]),
+      )
+      .map_err(|e| PresentManagerError::PresentError(format!("at present: {e}")))?;
     self.presenting_image = Some(image_idx as u32);
+    self.acquire_idx = (self.acquire_idx + 1) % self.acquire_image_sem_list.len();
     self.images_init_done[image_idx] = true;
-    self.cmd_buffer_init_done[image_idx] = true;
+    self.cmd_buffer_init_done[slot] = true;
     Ok(())
   }
 
+  // waits for every blit in flight, their command buffers and fences are free to reuse after
   pub fn wait_for_present(&mut self) {
-    unsafe {
-      if let Some(presenting_idx) = self.presenting_image {
-        let _ = self.vk_context.device.wait_for_fences(
-          &[self.image_blit_fences[presenting_idx as usize].inner],
-          true,
-          15000000,
-        );
-        let _ = self
-          .vk_context
-          .device
-          .reset_fences(&[self.image_blit_fences[presenting_idx as usize].inner]);
+    for (fence, init_done) in self.blit_fences.iter().zip(&mut self.cmd_buffer_init_done) {
+      if *init_done {
+        unsafe {
+          let _ = self.vk_context.device.wait_for_fences(&[fence.inner], true, u64::MAX);
+          let _ = self.vk_context.device.reset_fences(&[fence.inner]);
+        }
+        *init_done = false;
       }
     }
     self.presenting_image = None;
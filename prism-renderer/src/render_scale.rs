@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use std::time::Duration;
+use vk_context::ash::vk;
+use vk_context::auto_drop_wrappers::{AdCommandBuffer, AdQueryPool};
+use vk_context::VkContext;
+
+// bounds of the render resolution, as a factor of the window size
+pub const MIN_RENDER_SCALE: f32 = 0.5;
+pub const MAX_RENDER_SCALE: f32 = 2.0;
+
+/*
+Render resolution as a factor of the window size, the rendered frame is blit scaled to the
+window at present. Dynamic moves the factor between min and max to keep the GPU time of a
+frame near target_frame_time. It stays at its starting factor on GPUs without timestamps.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenderScale {
+  Fixed(f32),
+  Dynamic { target_frame_time: Duration, min: f32, max: f32 },
+}
+
+impl Default for RenderScale {
+  fn default() -> Self {
+    RenderScale::Fixed(1.0)
+  }
+}
+
+impl RenderScale {
+  pub(crate) fn validate(&self) -> Result<(), String> {
+    let in_range = |scale: f32| (MIN_RENDER_SCALE..=MAX_RENDER_SCALE).contains(&scale);
+    match *self {
+      RenderScale::Fixed(scale) if !in_range(scale) => {
+        Err(format!("render scale {scale} out of {MIN_RENDER_SCALE} to {MAX_RENDER_SCALE}"))
+      }
+      RenderScale::Dynamic { min, max, .. } if !(in_range(min) && in_range(max) && min <= max) => {
+        Err(format!(
+          "dynamic render scale {min} to {max} out of {MIN_RENDER_SCALE} to {MAX_RENDER_SCALE}"
+        ))
+      }
+      RenderScale::Dynamic { target_frame_time, .. } if target_frame_time.is_zero() => {
+        Err("dynamic render scale needs a non zero target frame time".to_string())
+      }
+      _ => Ok(()),
+    }
+  }
+
+  // factor to render at right after switching to this, coming from current
+  pub(crate) fn initial_scale(&self, current: f32) -> f32 {
+    match *self {
+      RenderScale::Fixed(scale) => scale,
+      RenderScale::Dynamic { min, max, .. } => current.clamp(min, max),
+    }
+  }
+}
+
+pub(crate) fn scaled_resolution(resolution: vk::Extent2D, scale: f32) -> vk::Extent2D {
+  vk::Extent2D {
+    width: ((resolution.width as f32 * scale).round() as u32).max(1),
+    height: ((resolution.height as f32 * scale).round() as u32).max(1),
+  }
+}
+
+// frame times averaged over roughly this many frames
+const SMOOTHING_FRAMES: f32 = 10.0;
+// frames measured after a change before the next one, changes wait for the frames in flight
+const SETTLE_FRAMES: u32 = 30;
+// relative changes smaller than this are ignored, so the scale doesn't keep hopping around
+const MIN_SCALE_STEP: f32 = 0.05;
+
+/*
+Timestamps around each frame in flight's render commands, two queries per frame.
+Also decides dynamic render scale changes from the measured times.
+ */
+pub(crate) struct FrameTimer {
+  vk_context: Arc<VkContext>,
+  query_pool: AdQueryPool,
+  timestamp_period: f32,
+  smoothed_frame_time: Option<f32>,
+  frames_since_change: u32,
+}
+
+impl FrameTimer {
+  // None when the GPU can't time the graphics queue
+  pub(crate) fn new(
+    vk_context: Arc<VkContext>,
+    frames_in_flight: usize,
+  ) -> Result<Option<Self>, String> {
+    let Some(timestamp_period) = vk_context.timestamp_period else {
+      return Ok(None);
+    };
+    let query_pool = vk_context
+      .create_ad_query_pool(
+        vk::QueryPoolCreateInfo::default()
+          .query_type(vk::QueryType::TIMESTAMP)
+          .query_count(2 * frames_in_flight as u32),
+      )
+      .map_err(|e| format!("at frame timer: {e}"))?;
+    Ok(Some(Self {
+      vk_context,
+      query_pool,
+      timestamp_period,
+      smoothed_frame_time: None,
+      frames_since_change: 0,
+    }))
+  }
+
+  // record before the frame's render pass
+  pub(crate) fn begin(&self, cmd_buffer: &AdCommandBuffer, frame: usize) {
+    cmd_buffer.reset_query_pool(self.query_pool.inner, 2 * frame as u32, 2);
+    cmd_buffer.write_timestamp(
+      vk::PipelineStageFlags::TOP_OF_PIPE,
+      self.query_pool.inner,
+      2 * frame as u32,
+    );
+  }
+
+  // record after the frame's render pass
+  pub(crate) fn end(&self, cmd_buffer: &AdCommandBuffer, frame: usize) {
+    cmd_buffer.write_timestamp(
+      vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+      self.query_pool.inner,
+      2 * frame as u32 + 1,
+    );
+  }
+
+  // GPU time of the frame's last submission, only call once its fence is signaled
+  pub(crate) fn read(&self, frame: usize) -> Result<Duration, String> {
+    let mut timestamps = [0u64; 2];
+    unsafe {
+      self
+        .vk_context
+        .device
+        .get_query_pool_results(
+          self.query_pool.inner,
+          2 * frame as u32,
+          &mut timestamps,
+          vk::QueryResultFlags::TYPE_64,
+        )
+        .map_err(|e| format!("at reading frame timestamps: {e}"))?;
+    }
+    let ticks = timestamps[1].saturating_sub(timestamps[0]);
+    Ok(Duration::from_nanos((ticks as f64 * self.timestamp_period as f64) as u64))
+  }
+
+  /*
+  Takes a measured frame time, returns the factor to switch to when the smoothed time is far
+  enough off the target. Pixel count goes with the square of the factor, and so does GPU time.
+   */
+  pub(crate) fn next_scale(
+    &mut self,
+    render_scale: RenderScale,
+    scale: f32,
+    frame_time: Duration,
+  ) -> Option<f32> {
+    let RenderScale::Dynamic { target_frame_time, min, max } = render_scale else {
+      return None;
+    };
+    let frame_time = frame_time.as_secs_f32();
+    let smoothed = self
+      .smoothed_frame_time
+      .map_or(frame_time, |smoothed| smoothed + (frame_time - smoothed) / SMOOTHING_FRAMES);
+    self.smoothed_frame_time = Some(smoothed);
+    self.frames_since_change += 1;
+    if self.frames_since_change < SETTLE_FRAMES || smoothed <= 0.0 {
+      return None;
+    }
+    let next = (scale * (target_frame_time.as_secs_f32() / smoothed).sqrt()).clamp(min, max);
+    if (next - scale).abs() < scale * MIN_SCALE_STEP {
+      return None;
+    }
+    // times measured at the old resolution say nothing about the new one
+    self.smoothed_frame_time = None;
+    self.frames_since_change = 0;
+    Some(next)
+  }
+
+  // measurements restart, for when the resolution changed from outside
+  pub(crate) fn reset(&mut self) {
+    self.smoothed_frame_time = None;
+    self.frames_since_change = 0;
+  }
+}
@@ -0,0 +1,416 @@
+use std::sync::{Arc, Mutex};
+use vk_context::ash::vk;
+use vk_context::auto_drop_wrappers::{ADRenderPass, AdAllocatedImage};
+use vk_context::gpu_allocator::vulkan::Allocator;
+use vk_context::gpu_allocator::MemoryLocation;
+use vk_context::VkContext;
+
+// attachment indices in the deferred render pass and its frame buffer
+pub const OUTPUT_ATTACHMENT: u32 = 0;
+pub const DEPTH_ATTACHMENT: u32 = 1;
+pub const ALBEDO_ATTACHMENT: u32 = 2;
+pub const NORMAL_ATTACHMENT: u32 = 3;
+pub const METALLIC_ROUGHNESS_ATTACHMENT: u32 = 4;
+pub const HDR_ATTACHMENT: u32 = 5;
+pub const EMISSIVE_ATTACHMENT: u32 = 6;
+
+// subpasses of the deferred render pass, in execution order
+pub const GEOMETRY_SUBPASS: u32 = 0;
+pub const LIGHTING_SUBPASS: u32 = 1;
+pub const TONEMAP_SUBPASS: u32 = 2;
+
+// linear albedo stored sRGB encoded keeps precision in the darks, decoded on subpass loads
+const ALBEDO_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+// world space normals need the sign and more precision than 8 bits
+const NORMAL_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+const METALLIC_ROUGHNESS_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+const EMISSIVE_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/*
+Images the deferred render pass draws into, along with their views and the frame buffer.
+The geometry subpass fills the g-buffer (albedo, normal, metallic roughness, emissive and depth),
+the lighting subpass resolves it into the hdr image and the tonemap subpass writes the
+color image, which is the only one whose content is kept after the pass.
+Recreated as a whole when the render resolution changes.
+ */
+pub struct RenderTargets {
+  vk_context: Arc<VkContext>,
+  pub frame_buffer: vk::Framebuffer,
+  pub color_image_view: vk::ImageView,
+  pub depth_image_view: vk::ImageView,
+  pub albedo_image_view: vk::ImageView,
+  pub normal_image_view: vk::ImageView,
+  pub metallic_roughness_image_view: vk::ImageView,
+  pub hdr_image_view: vk::ImageView,
+  pub emissive_image_view: vk::ImageView,
+  pub color_image: AdAllocatedImage,
+  _depth_image: AdAllocatedImage,
+  _albedo_image: AdAllocatedImage,
+  _normal_image: AdAllocatedImage,
+  _metallic_roughness_image: AdAllocatedImage,
+  _hdr_image: AdAllocatedImage,
+  _emissive_image: AdAllocatedImage,
+  pub resolution: vk::Extent2D,
+}
+
+fn g_buffer_attachment(format: vk::Format) -> vk::AttachmentDescription {
+  vk::AttachmentDescription::default()
+    .format(format)
+    .samples(vk::SampleCountFlags::TYPE_1)
+    .load_op(vk::AttachmentLoadOp::CLEAR)
+    .store_op(vk::AttachmentStoreOp::DONT_CARE)
+    .initial_layout(vk::ImageLayout::UNDEFINED)
+    .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+}
+
+fn color_attachment_ref(attachment: u32) -> vk::AttachmentReference {
+  vk::AttachmentReference::default()
+    .attachment(attachment)
+    .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+}
+
+fn input_attachment_ref(attachment: u32) -> vk::AttachmentReference {
+  vk::AttachmentReference::default()
+    .attachment(attachment)
+    .layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+}
+
+/*
+Geometry, lighting and tonemap subpasses with attachments laid out as in the *_ATTACHMENT consts.
+The output color image ends up in TRANSFER_SRC_OPTIMAL, ready to be blit or read back.
+ */
+pub fn create_deferred_render_pass(
+  vk_context: &VkContext,
+  output_format: vk::Format,
+  depth_format: vk::Format,
+) -> Result<ADRenderPass, String> {
+  vk_context
+    .create_ad_render_pass_builder(vk::RenderPassCreateFlags::default())
+    .add_attachment(
+      vk::AttachmentDescription::default()
+        .format(output_format)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+    )
+    .add_attachment(
+      vk::AttachmentDescription::default()
+        .format(depth_format)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+    )
+    .add_attachment(g_buffer_attachment(ALBEDO_FORMAT))
+    .add_attachment(g_buffer_attachment(NORMAL_FORMAT))
+    .add_attachment(g_buffer_attachment(METALLIC_ROUGHNESS_FORMAT))
+    .add_attachment(g_buffer_attachment(HDR_FORMAT))
+    .add_attachment(g_buffer_attachment(EMISSIVE_FORMAT))
+    .add_sub_pass(
+      vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&[
+          color_attachment_ref(ALBEDO_ATTACHMENT),
+          color_attachment_ref(NORMAL_ATTACHMENT),
+          color_attachment_ref(METALLIC_ROUGHNESS_ATTACHMENT),
+          color_attachment_ref(EMISSIVE_ATTACHMENT),
+        ])
+        .depth_stencil_attachment(
+          &vk::AttachmentReference::default()
+            .attachment(DEPTH_ATTACHMENT)
+            .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+        ),
+    )
+    .add_sub_pass(
+      vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .input_attachments(&[
+          input_attachment_ref(ALBEDO_ATTACHMENT),
+          input_attachment_ref(NORMAL_ATTACHMENT),
+          input_attachment_ref(METALLIC_ROUGHNESS_ATTACHMENT),
+          vk::AttachmentReference::default()
+            .attachment(DEPTH_ATTACHMENT)
+            .layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+          input_attachment_ref(EMISSIVE_ATTACHMENT),
+        ])
+        .color_attachments(&[color_attachment_ref(HDR_ATTACHMENT)]),
+    )
+    .add_sub_pass(
+      vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .input_attachments(&[input_attachment_ref(HDR_ATTACHMENT)])
+        .color_attachments(&[color_attachment_ref(OUTPUT_ATTACHMENT)]),
+    )
+    // previous frame's g-buffer reads have to finish before it gets cleared again
+    .add_sub_pass_dependency(
+      vk::SubpassDependency::default()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .dst_subpass(GEOMETRY_SUBPASS)
+        .src_stage_mask(
+          vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+        )
+        .dst_stage_mask(
+          vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+        )
+        .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+        .dst_access_mask(
+          vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        ),
+    )
+    .add_sub_pass_dependency(
+      vk::SubpassDependency::default()
+        .src_subpass(GEOMETRY_SUBPASS)
+        .dst_subpass(LIGHTING_SUBPASS)
+        .src_stage_mask(
+          vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+        )
+        .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+        .src_access_mask(
+          vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        )
+        .dst_access_mask(vk::AccessFlags::INPUT_ATTACHMENT_READ)
+        .dependency_flags(vk::DependencyFlags::BY_REGION),
+    )
+    .add_sub_pass_dependency(
+      vk::SubpassDependency::default()
+        .src_subpass(LIGHTING_SUBPASS)
+        .dst_subpass(TONEMAP_SUBPASS)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_access_mask(vk::AccessFlags::INPUT_ATTACHMENT_READ)
+        .dependency_flags(vk::DependencyFlags::BY_REGION),
+    )
+    // previous frame's blit or read back of the output image
+    .add_sub_pass_dependency(
+      vk::SubpassDependency::default()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .dst_subpass(TONEMAP_SUBPASS)
+        .src_stage_mask(vk::PipelineStageFlags::TRANSFER)
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE),
+    )
+    .add_sub_pass_dependency(
+      vk::SubpassDependency::default()
+        .src_subpass(TONEMAP_SUBPASS)
+        .dst_subpass(vk::SUBPASS_EXTERNAL)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .dst_stage_mask(vk::PipelineStageFlags::TRANSFER)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_access_mask(vk::AccessFlags::TRANSFER_READ),
+    )
+    .build()
+}
+
+fn create_attachment_image(
+  vk_context: &VkContext,
+  allocator: Arc<Mutex<Allocator>>,
+  name: &str,
+  format: vk::Format,
+  usage: vk::ImageUsageFlags,
+  resolution: vk::Extent2D,
+) -> Result<AdAllocatedImage, String> {
+  AdAllocatedImage::new(
+    Arc::clone(&vk_context.device),
+    allocator,
+    name,
+    vk::ImageCreateInfo::default()
+      .image_type(vk::ImageType::TYPE_2D)
+      .format(format)
+      .usage(usage)
+      .initial_layout(vk::ImageLayout::UNDEFINED)
+      .sharing_mode(vk::SharingMode::EXCLUSIVE)
+      .samples(vk::SampleCountFlags::TYPE_1)
+      .tiling(vk::ImageTiling::OPTIMAL)
+      .mip_levels(1)
+      .array_layers(1)
+      .extent(vk::Extent3D::from(resolution).depth(1)),
+    MemoryLocation::GpuOnly,
+  )
+}
+
+fn create_attachment_image_view(
+  vk_context: &VkContext,
+  image: &AdAllocatedImage,
+  aspect_mask: vk::ImageAspectFlags,
+) -> Result<vk::ImageView, String> {
+  unsafe {
+    vk_context
+      .device
+      .create_image_view(
+        &vk::ImageViewCreateInfo::default()
+          .format(image.format)
+          .image(image.inner)
+          .view_type(vk::ImageViewType::TYPE_2D)
+          .components(vk::ComponentMapping::default())
+          .subresource_range(
+            vk::ImageSubresourceRange::default()
+              .aspect_mask(aspect_mask)
+              .level_count(1)
+              .layer_count(1)
+              .base_mip_level(0)
+              .base_array_layer(0),
+          ),
+        None,
+      )
+      .map_err(|e| format!("at {} view create: {e}", image.name))
+  }
+}
+
+impl RenderTargets {
+  pub fn new(
+    vk_context: Arc<VkContext>,
+    allocator: Arc<Mutex<Allocator>>,
+    render_pass: vk::RenderPass,
+    color_format: vk::Format,
+    depth_format: vk::Format,
+    resolution: vk::Extent2D,
+  ) -> Result<Self, String> {
+    let g_buffer_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT;
+    let color_image = create_attachment_image(
+      &vk_context,
+      Arc::clone(&allocator),
+      "attachment_image_allocation",
+      color_format,
+      vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+      resolution,
+    )?;
+    let depth_image = create_attachment_image(
+      &vk_context,
+      Arc::clone(&allocator),
+      "depth_image_allocation",
+      depth_format,
+      vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT,
+      resolution,
+    )?;
+    let albedo_image = create_attachment_image(
+      &vk_context,
+      Arc::clone(&allocator),
+      "albedo_image_allocation",
+      ALBEDO_FORMAT,
+      g_buffer_usage,
+      resolution,
+    )?;
+    let normal_image = create_attachment_image(
+      &vk_context,
+      Arc::clone(&allocator),
+      "normal_image_allocation",
+      NORMAL_FORMAT,
+      g_buffer_usage,
+      resolution,
+    )?;
+    let metallic_roughness_image = create_attachment_image(
+      &vk_context,
+      Arc::clone(&allocator),
+      "metallic_roughness_image_allocation",
+      METALLIC_ROUGHNESS_FORMAT,
+      g_buffer_usage,
+      resolution,
+    )?;
+    let hdr_image = create_attachment_image(
+      &vk_context,
+      Arc::clone(&allocator),
+      "hdr_image_allocation",
+      HDR_FORMAT,
+      g_buffer_usage,
+      resolution,
+    )?;
+    let emissive_image = create_attachment_image(
+      &vk_context,
+      Arc::clone(&allocator),
+      "emissive_image_allocation",
+      EMISSIVE_FORMAT,
+      g_buffer_usage,
+      resolution,
+    )?;
+
+    let color_image_view =
+      create_attachment_image_view(&vk_context, &color_image, vk::ImageAspectFlags::COLOR)?;
+    // the depth view is also read as an input attachment, which only allows a single aspect.
+    // as a frame buffer attachment the aspect mask is ignored so stencil formats still work
+    let depth_image_view =
+      create_attachment_image_view(&vk_context, &depth_image, vk::ImageAspectFlags::DEPTH)?;
+    let albedo_image_view =
+      create_attachment_image_view(&vk_context, &albedo_image, vk::ImageAspectFlags::COLOR)?;
+    let normal_image_view =
+      create_attachment_image_view(&vk_context, &normal_image, vk::ImageAspectFlags::COLOR)?;
+    let metallic_roughness_image_view = create_attachment_image_view(
+      &vk_context,
+      &metallic_roughness_image,
+      vk::ImageAspectFlags::COLOR,
+    )?;
+    let hdr_image_view =
+      create_attachment_image_view(&vk_context, &hdr_image, vk::ImageAspectFlags::COLOR)?;
+    let emissive_image_view =
+      create_attachment_image_view(&vk_context, &emissive_image, vk::ImageAspectFlags::COLOR)?;
+
+    let frame_buffer = unsafe {
+      vk_context
+        .device
+        .create_framebuffer(
+          &vk::FramebufferCreateInfo::default()
+            .render_pass(render_pass)
+            .attachments(&[
+              color_image_view,
+              depth_image_view,
+              albedo_image_view,
+              normal_image_view,
+              metallic_roughness_image_view,
+              hdr_image_view,
+              emissive_image_view,
+            ])
+            .width(resolution.width)
+            .height(resolution.height)
+            .layers(1),
+          None,
+        )
+        .map_err(|e| format!("at frame buffer create: {e}"))?
+    };
+
+    Ok(Self {
+      vk_context,
+      frame_buffer,
+      color_image_view,
+      depth_image_view,
+      albedo_image_view,
+      normal_image_view,
+      metallic_roughness_image_view,
+      hdr_image_view,
+      emissive_image_view,
+      color_image,
+      _depth_image: depth_image,
+      _albedo_image: albedo_image,
+      _normal_image: normal_image,
+      _metallic_roughness_image: metallic_roughness_image,
+      _hdr_image: hdr_image,
+      _emissive_image: emissive_image,
+      resolution,
+    })
+  }
+}
+
+impl Drop for RenderTargets {
+  fn drop(&mut self) {
+    unsafe {
+      self.vk_context.device.destroy_framebuffer(self.frame_buffer, None);
+      self.vk_context.device.destroy_image_view(self.color_image_view, None);
+      self.vk_context.device.destroy_image_view(self.depth_image_view, None);
+      self.vk_context.device.destroy_image_view(self.albedo_image_view, None);
+      self.vk_context.device.destroy_image_view(self.normal_image_view, None);
+      self.vk_context.device.destroy_image_view(self.metallic_roughness_image_view, None);
+      self.vk_context.device.destroy_image_view(self.hdr_image_view, None);
+      self.vk_context.device.destroy_image_view(self.emissive_image_view, None);
+    }
+  }
+}
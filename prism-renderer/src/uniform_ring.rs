@@ -0,0 +1,86 @@
+use std::sync::{Arc, Mutex};
+use vk_context::ash::vk;
+use vk_context::auto_drop_wrappers::AdAllocatedBuffer;
+use vk_context::gpu_allocator::vulkan::Allocator;
+use vk_context::gpu_allocator::MemoryLocation;
+use vk_context::VkContext;
+
+/*
+Persistently mapped uniform buffers, one per frame in flight, read through dynamic offsets.
+Values of a frame are pushed one after the other into that frame's buffer, so writing
+the next frame never touches a buffer the GPU may still be reading.
+ */
+pub struct UniformRing {
+  buffers: Vec<AdAllocatedBuffer>,
+  alignment: vk::DeviceSize,
+  frame: usize,
+  cursor: vk::DeviceSize,
+}
+
+impl UniformRing {
+  pub fn new(
+    vk_context: &VkContext,
+    allocator: Arc<Mutex<Allocator>>,
+    name: &str,
+    frames_in_flight: usize,
+    frame_size: vk::DeviceSize,
+  ) -> Result<Self, String> {
+    let buffers = (0..frames_in_flight)
+      .map(|frame| {
+        AdAllocatedBuffer::new(
+          Arc::clone(&vk_context.device),
+          Arc::clone(&allocator),
+          &format!("{name}_{frame}"),
+          vk::BufferCreateInfo::default()
+            .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+            .size(frame_size),
+          MemoryLocation::CpuToGpu,
+        )
+      })
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|e| format!("at {name} buffers: {e}"))?;
+    Ok(Self {
+      buffers,
+      alignment: vk_context.min_uniform_buffer_offset_alignment.max(1),
+      frame: 0,
+      cursor: 0,
+    })
+  }
+
+  // per frame buffers, in frame order, for pointing descriptor sets at
+  pub fn buffers(&self) -> &[AdAllocatedBuffer] {
+    &self.buffers
+  }
+
+  // starts over at the start of frame's buffer, the GPU has to be done with it before calling
+  pub fn begin_frame(&mut self, frame: usize) {
+    self.frame = frame % self.buffers.len();
+    self.cursor = 0;
+  }
+
+  // copies value into the current frame's buffer and returns its dynamic offset
+  pub fn push<T: Copy>(&mut self, value: &T) -> Result<u32, String> {
+    let bytes =
+      unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    let offset = self.cursor.next_multiple_of(self.alignment);
+    let end = offset + bytes.len() as vk::DeviceSize;
+    let buffer = &mut self.buffers[self.frame];
+    if end > buffer.size {
+      return Err(format!("{} full, it holds {} bytes per frame", buffer.name, buffer.size));
+    }
+    buffer
+      .allocation
+      .as_mut()
+      .ok_or(format!("{} not allocated", buffer.name))?
+      .mapped_slice_mut()
+      .ok_or(format!("at mapping {} memory to CPU", buffer.name))?[offset as usize..end as usize]
+      .copy_from_slice(bytes);
+    self.cursor = end;
+    Ok(offset as u32)
+  }
+
+  // space one value of T takes in a frame buffer, for sizing the frames
+  pub fn aligned_size<T>(alignment: vk::DeviceSize) -> vk::DeviceSize {
+    (size_of::<T>() as vk::DeviceSize).next_multiple_of(alignment.max(1))
+  }
+}
@@ -0,0 +1,664 @@
+use crate::mipmaps::mip_extent;
+use basis_universal::{
+  DecodeFlags, LowLevelUastcTranscoder, SliceParametersUastc, TranscodeParameters, Transcoder,
+  TranscoderBlockFormat, TranscoderTextureFormat,
+};
+use ktx2::{ColorModel, DfdBlockBasic, SupercompressionScheme};
+use std::borrow::Cow;
+use vk_context::ash::vk;
+
+// targets in order of preference, textures get the first one the gpu can sample
+pub(crate) const TRANSCODE_FORMATS: [vk::Format; 3] = [
+  vk::Format::BC7_UNORM_BLOCK,
+  vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK,
+  vk::Format::ASTC_4X4_UNORM_BLOCK,
+];
+
+const UASTC_BLOCK_BYTES: usize = 16;
+// KHR_DF_CHANNEL ids of the DFD samples carrying alpha
+const ETC1S_CHANNEL_AAA: u8 = 15;
+const UASTC_CHANNEL_RGBA: u8 = 3;
+const UASTC_CHANNEL_RRRG: u8 = 5;
+
+// sizes of the packed structs in basisu_file_headers.h and of the ktx2 BasisLZ global data
+const BASIS_HEADER_BYTES: usize = 77;
+const BASIS_SLICE_DESC_BYTES: usize = 23;
+const BASISLZ_HEADER_BYTES: usize = 20;
+const BASISLZ_IMAGE_DESC_BYTES: usize = 20;
+// basis_file_header values, see basisu_file_headers.h
+const BASIS_SIGNATURE: u64 = ((b'B' as u64) << 8) | b's' as u64;
+const BASIS_VERSION: u64 = 0x13;
+const BASIS_HEADER_FLAG_ETC1S: u64 = 1;
+const BASIS_HEADER_FLAG_HAS_ALPHA_SLICES: u64 = 4;
+const BASIS_SLICE_FLAG_HAS_ALPHA: u64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BasisEncoding {
+  // BasisLZ supercompressed, the codebooks are in the supercompression global data
+  Etc1s,
+  // UASTC 4x4 blocks, the levels may be zstd supercompressed on top
+  Uastc,
+}
+
+fn dfd_basic<'a>(reader: &'a ktx2::Reader<&[u8]>) -> Option<DfdBlockBasic<'a>> {
+  reader.dfd_blocks().next().and_then(|block| DfdBlockBasic::parse(block.data).ok())
+}
+
+// None for textures that are stored in a vulkan format
+pub(crate) fn basis_encoding(reader: &ktx2::Reader<&[u8]>) -> Option<BasisEncoding> {
+  let header = reader.header();
+  if header.supercompression_scheme == Some(SupercompressionScheme::BasisLZ) {
+    return Some(BasisEncoding::Etc1s);
+  }
+  let color_model = dfd_basic(reader)?.header.color_model;
+  (header.format.is_none() && color_model == Some(ColorModel::UASTC))
+    .then_some(BasisEncoding::Uastc)
+}
+
+fn dfd_has_alpha(reader: &ktx2::Reader<&[u8]>, encoding: BasisEncoding) -> bool {
+  dfd_basic(reader).is_some_and(|dfd| {
+    dfd.sample_information().any(|sample| match encoding {
+      BasisEncoding::Etc1s => sample.channel_type == ETC1S_CHANNEL_AAA,
+      BasisEncoding::Uastc => {
+        matches!(sample.channel_type, UASTC_CHANNEL_RGBA | UASTC_CHANNEL_RRRG)
+      }
+    })
+  })
+}
+
+fn transcoder_formats(
+  format: vk::Format,
+) -> Result<(TranscoderTextureFormat, TranscoderBlockFormat), String> {
+  match format {
+    vk::Format::BC7_UNORM_BLOCK => {
+      Ok((TranscoderTextureFormat::BC7_RGBA, TranscoderBlockFormat::BC7))
+    }
+    vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK => {
+      Ok((TranscoderTextureFormat::ETC2_RGBA, TranscoderBlockFormat::ETC2_RGBA))
+    }
+    vk::Format::ASTC_4X4_UNORM_BLOCK => {
+      Ok((TranscoderTextureFormat::ASTC_4x4_RGBA, TranscoderBlockFormat::ASTC_4x4))
+    }
+    _ => Err(format!("Basis Universal textures can't be transcoded to {format:?}")),
+  }
+}
+
+/*
+Transcode the levels of a Basis Universal ktx2 to format, one of TRANSCODE_FORMATS.
+levels are the ones in the file with zstd supercompression already inflated, the transcoded
+levels keep every layer and face next to each other like the ones of a plain ktx2.
+ */
+pub(crate) fn transcode_ktx2(
+  reader: &ktx2::Reader<&[u8]>,
+  encoding: BasisEncoding,
+  levels: &[Cow<[u8]>],
+  extent: vk::Extent3D,
+  image_count: u32,
+  format: vk::Format,
+) -> Result<Vec<Vec<u8>>, String> {
+  let (texture_format, block_format) = transcoder_formats(format)?;
+  let has_alpha = dfd_has_alpha(reader, encoding);
+  match encoding {
+    BasisEncoding::Uastc => transcode_uastc(levels, extent, image_count, has_alpha, block_format),
+    BasisEncoding::Etc1s => {
+      let basis_file = etc1s_basis_file(reader, levels, extent, image_count, has_alpha)?;
+      transcode_basis_file(&basis_file, levels.len() as u32, image_count, texture_format)
+    }
+  }
+}
+
+// UASTC levels are plain 4x4 blocks, each image is a slice of its own
+fn transcode_uastc(
+  levels: &[Cow<[u8]>],
+  extent: vk::Extent3D,
+  image_count: u32,
+  has_alpha: bool,
+  block_format: TranscoderBlockFormat,
+) -> Result<Vec<Vec<u8>>, String> {
+  let transcoder = LowLevelUastcTranscoder::new();
+  levels
+    .iter()
+    .zip(0..)
+    .map(|(level_bytes, level)| {
+      let level_extent = mip_extent(extent, level);
+      let blocks_x = level_extent.width.div_ceil(4);
+      let blocks_y = level_extent.height.div_ceil(4);
+      let image_bytes = (blocks_x * blocks_y) as usize * UASTC_BLOCK_BYTES;
+      if level_bytes.len() != image_bytes * image_count as usize {
+        return Err(format!(
+          "uastc level {level} is {} bytes, expected {}",
+          level_bytes.len(),
+          image_bytes * image_count as usize
+        ));
+      }
+      let mut transcoded = vec![];
+      for (image, image_idx) in level_bytes.chunks_exact(image_bytes).zip(0..) {
+        let slice_parameters = SliceParametersUastc {
+          num_blocks_x: blocks_x,
+          num_blocks_y: blocks_y,
+          has_alpha,
+          original_width: level_extent.width,
+          original_height: level_extent.height,
+        };
+        let image_transcoded = transcoder
+          .transcode_slice(image, slice_parameters, DecodeFlags::empty(), block_format)
+          .map_err(|e| format!("at transcoding uastc level {level} image {image_idx}: {e:?}"))?;
+        transcoded.extend_from_slice(&image_transcoded);
+      }
+      Ok(transcoded)
+    })
+    .collect()
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+  bytes
+    .get(offset..offset + 4)
+    .and_then(|x| x.try_into().ok())
+    .map(u32::from_le_bytes)
+    .ok_or(format!("BasisLZ global data ends before byte {}", offset + 4))
+}
+
+// little endian like basisu::packed_uint
+fn put_packed(bytes: &mut Vec<u8>, value: u64, size: usize) {
+  bytes.extend_from_slice(&value.to_le_bytes()[..size]);
+}
+
+/*
+A BasisLZ ktx2 holds the same codebooks, tables and slices as a .basis file, only laid out
+differently. The transcoder only reads .basis files, so one is put together around them.
+ */
+fn etc1s_basis_file(
+  reader: &ktx2::Reader<&[u8]>,
+  levels: &[Cow<[u8]>],
+  extent: vk::Extent3D,
+  image_count: u32,
+  has_alpha: bool,
+) -> Result<Vec<u8>, String> {
+  let global_data = reader.supercompression_global_data();
+  let counts = read_u32(global_data, 0)?;
+  let (endpoint_count, selector_count) = (counts & 0xffff, counts >> 16);
+  let endpoints_length = read_u32(global_data, 4)? as usize;
+  let selectors_length = read_u32(global_data, 8)? as usize;
+  let tables_length = read_u32(global_data, 12)? as usize;
+  let image_descs_start = BASISLZ_HEADER_BYTES;
+  let endpoints_start =
+    image_descs_start + levels.len() * image_count as usize * BASISLZ_IMAGE_DESC_BYTES;
+  let codebooks = global_data
+    .get(endpoints_start..endpoints_start + endpoints_length + selectors_length + tables_length)
+    .ok_or("BasisLZ global data is too short for its codebooks".to_string())?;
+
+  // color slice of every image of every level, each followed by its alpha slice if any
+  let mut slices = vec![];
+  for (level_bytes, level) in levels.iter().zip(0..) {
+    for image in 0..image_count {
+      let desc = image_descs_start
+        + (level as usize * image_count as usize + image as usize) * BASISLZ_IMAGE_DESC_BYTES;
+      let rgb_slice = (read_u32(global_data, desc + 4)?, read_u32(global_data, desc + 8)?, 0);
+      let mut image_slices = vec![rgb_slice];
+      if has_alpha {
+        image_slices.push((
+          read_u32(global_data, desc + 12)?,
+          read_u32(global_data, desc + 16)?,
+          BASIS_SLICE_FLAG_HAS_ALPHA,
+        ));
+      }
+      for (offset, length, flags) in image_slices {
+        let data = level_bytes
+          .get(offset as usize..offset as usize + length as usize)
+          .ok_or(format!("BasisLZ slice of level {level} image {image} is out of its level"))?;
+        slices.push((level, image, flags, data));
+      }
+    }
+  }
+
+  let slice_descs_start = BASIS_HEADER_BYTES;
+  let codebooks_start = slice_descs_start + slices.len() * BASIS_SLICE_DESC_BYTES;
+  let slices_start = codebooks_start + codebooks.len();
+  let file_size = slices_start + slices.iter().map(|(.., data)| data.len()).sum::<usize>();
+  let mut file = Vec::with_capacity(file_size);
+  let header_fields: [(u64, usize); 26] = [
+    (BASIS_SIGNATURE, 2),
+    (BASIS_VERSION, 2),
+    (BASIS_HEADER_BYTES as u64, 2),
+    // header and data crc16s are only checked when asked for
+    (0, 2),
+    ((file_size - BASIS_HEADER_BYTES) as u64, 4),
+    (0, 2),
+    (slices.len() as u64, 3),
+    (image_count as u64, 3),
+    // ETC1S
+    (0, 1),
+    (BASIS_HEADER_FLAG_ETC1S | if has_alpha { BASIS_HEADER_FLAG_HAS_ALPHA_SLICES } else { 0 }, 2),
+    // 2D
+    (0, 1),
+    // microseconds per frame, reserved and user data
+    (0, 3),
+    (0, 4),
+    (0, 4),
+    (0, 4),
+    (endpoint_count as u64, 2),
+    (codebooks_start as u64, 4),
+    (endpoints_length as u64, 3),
+    (selector_count as u64, 2),
+    ((codebooks_start + endpoints_length) as u64, 4),
+    (selectors_length as u64, 3),
+    ((codebooks_start + endpoints_length + selectors_length) as u64, 4),
+    (tables_length as u64, 4),
+    (slice_descs_start as u64, 4),
+    // no extended data
+    (0, 4),
+    (0, 4),
+  ];
+  for (value, size) in header_fields {
+    put_packed(&mut file, value, size);
+  }
+
+  let mut slice_offset = slices_start;
+  for (level, image, flags, data) in &slices {
+    let level_extent = mip_extent(extent, *level);
+    put_packed(&mut file, *image as u64, 3);
+    put_packed(&mut file, *level as u64, 1);
+    put_packed(&mut file, *flags, 1);
+    put_packed(&mut file, level_extent.width as u64, 2);
+    put_packed(&mut file, level_extent.height as u64, 2);
+    put_packed(&mut file, level_extent.width.div_ceil(4) as u64, 2);
+    put_packed(&mut file, level_extent.height.div_ceil(4) as u64, 2);
+    put_packed(&mut file, slice_offset as u64, 4);
+    put_packed(&mut file, data.len() as u64, 4);
+    put_packed(&mut file, 0, 2);
+    slice_offset += data.len();
+  }
+  file.extend_from_slice(codebooks);
+  for (.., data) in &slices {
+    file.extend_from_slice(data);
+  }
+  Ok(file)
+}
+
+fn transcode_basis_file(
+  basis_file: &[u8],
+  level_count: u32,
+  image_count: u32,
+  texture_format: TranscoderTextureFormat,
+) -> Result<Vec<Vec<u8>>, String> {
+  let mut transcoder = Transcoder::new();
+  transcoder
+    .prepare_transcoding(basis_file)
+    .map_err(|_| "at decoding BasisLZ codebooks".to_string())?;
+  (0..level_count)
+    .map(|level| {
+      let mut transcoded = vec![];
+      for image in 0..image_count {
+        let transcode_parameters =
+          TranscodeParameters { image_index: image, level_index: level, ..Default::default() };
+        let image_transcoded = transcoder
+          .transcode_image_level(basis_file, texture_format, transcode_parameters)
+          .map_err(|e| format!("at transcoding etc1s level {level} image {image}: {e:?}"))?;
+        transcoded.extend_from_slice(&image_transcoded);
+      }
+      Ok(transcoded)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use basis_universal::{BasisTextureFormat, Compressor, CompressorParams};
+  use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+
+  const KTX2_IDENTIFIER: [u8; 12] =
+    [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+  // KHR_DF_MODEL ids of the DFD basic block
+  pub(crate) const COLOR_MODEL_RGBSDA: u8 = 1;
+  const COLOR_MODEL_ETC1S: u8 = 163;
+  const COLOR_MODEL_UASTC: u8 = 166;
+  const ETC1S_CHANNEL_RGB: u8 = 0;
+  // basis_file_header field offsets, see basisu_file_headers.h
+  const BASIS_TOTAL_SLICES: usize = 14;
+  const BASIS_TOTAL_IMAGES: usize = 17;
+  const BASIS_FLAGS: usize = 21;
+  const BASIS_ENDPOINTS: usize = 39;
+  const BASIS_SELECTORS: usize = 48;
+  const BASIS_TABLES: usize = 57;
+  const BASIS_SLICE_DESCS: usize = 65;
+
+  fn get_packed(bytes: &[u8], offset: usize, size: usize) -> u64 {
+    let mut value = [0u8; 8];
+    value[..size].copy_from_slice(&bytes[offset..offset + size]);
+    u64::from_le_bytes(value)
+  }
+
+  // KTX2 file put together by hand, levels are given as they are before supercompression
+  pub(crate) struct TestKtx2<'a> {
+    pub(crate) format: vk::Format,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) layer_count: u32,
+    pub(crate) scheme: Option<SupercompressionScheme>,
+    pub(crate) color_model: u8,
+    pub(crate) channel_types: &'a [u8],
+    pub(crate) levels: &'a [Vec<u8>],
+    pub(crate) global_data: &'a [u8],
+  }
+
+  impl TestKtx2<'_> {
+    pub(crate) fn bytes(&self) -> Vec<u8> {
+      // the level index follows the 80 byte header, then the DFD and the global data
+      let dfd_offset = 80 + 24 * self.levels.len();
+      let dfd_length = 4 + 24 + 16 * self.channel_types.len();
+      let global_data_offset = (dfd_offset + dfd_length).next_multiple_of(8);
+      let stored_levels: Vec<(Vec<u8>, usize)> = self
+        .levels
+        .iter()
+        .map(|level| match self.scheme {
+          Some(SupercompressionScheme::Zstandard) => {
+            (compress_to_vec(&level[..], CompressionLevel::Fastest), level.len())
+          }
+          Some(SupercompressionScheme::BasisLZ) => (level.clone(), 0),
+          _ => (level.clone(), level.len()),
+        })
+        .collect();
+
+      let mut file = KTX2_IDENTIFIER.to_vec();
+      let header = [
+        self.format.as_raw() as u32,
+        1,
+        self.width,
+        self.height,
+        0,
+        self.layer_count,
+        1,
+        self.levels.len() as u32,
+        self.scheme.map_or(0, |x| x.value()),
+        dfd_offset as u32,
+        dfd_length as u32,
+        0,
+        0,
+      ];
+      for value in header {
+        put_packed(&mut file, value as u64, 4);
+      }
+      let global_data_offset_in_index =
+        if self.global_data.is_empty() { 0 } else { global_data_offset };
+      put_packed(&mut file, global_data_offset_in_index as u64, 8);
+      put_packed(&mut file, self.global_data.len() as u64, 8);
+      let mut level_offset = global_data_offset + self.global_data.len();
+      let mut level_offsets = vec![];
+      for (stored, uncompressed_length) in &stored_levels {
+        level_offset = level_offset.next_multiple_of(16);
+        level_offsets.push(level_offset);
+        put_packed(&mut file, level_offset as u64, 8);
+        put_packed(&mut file, stored.len() as u64, 8);
+        put_packed(&mut file, *uncompressed_length as u64, 8);
+        level_offset += stored.len();
+      }
+
+      // one basic block: vendor and type 0, version 2, then 4x4 texel blocks
+      put_packed(&mut file, dfd_length as u64, 4);
+      put_packed(&mut file, 0, 4);
+      put_packed(&mut file, 2, 2);
+      put_packed(&mut file, (24 + 16 * self.channel_types.len()) as u64, 2);
+      file.extend_from_slice(&[self.color_model, 1, 2, 0, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+      for channel_type in self.channel_types {
+        file.extend_from_slice(&[
+          0,
+          0,
+          63,
+          *channel_type,
+          0,
+          0,
+          0,
+          0,
+          0,
+          0,
+          0,
+          0,
+          255,
+          255,
+          255,
+          255,
+        ]);
+      }
+      file.resize(global_data_offset, 0);
+      file.extend_from_slice(self.global_data);
+      for ((stored, _), offset) in stored_levels.iter().zip(level_offsets) {
+        file.resize(offset, 0);
+        file.extend_from_slice(stored);
+      }
+      file
+    }
+  }
+
+  // .basis file of a gradient with alpha, each layer slightly different, mipmapped to 1x1
+  fn encode_basis(
+    format: BasisTextureFormat,
+    width: u32,
+    height: u32,
+    layer_count: u32,
+  ) -> Vec<u8> {
+    let mut params = CompressorParams::new();
+    params.set_basis_format(format);
+    params.set_generate_mipmaps(true);
+    params.set_mipmap_smallest_dimension(1);
+    for layer in 0..layer_count {
+      let mut rgba = vec![];
+      for y in 0..height {
+        for x in 0..width {
+          rgba.extend([x * 13 + layer * 50, y * 17, (x ^ y) * 9, x * 20].map(|c| c as u8));
+        }
+      }
+      params.source_image_mut(layer).init(&rgba, width, height, 4);
+    }
+    let mut compressor = Compressor::new(1);
+    unsafe {
+      assert!(compressor.init(&params));
+      compressor.process().unwrap();
+    }
+    compressor.basis_file().to_vec()
+  }
+
+  struct BasisSlice<'a> {
+    image: u32,
+    level: u32,
+    alpha: bool,
+    data: &'a [u8],
+  }
+
+  fn basis_slices(basis_file: &[u8]) -> Vec<BasisSlice<'_>> {
+    let total_slices = get_packed(basis_file, BASIS_TOTAL_SLICES, 3) as usize;
+    let slice_descs = get_packed(basis_file, BASIS_SLICE_DESCS, 4) as usize;
+    (0..total_slices)
+      .map(|slice| {
+        let desc = slice_descs + slice * BASIS_SLICE_DESC_BYTES;
+        let offset = get_packed(basis_file, desc + 13, 4) as usize;
+        let length = get_packed(basis_file, desc + 17, 4) as usize;
+        BasisSlice {
+          image: get_packed(basis_file, desc, 3) as u32,
+          level: get_packed(basis_file, desc + 3, 1) as u32,
+          alpha: get_packed(basis_file, desc + 4, 1) & BASIS_SLICE_FLAG_HAS_ALPHA != 0,
+          data: &basis_file[offset..offset + length],
+        }
+      })
+      .collect()
+  }
+
+  /*
+  The .basis texture as a KTX2 file, laid out like basisu writes them. ETC1S becomes BasisLZ
+  with the codebooks in the global data, UASTC keeps its blocks and is zstd supercompressed.
+   */
+  pub(crate) fn basis_ktx2(
+    format: BasisTextureFormat,
+    width: u32,
+    height: u32,
+    layer_count: u32,
+  ) -> (Vec<u8>, Vec<u8>) {
+    let basis_file = encode_basis(format, width, height, layer_count);
+    let slices = basis_slices(&basis_file);
+    let level_count = slices.iter().map(|slice| slice.level + 1).max().unwrap();
+    let mut levels = vec![vec![]; level_count as usize];
+    let mut global_data = vec![];
+    let ktx2 = if format == BasisTextureFormat::ETC1S {
+      let mut image_descs = vec![];
+      for level in 0..level_count {
+        for image in 0..layer_count {
+          let mut desc = [0u32; 5];
+          for slice in slices.iter().filter(|x| x.level == level && x.image == image) {
+            let first = if slice.alpha { 3 } else { 1 };
+            desc[first] = levels[level as usize].len() as u32;
+            desc[first + 1] = slice.data.len() as u32;
+            levels[level as usize].extend_from_slice(slice.data);
+          }
+          image_descs.extend(desc);
+        }
+      }
+      let codebook = |field: usize, length_size: usize| {
+        let offset = get_packed(&basis_file, field, 4) as usize;
+        let length = get_packed(&basis_file, field + 4, length_size) as usize;
+        &basis_file[offset..offset + length]
+      };
+      let endpoints = codebook(BASIS_ENDPOINTS + 2, 3);
+      let selectors = codebook(BASIS_SELECTORS + 2, 3);
+      let tables = codebook(BASIS_TABLES, 4);
+      put_packed(&mut global_data, get_packed(&basis_file, BASIS_ENDPOINTS, 2), 2);
+      put_packed(&mut global_data, get_packed(&basis_file, BASIS_SELECTORS, 2), 2);
+      for length in [endpoints.len(), selectors.len(), tables.len(), 0] {
+        put_packed(&mut global_data, length as u64, 4);
+      }
+      for value in image_descs {
+        put_packed(&mut global_data, value as u64, 4);
+      }
+      global_data.extend_from_slice(endpoints);
+      global_data.extend_from_slice(selectors);
+      global_data.extend_from_slice(tables);
+      let has_alpha = get_packed(&basis_file, BASIS_FLAGS, 2) & BASIS_HEADER_FLAG_HAS_ALPHA_SLICES;
+      let channel_types: &[u8] =
+        if has_alpha != 0 { &[ETC1S_CHANNEL_RGB, ETC1S_CHANNEL_AAA] } else { &[ETC1S_CHANNEL_RGB] };
+      TestKtx2 {
+        format: vk::Format::UNDEFINED,
+        width,
+        height,
+        layer_count,
+        scheme: Some(SupercompressionScheme::BasisLZ),
+        color_model: COLOR_MODEL_ETC1S,
+        channel_types,
+        levels: &levels,
+        global_data: &global_data,
+      }
+      .bytes()
+    } else {
+      for level in 0..level_count {
+        for slice in slices.iter().filter(|x| x.level == level) {
+          levels[level as usize].extend_from_slice(slice.data);
+        }
+      }
+      TestKtx2 {
+        format: vk::Format::UNDEFINED,
+        width,
+        height,
+        layer_count,
+        scheme: Some(SupercompressionScheme::Zstandard),
+        color_model: COLOR_MODEL_UASTC,
+        channel_types: &[UASTC_CHANNEL_RGBA],
+        levels: &levels,
+        global_data: &[],
+      }
+      .bytes()
+    };
+    (basis_file, ktx2)
+  }
+
+  #[test]
+  fn etc1s_basis_file_layout() {
+    let endpoints = [1u8, 2, 3];
+    let selectors = [4u8, 5];
+    let tables = [6u8, 7, 8, 9];
+    let levels = [vec![10u8, 11, 12, 13, 14], vec![15u8, 16, 17]];
+    let mut global_data = vec![];
+    put_packed(&mut global_data, 7, 2);
+    put_packed(&mut global_data, 9, 2);
+    for length in [endpoints.len(), selectors.len(), tables.len(), 0] {
+      put_packed(&mut global_data, length as u64, 4);
+    }
+    // flags, rgb offset and length, alpha offset and length of each level's single image
+    for value in [0, 0, 3, 3, 2, 0, 0, 2, 2, 1] {
+      put_packed(&mut global_data, value, 4);
+    }
+    global_data.extend(endpoints.iter().chain(&selectors).chain(&tables));
+    let ktx2 = TestKtx2 {
+      format: vk::Format::UNDEFINED,
+      width: 8,
+      height: 5,
+      layer_count: 0,
+      scheme: Some(SupercompressionScheme::BasisLZ),
+      color_model: COLOR_MODEL_ETC1S,
+      channel_types: &[ETC1S_CHANNEL_RGB, ETC1S_CHANNEL_AAA],
+      levels: &levels,
+      global_data: &global_data,
+    }
+    .bytes();
+    let reader = ktx2::Reader::new(&ktx2[..]).unwrap();
+    let levels: Vec<Cow<[u8]>> = reader.levels().map(|x| Cow::Borrowed(x.data)).collect();
+    let extent = vk::Extent3D { width: 8, height: 5, depth: 1 };
+    let file = etc1s_basis_file(&reader, &levels, extent, 1, true).unwrap();
+
+    let header = |offset: usize, size: usize| get_packed(&file, offset, size);
+    let codebooks_start = BASIS_HEADER_BYTES + 4 * BASIS_SLICE_DESC_BYTES;
+    assert_eq!(header(0, 2), BASIS_SIGNATURE);
+    assert_eq!(header(2, 2), BASIS_VERSION);
+    assert_eq!(header(4, 2), BASIS_HEADER_BYTES as u64);
+    assert_eq!(header(8, 4), (file.len() - BASIS_HEADER_BYTES) as u64);
+    assert_eq!(header(BASIS_TOTAL_SLICES, 3), 4);
+    assert_eq!(header(BASIS_TOTAL_IMAGES, 3), 1);
+    assert_eq!(
+      header(BASIS_FLAGS, 2),
+      BASIS_HEADER_FLAG_ETC1S | BASIS_HEADER_FLAG_HAS_ALPHA_SLICES
+    );
+    assert_eq!(header(BASIS_ENDPOINTS, 2), 7);
+    assert_eq!(header(BASIS_ENDPOINTS + 2, 4), codebooks_start as u64);
+    assert_eq!(header(BASIS_ENDPOINTS + 6, 3), 3);
+    assert_eq!(header(BASIS_SELECTORS, 2), 9);
+    assert_eq!(header(BASIS_SELECTORS + 2, 4), codebooks_start as u64 + 3);
+    assert_eq!(header(BASIS_SELECTORS + 6, 3), 2);
+    assert_eq!(header(BASIS_TABLES, 4), codebooks_start as u64 + 5);
+    assert_eq!(header(BASIS_TABLES + 4, 4), 4);
+    assert_eq!(header(BASIS_SLICE_DESCS, 4), BASIS_HEADER_BYTES as u64);
+    assert_eq!(&file[codebooks_start..codebooks_start + 9], &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+
+    // color then alpha slice of level 0, then of level 1, each pointing at its bytes
+    let expected = [
+      (0, 0, &[10u8, 11, 12][..], (8, 5, 2, 2)),
+      (0, 1, &[13, 14], (8, 5, 2, 2)),
+      (1, 0, &[15, 16], (4, 2, 1, 1)),
+      (1, 1, &[17], (4, 2, 1, 1)),
+    ];
+    for (slice, (level, flags, data, size)) in expected.into_iter().enumerate() {
+      let desc = |offset: usize, size: usize| {
+        get_packed(&file, BASIS_HEADER_BYTES + slice * BASIS_SLICE_DESC_BYTES + offset, size)
+      };
+      assert_eq!((desc(0, 3), desc(3, 1), desc(4, 1)), (0, level, flags));
+      assert_eq!((desc(5, 2), desc(7, 2), desc(9, 2), desc(11, 2)), size);
+      let (offset, length) = (desc(13, 4) as usize, desc(17, 4) as usize);
+      assert_eq!(&file[offset..offset + length], data);
+    }
+  }
+
+  #[test]
+  fn transcodes_like_the_basis_transcoder() {
+    for encoding in [BasisTextureFormat::ETC1S, BasisTextureFormat::UASTC4x4] {
+      let (basis_file, ktx2) = basis_ktx2(encoding, 37, 21, 2);
+      let reader = ktx2::Reader::new(&ktx2[..]).unwrap();
+      let levels = crate::compressed::inflate_ktx2_levels(&reader).unwrap();
+      let basis_encoding = basis_encoding(&reader).unwrap();
+      let extent = vk::Extent3D { width: 37, height: 21, depth: 1 };
+      for format in TRANSCODE_FORMATS {
+        let transcoded =
+          transcode_ktx2(&reader, basis_encoding, &levels, extent, 2, format).unwrap();
+        let (texture_format, _) = transcoder_formats(format).unwrap();
+        let expected =
+          transcode_basis_file(&basis_file, levels.len() as u32, 2, texture_format).unwrap();
+        assert!(transcoded == expected, "{encoding:?} to {format:?}");
+      }
+    }
+  }
+}
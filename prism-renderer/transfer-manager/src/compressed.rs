@@ -0,0 +1,524 @@
+use crate::mipmaps::{mip_extent, MipChain};
+use crate::{
+  basis, buffer_image_copy, record_image_copy, ColorSpace, TransferManager, UploadTicket,
+};
+use ddsfile::{Caps2, D3DFormat, Dds, DxgiFormat, MiscFlag};
+use ktx2::SupercompressionScheme;
+use std::borrow::Cow;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use vk_context::ash::vk;
+use vk_context::auto_drop_wrappers::AdAllocatedImage;
+use vk_context::gpu_allocator::vulkan::Allocator;
+use vk_context::gpu_allocator::MemoryLocation;
+
+// block compressed levels and layers as stored in the file, uploaded without re-encoding
+struct CompressedTexture {
+  format: vk::Format,
+  extent: vk::Extent3D,
+  mip_levels: u32,
+  array_layers: u32,
+  cube: bool,
+  data: Vec<u8>,
+  // buffer offsets are relative to the start of data
+  regions: Vec<vk::BufferImageCopy>,
+}
+
+// BC, ETC2, EAC and ASTC, the staging buffer alignment covers their 8 and 16 byte blocks
+fn is_block_compressed(format: vk::Format) -> bool {
+  (vk::Format::BC1_RGB_UNORM_BLOCK.as_raw()..=vk::Format::ASTC_12X12_SRGB_BLOCK.as_raw())
+    .contains(&format.as_raw())
+}
+
+// levels as stored, with Zstandard and ZLIB supercompression inflated
+pub(crate) fn inflate_ktx2_levels<'a>(
+  reader: &'a ktx2::Reader<&[u8]>,
+) -> Result<Vec<Cow<'a, [u8]>>, String> {
+  let scheme = reader.header().supercompression_scheme;
+  let mut levels = vec![];
+  for (level_data, level) in reader.levels().zip(0..) {
+    let level_bytes: Cow<[u8]> = match scheme {
+      // BasisLZ levels are left for the transcoder, they have no uncompressed length
+      None | Some(SupercompressionScheme::BasisLZ) => Cow::Borrowed(level_data.data),
+      Some(SupercompressionScheme::Zstandard) => {
+        let mut inflated = Vec::with_capacity(level_data.uncompressed_byte_length as usize);
+        ruzstd::decoding::StreamingDecoder::new(level_data.data)
+          .map_err(|e| format!("at starting zstd decoding of ktx2 level {level}: {e}"))?
+          .read_to_end(&mut inflated)
+          .map_err(|e| format!("at zstd decoding ktx2 level {level}: {e}"))?;
+        Cow::Owned(inflated)
+      }
+      Some(SupercompressionScheme::ZLIB) => {
+        let mut inflated = Vec::with_capacity(level_data.uncompressed_byte_length as usize);
+        flate2::read::ZlibDecoder::new(level_data.data)
+          .read_to_end(&mut inflated)
+          .map_err(|e| format!("at zlib decoding ktx2 level {level}: {e}"))?;
+        Cow::Owned(inflated)
+      }
+      Some(scheme) => return Err(format!("unknown ktx2 supercompression scheme {scheme:?}")),
+    };
+    let basis_lz = scheme == Some(SupercompressionScheme::BasisLZ);
+    if !basis_lz && level_bytes.len() as u64 != level_data.uncompressed_byte_length {
+      return Err(format!(
+        "ktx2 level {level} is {} bytes, expected {}",
+        level_bytes.len(),
+        level_data.uncompressed_byte_length
+      ));
+    }
+    levels.push(level_bytes);
+  }
+  Ok(levels)
+}
+
+/*
+KTX2 keeps every layer and face of a level next to each other, so a level is a single copy.
+Basis Universal textures are transcoded to basis_target, None when the gpu samples none of
+the targets. Other textures have to be block compressed.
+ */
+fn parse_ktx2(bytes: &[u8], basis_target: Option<vk::Format>) -> Result<CompressedTexture, String> {
+  let reader = ktx2::Reader::new(bytes).map_err(|e| format!("at parsing ktx2 header: {e}"))?;
+  let header = reader.header();
+  if header.pixel_depth > 1 {
+    return Err("3D ktx2 textures are not supported".to_string());
+  }
+
+  let extent =
+    vk::Extent3D { width: header.pixel_width, height: header.pixel_height.max(1), depth: 1 };
+  let array_layers = header.layer_count.max(1) * header.face_count.max(1);
+  let levels = inflate_ktx2_levels(&reader)?;
+  let (format, levels) = match basis::basis_encoding(&reader) {
+    Some(encoding) => {
+      let format = basis_target.ok_or(
+        "ktx2 texture is Basis Universal encoded, and BC7, ETC2 and ASTC 4x4 can't be sampled \
+        on this gpu"
+          .to_string(),
+      )?;
+      let transcoded =
+        basis::transcode_ktx2(&reader, encoding, &levels, extent, array_layers, format)?;
+      (format, transcoded.into_iter().map(Cow::Owned).collect())
+    }
+    None => {
+      let format = header.format.ok_or("ktx2 texture without a vulkan format".to_string())?;
+      let format = vk::Format::from_raw(format.value() as i32);
+      if !is_block_compressed(format) {
+        return Err(format!(
+          "ktx2 format {format:?} is not block compressed BC, ETC2, EAC or ASTC"
+        ));
+      }
+      (format, levels)
+    }
+  };
+
+  let mut data = vec![];
+  let mut regions = vec![];
+  for (level_bytes, level) in levels.iter().zip(0..) {
+    regions.push(buffer_image_copy(data.len() as vk::DeviceSize, level, 0, array_layers, extent));
+    data.extend_from_slice(level_bytes);
+  }
+
+  Ok(CompressedTexture {
+    format,
+    extent,
+    mip_levels: regions.len() as u32,
+    array_layers,
+    cube: header.face_count == 6,
+    data,
+    regions,
+  })
+}
+
+fn dxgi_to_vk_format(format: DxgiFormat) -> Option<vk::Format> {
+  match format {
+    DxgiFormat::BC1_UNorm => Some(vk::Format::BC1_RGBA_UNORM_BLOCK),
+    DxgiFormat::BC1_UNorm_sRGB => Some(vk::Format::BC1_RGBA_SRGB_BLOCK),
+    DxgiFormat::BC2_UNorm => Some(vk::Format::BC2_UNORM_BLOCK),
+    DxgiFormat::BC2_UNorm_sRGB => Some(vk::Format::BC2_SRGB_BLOCK),
+    DxgiFormat::BC3_UNorm => Some(vk::Format::BC3_UNORM_BLOCK),
+    DxgiFormat::BC3_UNorm_sRGB => Some(vk::Format::BC3_SRGB_BLOCK),
+    DxgiFormat::BC4_UNorm => Some(vk::Format::BC4_UNORM_BLOCK),
+    DxgiFormat::BC4_SNorm => Some(vk::Format::BC4_SNORM_BLOCK),
+    DxgiFormat::BC5_UNorm => Some(vk::Format::BC5_UNORM_BLOCK),
+    DxgiFormat::BC5_SNorm => Some(vk::Format::BC5_SNORM_BLOCK),
+    DxgiFormat::BC6H_UF16 => Some(vk::Format::BC6H_UFLOAT_BLOCK),
+    DxgiFormat::BC6H_SF16 => Some(vk::Format::BC6H_SFLOAT_BLOCK),
+    DxgiFormat::BC7_UNorm => Some(vk::Format::BC7_UNORM_BLOCK),
+    DxgiFormat::BC7_UNorm_sRGB => Some(vk::Format::BC7_SRGB_BLOCK),
+    _ => None,
+  }
+}
+
+// legacy headers carry no color space, DXT data is UNORM unless uploaded as Srgb
+fn dds_format(dds: &Dds) -> Option<vk::Format> {
+  if let Some(header10) = &dds.header10 {
+    return dxgi_to_vk_format(header10.dxgi_format);
+  }
+  match dds.get_d3d_format() {
+    Some(D3DFormat::DXT1) => Some(vk::Format::BC1_RGBA_UNORM_BLOCK),
+    Some(D3DFormat::DXT2 | D3DFormat::DXT3) => Some(vk::Format::BC2_UNORM_BLOCK),
+    Some(D3DFormat::DXT4 | D3DFormat::DXT5) => Some(vk::Format::BC3_UNORM_BLOCK),
+    Some(_) => None,
+    // ATI1 and ATI2 four cc codes only map to dxgi formats
+    None => dds.get_dxgi_format().and_then(dxgi_to_vk_format),
+  }
+}
+
+/*
+DDS keeps the full mip chain of a layer before the next layer, so every layer of every level
+is its own copy. Only BC1 to BC7 data is supported.
+ */
+fn parse_dds(bytes: &[u8]) -> Result<CompressedTexture, String> {
+  let dds = Dds::read(bytes).map_err(|e| format!("at parsing dds header: {e}"))?;
+  let format = dds_format(&dds).ok_or(format!(
+    "dds format {:?} {:?} is not block compressed BC1 to BC7",
+    dds.get_dxgi_format(),
+    dds.get_d3d_format()
+  ))?;
+  if dds.get_depth() > 1 {
+    return Err("3D dds textures are not supported".to_string());
+  }
+
+  // legacy cube maps already count their 6 faces as layers
+  let dx10_cube =
+    dds.header10.as_ref().is_some_and(|x| x.misc_flag.contains(MiscFlag::TEXTURECUBE));
+  let array_layers = dds.get_num_array_layers().max(1) * if dx10_cube { 6 } else { 1 };
+  let mip_levels = dds.get_num_mipmap_levels().max(1);
+  let extent = vk::Extent3D { width: dds.get_width(), height: dds.get_height(), depth: 1 };
+  let block_bytes = match format {
+    vk::Format::BC1_RGBA_UNORM_BLOCK
+    | vk::Format::BC1_RGBA_SRGB_BLOCK
+    | vk::Format::BC4_UNORM_BLOCK
+    | vk::Format::BC4_SNORM_BLOCK => 8,
+    _ => 16,
+  };
+
+  let mut regions = Vec::with_capacity((array_layers * mip_levels) as usize);
+  let mut offset: vk::DeviceSize = 0;
+  for layer in 0..array_layers {
+    for level in 0..mip_levels {
+      regions.push(buffer_image_copy(offset, level, layer, 1, extent));
+      let level_extent = mip_extent(extent, level);
+      let blocks = level_extent.width.div_ceil(4) * level_extent.height.div_ceil(4);
+      offset += (blocks * block_bytes) as vk::DeviceSize;
+    }
+  }
+  if offset > dds.data.len() as vk::DeviceSize {
+    return Err(format!("dds data is {} bytes, expected {offset}", dds.data.len()));
+  }
+
+  Ok(CompressedTexture {
+    format,
+    extent,
+    mip_levels,
+    array_layers,
+    cube: dx10_cube || dds.header.caps2.contains(Caps2::CUBEMAP),
+    data: dds.data,
+    regions,
+  })
+}
+
+// formats with both an sRGB and a UNORM variant, others keep the format of the file
+const SRGB_UNORM_PAIRS: [(vk::Format, vk::Format); 8] = [
+  (vk::Format::BC1_RGBA_SRGB_BLOCK, vk::Format::BC1_RGBA_UNORM_BLOCK),
+  (vk::Format::BC2_SRGB_BLOCK, vk::Format::BC2_UNORM_BLOCK),
+  (vk::Format::BC3_SRGB_BLOCK, vk::Format::BC3_UNORM_BLOCK),
+  (vk::Format::BC7_SRGB_BLOCK, vk::Format::BC7_UNORM_BLOCK),
+  (vk::Format::ETC2_R8G8B8_SRGB_BLOCK, vk::Format::ETC2_R8G8B8_UNORM_BLOCK),
+  (vk::Format::ETC2_R8G8B8A1_SRGB_BLOCK, vk::Format::ETC2_R8G8B8A1_UNORM_BLOCK),
+  (vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK, vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK),
+  (vk::Format::ASTC_4X4_SRGB_BLOCK, vk::Format::ASTC_4X4_UNORM_BLOCK),
+];
+
+fn with_color_space(format: vk::Format, color_space: ColorSpace) -> vk::Format {
+  SRGB_UNORM_PAIRS.iter().find(|(srgb, unorm)| format == *srgb || format == *unorm).map_or(
+    format,
+    |(srgb, unorm)| match color_space {
+      ColorSpace::Srgb => *srgb,
+      ColorSpace::Linear => *unorm,
+    },
+  )
+}
+
+impl TransferManager {
+  // the image crate isn't used for block compressed formats, support depends on the gpu
+  fn supports_sampling(&self, format: vk::Format) -> bool {
+    let format_properties = unsafe {
+      self
+        .vk_context
+        .vk_loaders
+        .vk_driver
+        .get_physical_device_format_properties(self.vk_context.gpu, format)
+    };
+    format_properties.optimal_tiling_features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE)
+  }
+
+  // the first transcode target the gpu can sample, the sRGB variants go along with these
+  fn basis_target(&self) -> Option<vk::Format> {
+    basis::TRANSCODE_FORMATS.into_iter().find(|format| self.supports_sampling(*format))
+  }
+
+  /*
+  Load a block compressed KTX2 or DDS texture, picked by the file extension, left in
+  SHADER_READ_ONLY_OPTIMAL layout. Basis Universal KTX2 textures are transcoded to BC7, ETC2 or
+  ASTC 4x4, the first of them the gpu can sample. The mip levels and array layers of the file
+  are uploaded as they are, cube maps get a CUBE_COMPATIBLE image. color_space overrides the
+  one tagged in the file where the format has both variants. Blocks till the upload is done.
+   */
+  pub fn load_compressed_texture(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    path: &Path,
+    color_space: ColorSpace,
+    name: &str,
+  ) -> Result<AdAllocatedImage, String> {
+    let (texture, ticket) = self.queue_compressed_texture(allocator, path, color_space, name)?;
+    self.wait_for_upload(ticket)?;
+    Ok(texture)
+  }
+
+  // same as load_compressed_texture but returns once the copy is recorded into the open batch
+  pub fn queue_compressed_texture(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    path: &Path,
+    color_space: ColorSpace,
+    name: &str,
+  ) -> Result<(AdAllocatedImage, UploadTicket), String> {
+    let bytes =
+      std::fs::read(path).map_err(|e| format!("at reading texture file {path:?}: {e}"))?;
+    let extension = path.extension().and_then(|x| x.to_str()).map(|x| x.to_ascii_lowercase());
+    let mut texture = match extension.as_deref() {
+      Some("ktx2") => parse_ktx2(&bytes, self.basis_target()),
+      Some("dds") => parse_dds(&bytes),
+      _ => Err("expected a .ktx2 or .dds file".to_string()),
+    }
+    .map_err(|e| format!("at loading compressed texture {path:?}: {e}"))?;
+    texture.format = with_color_space(texture.format, color_space);
+    if !self.supports_sampling(texture.format) {
+      return Err(format!("format {:?} of {path:?} can't be sampled on this gpu", texture.format));
+    }
+
+    let cube_flags = if texture.cube {
+      vk::ImageCreateFlags::CUBE_COMPATIBLE
+    } else {
+      vk::ImageCreateFlags::empty()
+    };
+    let image = AdAllocatedImage::new(
+      Arc::clone(&self.vk_context.device),
+      Arc::clone(&allocator),
+      name,
+      vk::ImageCreateInfo::default()
+        .flags(cube_flags)
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(texture.format)
+        .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .mip_levels(texture.mip_levels)
+        .array_layers(texture.array_layers)
+        .extent(texture.extent),
+      MemoryLocation::GpuOnly,
+    )
+    .map_err(|e| format!("at creating compressed tex ad image: {e}"))?;
+
+    let mip_chain = MipChain {
+      image: image.inner,
+      extent: texture.extent,
+      mip_levels: texture.mip_levels,
+      final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    };
+    let release_families = self.release_families(self.vk_context.transfer_q_idx);
+    let ticket =
+      self.record_upload(allocator, name, &texture.data, |cmd_buffer, stage_buffer, offset| {
+        let regions: Vec<_> = texture
+          .regions
+          .iter()
+          .map(|region| region.buffer_offset(offset + region.buffer_offset))
+          .collect();
+        record_image_copy(
+          cmd_buffer,
+          stage_buffer,
+          &regions,
+          texture.array_layers,
+          mip_chain,
+          release_families,
+        )
+      })?;
+    Ok((image, ticket))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::basis::tests::{basis_ktx2, TestKtx2, COLOR_MODEL_RGBSDA};
+  use basis_universal::BasisTextureFormat;
+  use ddsfile::{AlphaMode, D3D10ResourceDimension, NewD3dParams, NewDxgiParams};
+
+  fn region_layout(regions: &[vk::BufferImageCopy]) -> Vec<(u64, u32, u32, u32, u32, u32)> {
+    regions
+      .iter()
+      .map(|x| {
+        let subresource = x.image_subresource;
+        (
+          x.buffer_offset,
+          subresource.mip_level,
+          subresource.base_array_layer,
+          subresource.layer_count,
+          x.image_extent.width,
+          x.image_extent.height,
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn parse_ktx2_transcodes_basis_textures_to_the_target() {
+    for encoding in [BasisTextureFormat::ETC1S, BasisTextureFormat::UASTC4x4] {
+      let (_, ktx2) = basis_ktx2(encoding, 37, 21, 2);
+      for target in basis::TRANSCODE_FORMATS {
+        let texture = parse_ktx2(&ktx2, Some(target)).unwrap();
+        assert_eq!(texture.format, target);
+        assert_eq!(texture.extent, vk::Extent3D { width: 37, height: 21, depth: 1 });
+        assert_eq!((texture.mip_levels, texture.array_layers, texture.cube), (6, 2, false));
+        // every target has 16 byte 4x4 blocks, both layers of a level in one copy
+        let mut offset = 0;
+        let mut expected = vec![];
+        for level in 0..6 {
+          let extent = mip_extent(texture.extent, level);
+          expected.push((offset, level, 0, 2, extent.width, extent.height));
+          offset += 2 * 16 * (extent.width.div_ceil(4) * extent.height.div_ceil(4)) as u64;
+        }
+        assert_eq!(region_layout(&texture.regions), expected);
+        assert_eq!(texture.data.len() as u64, offset);
+      }
+      let error = parse_ktx2(&ktx2, None).err().unwrap();
+      assert!(error.contains("can't be sampled"), "{error}");
+    }
+  }
+
+  #[test]
+  fn parse_ktx2_takes_only_block_compressed_formats() {
+    let ktx2 = |format: vk::Format, scheme: Option<SupercompressionScheme>, level: Vec<u8>| {
+      TestKtx2 {
+        format,
+        width: 8,
+        height: 4,
+        layer_count: 0,
+        scheme,
+        color_model: COLOR_MODEL_RGBSDA,
+        channel_types: &[0, 1, 2],
+        levels: &[level],
+        global_data: &[],
+      }
+      .bytes()
+    };
+
+    let blocks: Vec<u8> = (0..16).collect();
+    for scheme in [None, Some(SupercompressionScheme::Zstandard)] {
+      let texture =
+        parse_ktx2(&ktx2(vk::Format::BC1_RGB_UNORM_BLOCK, scheme, blocks.clone()), None).unwrap();
+      assert_eq!(texture.format, vk::Format::BC1_RGB_UNORM_BLOCK);
+      assert_eq!((texture.mip_levels, texture.array_layers), (1, 1));
+      assert_eq!(texture.data, blocks);
+      assert_eq!(region_layout(&texture.regions), [(0, 0, 0, 1, 8, 4)]);
+    }
+
+    // 3 byte texels would break the staging buffer alignment of the copies
+    for format in [vk::Format::R8G8B8_UNORM, vk::Format::R8G8B8A8_UNORM] {
+      let error = parse_ktx2(&ktx2(format, None, vec![0; 128]), None).err().unwrap();
+      assert!(error.contains("not block compressed"), "{error}");
+    }
+  }
+
+  #[test]
+  fn parse_dds_reads_dx10_and_legacy_headers() {
+    let mut dds = Dds::new_dxgi(NewDxgiParams {
+      height: 8,
+      width: 12,
+      depth: None,
+      format: DxgiFormat::BC7_UNorm_sRGB,
+      mipmap_levels: Some(3),
+      array_layers: Some(2),
+      caps2: None,
+      is_cubemap: false,
+      resource_dimension: D3D10ResourceDimension::Texture2D,
+      alpha_mode: AlphaMode::Unknown,
+    })
+    .unwrap();
+    // ddsfile sizes each mip a quarter of the one above, short of the 144 bytes per layer
+    dds.data = (0..288).map(|x| x as u8).collect();
+    let mut bytes = vec![];
+    dds.write(&mut bytes).unwrap();
+    let texture = parse_dds(&bytes).unwrap();
+    assert_eq!(texture.format, vk::Format::BC7_SRGB_BLOCK);
+    assert_eq!((texture.mip_levels, texture.array_layers, texture.cube), (3, 2, false));
+    assert_eq!(texture.data, dds.data);
+    // the whole mip chain of layer 0, then of layer 1
+    assert_eq!(
+      region_layout(&texture.regions),
+      [
+        (0, 0, 0, 1, 12, 8),
+        (96, 1, 0, 1, 6, 4),
+        (128, 2, 0, 1, 3, 2),
+        (144, 0, 1, 1, 12, 8),
+        (240, 1, 1, 1, 6, 4),
+        (272, 2, 1, 1, 3, 2),
+      ]
+    );
+    bytes.truncate(bytes.len() - 16);
+    let error = parse_dds(&bytes).err().unwrap();
+    assert!(error.contains("expected 288"), "{error}");
+
+    let legacy = |format: D3DFormat| {
+      let dds = Dds::new_d3d(NewD3dParams {
+        height: 8,
+        width: 8,
+        depth: None,
+        format,
+        mipmap_levels: Some(4),
+        caps2: None,
+      })
+      .unwrap();
+      let mut bytes = vec![];
+      dds.write(&mut bytes).unwrap();
+      parse_dds(&bytes)
+    };
+    for (format, vk_format, block_bytes) in [
+      (D3DFormat::DXT1, vk::Format::BC1_RGBA_UNORM_BLOCK, 8),
+      (D3DFormat::DXT5, vk::Format::BC3_UNORM_BLOCK, 16),
+    ] {
+      let texture = legacy(format).unwrap();
+      assert_eq!(texture.format, vk_format);
+      assert_eq!((texture.mip_levels, texture.array_layers), (4, 1));
+      assert_eq!(
+        region_layout(&texture.regions),
+        [
+          (0, 0, 0, 1, 8, 8),
+          (4 * block_bytes, 1, 0, 1, 4, 4),
+          (5 * block_bytes, 2, 0, 1, 2, 2),
+          (6 * block_bytes, 3, 0, 1, 1, 1),
+        ]
+      );
+    }
+    let error = legacy(D3DFormat::A8R8G8B8).err().unwrap();
+    assert!(error.contains("not block compressed"), "{error}");
+  }
+
+  #[test]
+  fn with_color_space_switches_paired_formats() {
+    for (srgb, unorm) in SRGB_UNORM_PAIRS {
+      for format in [srgb, unorm] {
+        assert_eq!(with_color_space(format, ColorSpace::Srgb), srgb);
+        assert_eq!(with_color_space(format, ColorSpace::Linear), unorm);
+      }
+    }
+    // no sRGB variant to switch to
+    for format in [vk::Format::BC1_RGB_UNORM_BLOCK, vk::Format::BC5_UNORM_BLOCK] {
+      assert_eq!(with_color_space(format, ColorSpace::Srgb), format);
+      assert_eq!(with_color_space(format, ColorSpace::Linear), format);
+    }
+  }
+}
@@ -0,0 +1,651 @@
+use crate::upload_batch::AcquireBarrier;
+use crate::TransferManager;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use vk_context::ash;
+use vk_context::ash::vk;
+use vk_context::auto_drop_wrappers::{
+  AdAllocatedImage, AdDescriptorSet, AdDescriptorSetLayout, AdSampler,
+};
+use vk_context::gpu_allocator::vulkan::Allocator;
+use vk_context::gpu_allocator::MemoryLocation;
+use vk_context::SamplerDesc;
+
+static EQUIRECT_TO_CUBE_COMP_SPV: &[u8] = include_bytes!("../shaders/equirect_to_cube.comp.spv");
+static IRRADIANCE_COMP_SPV: &[u8] = include_bytes!("../shaders/irradiance.comp.spv");
+static PREFILTER_COMP_SPV: &[u8] = include_bytes!("../shaders/prefilter.comp.spv");
+static BRDF_LUT_COMP_SPV: &[u8] = include_bytes!("../shaders/brdf_lut.comp.spv");
+
+// the environment cube gets half the equirect height per face, up to this
+pub const MAX_ENVIRONMENT_FACE_SIZE: u32 = 1024;
+pub const IRRADIANCE_FACE_SIZE: u32 = 32;
+pub const PREFILTERED_FACE_SIZE: u32 = 128;
+// mip 0 is roughness 0 and the last mip roughness 1
+pub const PREFILTERED_MAX_MIP_LEVELS: u32 = 5;
+pub const BRDF_LUT_SIZE: u32 = 256;
+const CUBE_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+// has to match local_size in the bake shaders
+const WORKGROUP_SIZE: u32 = 8;
+
+/*
+Image based lighting baked from an equirectangular environment: a diffuse irradiance cube,
+a specular cube prefiltered for increasing roughness along its mips and the split sum
+BRDF lookup table. All three are left in SHADER_READ_ONLY_OPTIMAL layout and are meant to
+be sampled with the sampler in here.
+ */
+pub struct EnvironmentMap {
+  device: Arc<ash::Device>,
+  pub sampler: Arc<AdSampler>,
+  pub irradiance_view: vk::ImageView,
+  pub prefiltered_view: vk::ImageView,
+  pub brdf_lut_view: vk::ImageView,
+  pub prefiltered_mip_levels: u32,
+  _irradiance: AdAllocatedImage,
+  _prefiltered: AdAllocatedImage,
+  _brdf_lut: AdAllocatedImage,
+}
+
+impl Drop for EnvironmentMap {
+  fn drop(&mut self) {
+    unsafe {
+      self.device.destroy_image_view(self.irradiance_view, None);
+      self.device.destroy_image_view(self.prefiltered_view, None);
+      self.device.destroy_image_view(self.brdf_lut_view, None);
+    }
+  }
+}
+
+// compute pipeline with a single descriptor set and push constants
+struct BakePipeline {
+  device: Arc<ash::Device>,
+  set_layout: AdDescriptorSetLayout,
+  pipeline_layout: vk::PipelineLayout,
+  pipeline: vk::Pipeline,
+}
+
+impl BakePipeline {
+  fn new(
+    device: Arc<ash::Device>,
+    spv_bytes: &[u8],
+    descriptor_types: &[vk::DescriptorType],
+    push_constants_size: u32,
+  ) -> Result<Self, String> {
+    let bindings: Vec<_> = descriptor_types
+      .iter()
+      .zip(0..)
+      .map(|(descriptor_type, binding)| {
+        vk::DescriptorSetLayoutBinding::default()
+          .stage_flags(vk::ShaderStageFlags::COMPUTE)
+          .binding(binding)
+          .descriptor_count(1)
+          .descriptor_type(*descriptor_type)
+      })
+      .collect();
+    let set_layout = AdDescriptorSetLayout::new(Arc::clone(&device), &bindings)
+      .map_err(|e| format!("at bake descriptor set layout: {e}"))?;
+    // owns the layouts from here on, early returns clean up on drop
+    let mut bake_pipeline = Self {
+      device,
+      set_layout,
+      pipeline_layout: vk::PipelineLayout::null(),
+      pipeline: vk::Pipeline::null(),
+    };
+    bake_pipeline.pipeline_layout = unsafe {
+      bake_pipeline
+        .device
+        .create_pipeline_layout(
+          &vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&[bake_pipeline.set_layout.inner])
+            .push_constant_ranges(&[vk::PushConstantRange::default()
+              .stage_flags(vk::ShaderStageFlags::COMPUTE)
+              .offset(0)
+              .size(push_constants_size)]),
+          None,
+        )
+        .map_err(|e| format!("at bake pipeline layout create: {e}"))?
+    };
+
+    let spv_code = ash::util::read_spv(&mut Cursor::new(spv_bytes))
+      .map_err(|e| format!("at reading spv code: {e}"))?;
+    let shader = unsafe {
+      bake_pipeline
+        .device
+        .create_shader_module(&vk::ShaderModuleCreateInfo::default().code(&spv_code), None)
+        .map_err(|e| format!("at shader module create: {e}"))?
+    };
+    let pipeline_result = unsafe {
+      bake_pipeline.device.create_compute_pipelines(
+        vk::PipelineCache::null(),
+        &[vk::ComputePipelineCreateInfo::default()
+          .layout(bake_pipeline.pipeline_layout)
+          .stage(
+            vk::PipelineShaderStageCreateInfo::default()
+              .stage(vk::ShaderStageFlags::COMPUTE)
+              .module(shader)
+              .name(c"main"),
+          )],
+        None,
+      )
+    };
+    unsafe {
+      bake_pipeline.device.destroy_shader_module(shader, None);
+    }
+    bake_pipeline.pipeline =
+      pipeline_result.map_err(|e| format!("at creating compute pipeline: {}", e.1))?[0];
+    Ok(bake_pipeline)
+  }
+}
+
+impl Drop for BakePipeline {
+  fn drop(&mut self) {
+    unsafe {
+      self.device.destroy_pipeline(self.pipeline, None);
+      self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+    }
+  }
+}
+
+// vulkan objects only needed while baking, destroyed once the bake is waited on
+struct BakeScratch {
+  device: Arc<ash::Device>,
+  views: Vec<vk::ImageView>,
+}
+
+impl Drop for BakeScratch {
+  fn drop(&mut self) {
+    unsafe {
+      for view in &self.views {
+        self.device.destroy_image_view(*view, None);
+      }
+    }
+  }
+}
+
+fn create_view(
+  device: &ash::Device,
+  image: &AdAllocatedImage,
+  view_type: vk::ImageViewType,
+  base_mip_level: u32,
+  level_count: u32,
+) -> Result<vk::ImageView, String> {
+  let layer_count = match view_type {
+    vk::ImageViewType::CUBE | vk::ImageViewType::TYPE_2D_ARRAY => 6,
+    _ => 1,
+  };
+  unsafe {
+    device
+      .create_image_view(
+        &vk::ImageViewCreateInfo::default()
+          .format(image.format)
+          .image(image.inner)
+          .view_type(view_type)
+          .components(vk::ComponentMapping::default())
+          .subresource_range(
+            vk::ImageSubresourceRange::default()
+              .aspect_mask(vk::ImageAspectFlags::COLOR)
+              .base_mip_level(base_mip_level)
+              .level_count(level_count)
+              .base_array_layer(0)
+              .layer_count(layer_count),
+          ),
+        None,
+      )
+      .map_err(|e| format!("at {} view create: {e}", image.name))
+  }
+}
+
+fn layout_barrier(
+  image: &AdAllocatedImage,
+  layer_count: u32,
+  old_layout: vk::ImageLayout,
+  new_layout: vk::ImageLayout,
+  src_access_mask: vk::AccessFlags,
+  dst_access_mask: vk::AccessFlags,
+) -> vk::ImageMemoryBarrier<'static> {
+  vk::ImageMemoryBarrier::default()
+    .image(image.inner)
+    .subresource_range(
+      vk::ImageSubresourceRange::default()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(vk::REMAINING_MIP_LEVELS)
+        .base_array_layer(0)
+        .layer_count(layer_count),
+    )
+    .src_access_mask(src_access_mask)
+    .dst_access_mask(dst_access_mask)
+    .old_layout(old_layout)
+    .new_layout(new_layout)
+    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+}
+
+impl TransferManager {
+  fn create_bake_image(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    name: &str,
+    size: u32,
+    mip_levels: u32,
+    cube: bool,
+  ) -> Result<AdAllocatedImage, String> {
+    let (flags, array_layers) = if cube {
+      (vk::ImageCreateFlags::CUBE_COMPATIBLE, 6)
+    } else {
+      (vk::ImageCreateFlags::empty(), 1)
+    };
+    AdAllocatedImage::new(
+      Arc::clone(&self.vk_context.device),
+      allocator,
+      name,
+      vk::ImageCreateInfo::default()
+        .flags(flags)
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(CUBE_FORMAT)
+        .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .mip_levels(mip_levels)
+        .array_layers(array_layers)
+        .extent(vk::Extent3D { width: size, height: size, depth: 1 }),
+      MemoryLocation::GpuOnly,
+    )
+    .map_err(|e| format!("at creating {name} image: {e}"))
+  }
+
+  /*
+  Load an equirectangular .hdr or .exr file and bake it into an EnvironmentMap.
+  Blocks till the bake is done.
+   */
+  pub fn load_environment_map(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    path: &Path,
+    name: &str,
+  ) -> Result<EnvironmentMap, String> {
+    let equirect = image::open(path)
+      .map_err(|e| format!("at loading environment map file {path:?}: {e}"))?
+      .to_rgba32f();
+    self.create_environment_map(allocator, &equirect, name)
+  }
+
+  /*
+  Bake an equirectangular environment into an EnvironmentMap with compute shaders on the
+  compute queue. The cube converted from the equirect is only kept while baking.
+  Blocks till the bake is done.
+   */
+  pub fn create_environment_map(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    equirect: &image::Rgba32FImage,
+    name: &str,
+  ) -> Result<EnvironmentMap, String> {
+    let device = &self.vk_context.device;
+    let environment_face_size = (equirect.height() / 2).clamp(1, MAX_ENVIRONMENT_FACE_SIZE);
+    let irradiance_face_size = IRRADIANCE_FACE_SIZE.min(environment_face_size);
+    let prefiltered_face_size = PREFILTERED_FACE_SIZE.min(environment_face_size);
+    let prefiltered_mip_levels = PREFILTERED_MAX_MIP_LEVELS.min(prefiltered_face_size.ilog2() + 1);
+
+    // 32 bit float is sampleable everywhere but not linear filterable, the sampler is nearest
+    let equirect_image = AdAllocatedImage::new(
+      Arc::clone(device),
+      Arc::clone(&allocator),
+      &format!("{name}_equirect"),
+      vk::ImageCreateInfo::default()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(vk::Format::R32G32B32A32_SFLOAT)
+        .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .mip_levels(1)
+        .array_layers(1)
+        .extent(vk::Extent3D { width: equirect.width(), height: equirect.height(), depth: 1 }),
+      MemoryLocation::GpuOnly,
+    )
+    .map_err(|e| format!("at creating equirect image: {e}"))?;
+    let equirect_data = equirect.as_raw();
+    let equirect_bytes = unsafe {
+      std::slice::from_raw_parts(
+        equirect_data.as_ptr() as *const u8,
+        std::mem::size_of_val(equirect_data.as_slice()),
+      )
+    };
+    let stage_buffer =
+      self.make_stage_buffer(Arc::clone(&allocator), &format!("{name}_equirect"), equirect_bytes)?;
+
+    let environment = self.create_bake_image(
+      Arc::clone(&allocator),
+      &format!("{name}_environment"),
+      environment_face_size,
+      1,
+      true,
+    )?;
+    let irradiance = self.create_bake_image(
+      Arc::clone(&allocator),
+      &format!("{name}_irradiance"),
+      irradiance_face_size,
+      1,
+      true,
+    )?;
+    let prefiltered = self.create_bake_image(
+      Arc::clone(&allocator),
+      &format!("{name}_prefiltered"),
+      prefiltered_face_size,
+      prefiltered_mip_levels,
+      true,
+    )?;
+    let brdf_lut = self.create_bake_image(
+      Arc::clone(&allocator),
+      &format!("{name}_brdf_lut"),
+      BRDF_LUT_SIZE,
+      1,
+      false,
+    )?;
+
+    let sampler = self.vk_context.get_sampler(SamplerDesc {
+      address_modes: [vk::SamplerAddressMode::CLAMP_TO_EDGE; 3],
+      anisotropy: false,
+      ..Default::default()
+    })?;
+    // owns the kept images from here on, early returns clean up on drop
+    let mut environment_map = EnvironmentMap {
+      device: Arc::clone(device),
+      sampler,
+      irradiance_view: vk::ImageView::null(),
+      prefiltered_view: vk::ImageView::null(),
+      brdf_lut_view: vk::ImageView::null(),
+      prefiltered_mip_levels,
+      _irradiance: irradiance,
+      _prefiltered: prefiltered,
+      _brdf_lut: brdf_lut,
+    };
+    environment_map.irradiance_view =
+      create_view(device, &environment_map._irradiance, vk::ImageViewType::CUBE, 0, 1)?;
+    environment_map.prefiltered_view = create_view(
+      device,
+      &environment_map._prefiltered,
+      vk::ImageViewType::CUBE,
+      0,
+      prefiltered_mip_levels,
+    )?;
+    environment_map.brdf_lut_view =
+      create_view(device, &environment_map._brdf_lut, vk::ImageViewType::TYPE_2D, 0, 1)?;
+
+    let mut scratch = BakeScratch { device: Arc::clone(device), views: vec![] };
+    let equirect_sampler = self.vk_context.get_sampler(SamplerDesc {
+      mag_filter: vk::Filter::NEAREST,
+      min_filter: vk::Filter::NEAREST,
+      mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+      address_modes: [
+        vk::SamplerAddressMode::REPEAT,
+        vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        vk::SamplerAddressMode::CLAMP_TO_EDGE,
+      ],
+      anisotropy: false,
+      max_lod: 0.0,
+      ..Default::default()
+    })?;
+    let sampled_storage = [vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::DescriptorType::STORAGE_IMAGE];
+    let equirect_to_cube_pipeline =
+      BakePipeline::new(Arc::clone(device), EQUIRECT_TO_CUBE_COMP_SPV, &sampled_storage, 4)
+        .map_err(|e| format!("at equirect to cube pipeline: {e}"))?;
+    let irradiance_pipeline =
+      BakePipeline::new(Arc::clone(device), IRRADIANCE_COMP_SPV, &sampled_storage, 4)
+        .map_err(|e| format!("at irradiance pipeline: {e}"))?;
+    let prefilter_pipeline =
+      BakePipeline::new(Arc::clone(device), PREFILTER_COMP_SPV, &sampled_storage, 8)
+        .map_err(|e| format!("at prefilter pipeline: {e}"))?;
+    let brdf_lut_pipeline = BakePipeline::new(
+      Arc::clone(device),
+      BRDF_LUT_COMP_SPV,
+      &[vk::DescriptorType::STORAGE_IMAGE],
+      4,
+    )
+    .map_err(|e| format!("at brdf lut pipeline: {e}"))?;
+
+    // equirect to cube, irradiance, brdf lut and one prefilter set per mip
+    let mut set_layouts = vec![
+      &equirect_to_cube_pipeline.set_layout,
+      &irradiance_pipeline.set_layout,
+      &brdf_lut_pipeline.set_layout,
+    ];
+    set_layouts.extend((0..prefiltered_mip_levels).map(|_| &prefilter_pipeline.set_layout));
+    let sets = set_layouts
+      .into_iter()
+      .map(|set_layout| AdDescriptorSet::new(Arc::clone(&self.descriptor_allocator), set_layout))
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|e| format!("at bake descriptor sets: {e}"))?;
+
+    let equirect_view = create_view(device, &equirect_image, vk::ImageViewType::TYPE_2D, 0, 1)?;
+    scratch.views.push(equirect_view);
+    let environment_view = create_view(device, &environment, vk::ImageViewType::CUBE, 0, 1)?;
+    scratch.views.push(environment_view);
+    let environment_storage_view =
+      create_view(device, &environment, vk::ImageViewType::TYPE_2D_ARRAY, 0, 1)?;
+    scratch.views.push(environment_storage_view);
+    let irradiance_storage_view =
+      create_view(device, &environment_map._irradiance, vk::ImageViewType::TYPE_2D_ARRAY, 0, 1)?;
+    scratch.views.push(irradiance_storage_view);
+    let mut prefiltered_storage_views = Vec::with_capacity(prefiltered_mip_levels as usize);
+    for mip in 0..prefiltered_mip_levels {
+      let view =
+        create_view(device, &environment_map._prefiltered, vk::ImageViewType::TYPE_2D_ARRAY, mip, 1)?;
+      scratch.views.push(view);
+      prefiltered_storage_views.push(view);
+    }
+
+    let read_only = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+    let general = vk::ImageLayout::GENERAL;
+    sets[0]
+      .write_builder()
+      .add_combined_image_sampler(0, equirect_sampler.inner, equirect_view, read_only)
+      .add_storage_image(1, environment_storage_view, general)
+      .write();
+    sets[1]
+      .write_builder()
+      .add_combined_image_sampler(0, environment_map.sampler.inner, environment_view, read_only)
+      .add_storage_image(1, irradiance_storage_view, general)
+      .write();
+    sets[2].write_builder().add_storage_image(0, environment_map.brdf_lut_view, general).write();
+    for (set, view) in sets[3..].iter().zip(&prefiltered_storage_views) {
+      set
+        .write_builder()
+        .add_combined_image_sampler(0, environment_map.sampler.inner, environment_view, read_only)
+        .add_storage_image(1, *view, general)
+        .write();
+    }
+
+    let cmd_buffer = self
+      .compute_cmd_pool
+      .allocate_command_buffers(vk::CommandBufferLevel::PRIMARY, 1)?
+      .swap_remove(0);
+    cmd_buffer.begin(vk::CommandBufferBeginInfo::default())?;
+    // the equirect is copied on the compute queue as well so it needs no ownership transfer
+    cmd_buffer.pipeline_barrier(
+      vk::PipelineStageFlags::TOP_OF_PIPE,
+      vk::PipelineStageFlags::TRANSFER | vk::PipelineStageFlags::COMPUTE_SHADER,
+      vk::DependencyFlags::empty(),
+      &[],
+      &[],
+      &[
+        layout_barrier(
+          &equirect_image,
+          1,
+          vk::ImageLayout::UNDEFINED,
+          vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+          vk::AccessFlags::NONE,
+          vk::AccessFlags::TRANSFER_WRITE,
+        ),
+        layout_barrier(
+          &environment,
+          6,
+          vk::ImageLayout::UNDEFINED,
+          general,
+          vk::AccessFlags::NONE,
+          vk::AccessFlags::SHADER_WRITE,
+        ),
+        layout_barrier(
+          &environment_map._irradiance,
+          6,
+          vk::ImageLayout::UNDEFINED,
+          general,
+          vk::AccessFlags::NONE,
+          vk::AccessFlags::SHADER_WRITE,
+        ),
+        layout_barrier(
+          &environment_map._prefiltered,
+          6,
+          vk::ImageLayout::UNDEFINED,
+          general,
+          vk::AccessFlags::NONE,
+          vk::AccessFlags::SHADER_WRITE,
+        ),
+        layout_barrier(
+          &environment_map._brdf_lut,
+          1,
+          vk::ImageLayout::UNDEFINED,
+          general,
+          vk::AccessFlags::NONE,
+          vk::AccessFlags::SHADER_WRITE,
+        ),
+      ],
+    );
+    cmd_buffer.copy_buffer_to_image(
+      stage_buffer.inner,
+      equirect_image.inner,
+      vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+      &[vk::BufferImageCopy::default()
+        .image_subresource(
+          vk::ImageSubresourceLayers::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(1),
+        )
+        .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+        .image_extent(equirect_image.resolution)],
+    );
+    cmd_buffer.pipeline_barrier(
+      vk::PipelineStageFlags::TRANSFER,
+      vk::PipelineStageFlags::COMPUTE_SHADER,
+      vk::DependencyFlags::empty(),
+      &[],
+      &[],
+      &[layout_barrier(
+        &equirect_image,
+        1,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        read_only,
+        vk::AccessFlags::TRANSFER_WRITE,
+        vk::AccessFlags::SHADER_READ,
+      )],
+    );
+
+    // size is the image width and height, layers the faces to write
+    let dispatch = |pipeline: &BakePipeline,
+                    set: &AdDescriptorSet,
+                    push_constants: &[u8],
+                    size: u32,
+                    layers: u32| {
+      cmd_buffer.bind_pipeline(vk::PipelineBindPoint::COMPUTE, pipeline.pipeline);
+      cmd_buffer.bind_descriptor_sets(
+        vk::PipelineBindPoint::COMPUTE,
+        pipeline.pipeline_layout,
+        0,
+        &[set.inner],
+        &[],
+      );
+      cmd_buffer.push_constants(
+        pipeline.pipeline_layout,
+        vk::ShaderStageFlags::COMPUTE,
+        0,
+        push_constants,
+      );
+      let group_count = size.div_ceil(WORKGROUP_SIZE);
+      cmd_buffer.dispatch(group_count, group_count, layers);
+    };
+    let environment_params = environment_face_size.to_ne_bytes();
+    dispatch(&equirect_to_cube_pipeline, &sets[0], &environment_params, environment_face_size, 6);
+    dispatch(&brdf_lut_pipeline, &sets[2], &BRDF_LUT_SIZE.to_ne_bytes(), BRDF_LUT_SIZE, 1);
+
+    cmd_buffer.pipeline_barrier(
+      vk::PipelineStageFlags::COMPUTE_SHADER,
+      vk::PipelineStageFlags::COMPUTE_SHADER,
+      vk::DependencyFlags::empty(),
+      &[],
+      &[],
+      &[layout_barrier(
+        &environment,
+        6,
+        general,
+        read_only,
+        vk::AccessFlags::SHADER_WRITE,
+        vk::AccessFlags::SHADER_READ,
+      )],
+    );
+    let irradiance_params = irradiance_face_size.to_ne_bytes();
+    dispatch(&irradiance_pipeline, &sets[1], &irradiance_params, irradiance_face_size, 6);
+    for (mip, set) in (0..prefiltered_mip_levels).zip(&sets[3..]) {
+      let mip_face_size = (prefiltered_face_size >> mip).max(1);
+      let roughness = if prefiltered_mip_levels > 1 {
+        mip as f32 / (prefiltered_mip_levels - 1) as f32
+      } else {
+        0.0
+      };
+      let mut push_constants = [0u8; 8];
+      push_constants[..4].copy_from_slice(&mip_face_size.to_ne_bytes());
+      push_constants[4..].copy_from_slice(&roughness.to_ne_bytes());
+      dispatch(&prefilter_pipeline, set, &push_constants, mip_face_size, 6);
+    }
+
+    // the compute queue might not support later stages, the fence wait makes the writes visible.
+    // this also releases the maps to the graphics family if compute is a different family
+    let (src_family, dst_family) = self.release_families(self.vk_context.compute_q_idx);
+    let releases = [
+      (&environment_map._irradiance, 6),
+      (&environment_map._prefiltered, 6),
+      (&environment_map._brdf_lut, 1),
+    ]
+    .map(|(image, layer_count)| {
+      layout_barrier(
+        image,
+        layer_count,
+        general,
+        read_only,
+        vk::AccessFlags::SHADER_WRITE,
+        vk::AccessFlags::NONE,
+      )
+      .src_queue_family_index(src_family)
+      .dst_queue_family_index(dst_family)
+    });
+    cmd_buffer.pipeline_barrier(
+      vk::PipelineStageFlags::COMPUTE_SHADER,
+      vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+      vk::DependencyFlags::empty(),
+      &[],
+      &[],
+      &releases,
+    );
+    cmd_buffer.end()?;
+
+    let acquires: Vec<_> = if src_family == dst_family {
+      vec![]
+    } else {
+      releases
+        .iter()
+        .map(|release| {
+          AcquireBarrier::Image(
+            release.src_access_mask(vk::AccessFlags::NONE).dst_access_mask(vk::AccessFlags::MEMORY_READ),
+          )
+        })
+        .collect()
+    };
+    self.submit_and_wait_on(self.vk_context.compute_q, &cmd_buffer, &acquires)?;
+    Ok(environment_map)
+  }
+}
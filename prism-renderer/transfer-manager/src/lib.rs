@@ -1,12 +1,64 @@
+use mipmaps::{downsample_mip_chain, mip_extent, record_mip_blits, MipChain};
+use std::borrow::Cow;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use upload_batch::{AcquireBarrier, UploadBatches};
+use vk_context::descriptors::DescriptorAllocator;
 use vk_context::gpu_allocator::vulkan::Allocator;
 use vk_context::gpu_allocator::MemoryLocation;
 use vk_context::{ash::vk, VkContext};
-use vk_context::auto_drop_wrappers::{AdAllocatedBuffer, AdAllocatedImage, AdCommandPool};
+use vk_context::auto_drop_wrappers::{
+  AdAllocatedBuffer, AdAllocatedImage, AdCommandBuffer, AdCommandPool,
+};
 
+mod basis;
+mod compressed;
+mod environment_map;
+mod mipmaps;
+mod upload_batch;
+
+pub use environment_map::EnvironmentMap;
+pub use image;
+pub use mipmaps::mip_level_count;
+pub use upload_batch::{UploadTicket, STAGING_CHUNK_SIZE};
+
+/*
+How texel values are encoded. Color textures (albedo, emissive) are Srgb and get decoded to
+linear values when sampled, data textures (normals, roughness, masks) are read as they are.
+ */
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+  Srgb,
+  #[default]
+  Linear,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TextureUploadOptions {
+  /*
+  Fill a full mip chain, by blits on the graphics queue or on the cpu when the format can't
+  be blitted with linear filtering.
+   */
+  pub generate_mipmaps: bool,
+  pub color_space: ColorSpace,
+}
+
+/*
+Uploads data to GPU only buffers and images on the transfer queue.
+Everything uploaded ends up owned by the graphics queue family.
+The queue_* functions record into a shared batch and return right away with an UploadTicket,
+the resource must stay alive and unused by the GPU till its ticket is done.
+The other upload functions queue and then wait on their own upload.
+ */
 pub struct TransferManager {
+  // the batches hold command buffers of cmd_pool and acquire_cmd_pool so they have to drop first
+  upload_batches: Mutex<UploadBatches>,
   cmd_pool: AdCommandPool,
+  compute_cmd_pool: AdCommandPool,
+  // graphics family pool for the acquire half of ownership transfers
+  acquire_cmd_pool: AdCommandPool,
+  // environment map bake sets
+  descriptor_allocator: Arc<Mutex<DescriptorAllocator>>,
   vk_context: Arc<VkContext>,
 }
 
@@ -16,20 +68,307 @@ impl TransferManager {
         .create_ad_command_pool(
           vk::CommandPoolCreateInfo::default()
             .queue_family_index(vk_context.transfer_q_idx)
+            .flags(
+              vk::CommandPoolCreateFlags::TRANSIENT
+                | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+            ),
+        )?;
+    let compute_cmd_pool = vk_context
+        .create_ad_command_pool(
+          vk::CommandPoolCreateInfo::default()
+            .queue_family_index(vk_context.compute_q_idx)
             .flags(vk::CommandPoolCreateFlags::TRANSIENT),
         )?;
+    let acquire_cmd_pool = vk_context
+        .create_ad_command_pool(
+          vk::CommandPoolCreateInfo::default()
+            .queue_family_index(vk_context.graphics_q_idx)
+            .flags(
+              vk::CommandPoolCreateFlags::TRANSIENT
+                | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+            ),
+        )?;
+
+    Ok(Self {
+      upload_batches: Mutex::new(UploadBatches::default()),
+      cmd_pool,
+      compute_cmd_pool,
+      acquire_cmd_pool,
+      descriptor_allocator: Arc::new(Mutex::new(DescriptorAllocator::new(Arc::clone(
+        &vk_context.device,
+      )))),
+      vk_context,
+    })
+  }
+
+  /*
+  Submit and block till done. With acquire barriers the submission signals a semaphore
+  the graphics queue acquire waits on, and the wait is for the acquire.
+   */
+  fn submit_and_wait_on(
+    &self,
+    queue: vk::Queue,
+    cmd_buffer: &AdCommandBuffer,
+    acquire_barriers: &[AcquireBarrier],
+  ) -> Result<(), String> {
+    let upload_fence = self.vk_context.create_ad_fence()?;
+    let mut batches =
+      self.upload_batches.lock().map_err(|e| format!("at getting upload batches lock: {e}"))?;
+    let semaphore = if acquire_barriers.is_empty() {
+      None
+    } else {
+      Some(self.take_semaphore(&mut batches)?)
+    };
+    let (signal_semaphores, submit_fence) = match &semaphore {
+      Some(x) => (vec![x.inner], vk::Fence::null()),
+      None => (vec![], upload_fence.inner),
+    };
+    self
+      .vk_context
+      .queue_submit(
+        queue,
+        &[vk::SubmitInfo::default()
+          .command_buffers(&[cmd_buffer.inner])
+          .signal_semaphores(&signal_semaphores)],
+        submit_fence,
+      )
+      .map_err(|e| format!("at copying data to gpu: {e}"))?;
+    let acquire = match semaphore {
+      Some(x) => Some(self.submit_acquire(&mut batches, acquire_barriers, x, upload_fence.inner)?),
+      None => None,
+    };
+    // batches may be submitted from other threads while this waits
+    drop(batches);
+
+    unsafe {
+      self
+        .vk_context
+        .device
+        .wait_for_fences(&[upload_fence.inner], true, u64::MAX)
+        .inspect_err(|e| println!("{e}"))
+        .map_err(|e| format!("at waiting for fence: {e}"))?;
+    }
+    if let Some(acquire) = acquire {
+      let mut batches =
+        self.upload_batches.lock().map_err(|e| format!("at getting upload batches lock: {e}"))?;
+      self.recycle_acquire(&mut batches, acquire);
+    }
+    Ok(())
+  }
+
+  fn make_stage_buffer(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    name: &str,
+    data: &[u8],
+  ) -> Result<AdAllocatedBuffer, String> {
+    let mut stage_buffer = AdAllocatedBuffer::new(
+      Arc::clone(&self.vk_context.device),
+      allocator,
+      name,
+      vk::BufferCreateInfo::default()
+        .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+        .size(data.len() as vk::DeviceSize),
+      MemoryLocation::CpuToGpu
+    )
+      .map_err(|e| format!("at creating staging buffer: {e}"))?;
+
+    stage_buffer
+      .allocation
+      .as_mut()
+      .ok_or("stage buffer not allocated, hmmm".to_string())?
+      .mapped_slice_mut()
+      .ok_or("at mapping stage buffer memory to CPU".to_string())?[..data.len()]
+      .copy_from_slice(data);
+    Ok(stage_buffer)
+  }
+
+  /*
+  Upload data to a new GpuOnly buffer through a staging buffer.
+  TRANSFER_DST is added to the usage flags passed in. Blocks till the copy is done.
+   */
+  pub fn upload_buffer<T: Copy>(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    data: &[T],
+    usage: vk::BufferUsageFlags,
+    name: &str,
+  ) -> Result<AdAllocatedBuffer, String> {
+    let (buffer, ticket) = self.queue_buffer_upload(allocator, data, usage, name)?;
+    self.wait_for_upload(ticket)?;
+    Ok(buffer)
+  }
 
-    Ok(Self { cmd_pool, vk_context })
+  // same as upload_buffer but returns once the copy is recorded into the open batch
+  pub fn queue_buffer_upload<T: Copy>(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    data: &[T],
+    usage: vk::BufferUsageFlags,
+    name: &str,
+  ) -> Result<(AdAllocatedBuffer, UploadTicket), String> {
+    let data_bytes = unsafe {
+      std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data))
+    };
+    if data_bytes.is_empty() {
+      return Err(format!("no data to upload for buffer {name}"));
+    }
+
+    let buffer = AdAllocatedBuffer::new(
+      Arc::clone(&self.vk_context.device),
+      Arc::clone(&allocator),
+      name,
+      vk::BufferCreateInfo::default()
+        .usage(usage | vk::BufferUsageFlags::TRANSFER_DST)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .size(data_bytes.len() as vk::DeviceSize),
+      MemoryLocation::GpuOnly
+    )
+      .map_err(|e| format!("at creating ad buffer: {e}"))?;
+
+    let (src_family, dst_family) = self.release_families(self.vk_context.transfer_q_idx);
+    let ticket = self.record_upload(allocator, name, data_bytes, |cmd_buffer, stage_buffer, offset| {
+      cmd_buffer.copy_buffer(
+        stage_buffer,
+        buffer.inner,
+        &[vk::BufferCopy::default().src_offset(offset).dst_offset(0).size(buffer.size)],
+      );
+      if src_family == dst_family {
+        return None;
+      }
+      let release = vk::BufferMemoryBarrier::default()
+        .buffer(buffer.inner)
+        .offset(0)
+        .size(vk::WHOLE_SIZE)
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::NONE)
+        .src_queue_family_index(src_family)
+        .dst_queue_family_index(dst_family);
+      cmd_buffer.pipeline_barrier(
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[release],
+        &[],
+      );
+      Some(AcquireBarrier::Buffer(
+        release.src_access_mask(vk::AccessFlags::NONE).dst_access_mask(vk::AccessFlags::MEMORY_READ),
+      ))
+    })?;
+    Ok((buffer, ticket))
   }
 
+  // image to blit from, left in TRANSFER_SRC_OPTIMAL layout. Blocks till the upload is done.
   pub fn load_image_from_file(
     &self,
     allocator: Arc<Mutex<Allocator>>,
     path: &Path,
+    color_space: ColorSpace,
     name: &str,
   ) -> Result<AdAllocatedImage, String> {
     let image_info = image::open(path).map_err(|e| format!("at loading image file: {e}"))?;
-    let image_rgba8 = image_info.to_rgba8();
+    let (image, ticket) = self.queue_rgba8_image(
+      allocator,
+      &image_info.to_rgba8(),
+      vk::ImageUsageFlags::TRANSFER_SRC,
+      vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+      TextureUploadOptions { color_space, ..Default::default() },
+      name,
+    )?;
+    self.wait_for_upload(ticket)?;
+    Ok(image)
+  }
+
+  /*
+  Load an image file as a sampled texture, left in SHADER_READ_ONLY_OPTIMAL layout.
+  Blocks till the upload is done.
+   */
+  pub fn load_texture_from_file(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    path: &Path,
+    options: TextureUploadOptions,
+    name: &str,
+  ) -> Result<AdAllocatedImage, String> {
+    let (texture, ticket) = self.queue_texture_from_file(allocator, path, options, name)?;
+    self.wait_for_upload(ticket)?;
+    Ok(texture)
+  }
+
+  // same as load_texture_from_file but returns once the copy is recorded into the open batch
+  pub fn queue_texture_from_file(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    path: &Path,
+    options: TextureUploadOptions,
+    name: &str,
+  ) -> Result<(AdAllocatedImage, UploadTicket), String> {
+    let image_info =
+      image::open(path).map_err(|e| format!("at loading texture file {path:?}: {e}"))?;
+    self.queue_texture_upload(allocator, &image_info.to_rgba8(), options, name)
+  }
+
+  pub fn upload_texture(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    texture: &image::RgbaImage,
+    options: TextureUploadOptions,
+    name: &str,
+  ) -> Result<AdAllocatedImage, String> {
+    let (texture, ticket) = self.queue_texture_upload(allocator, texture, options, name)?;
+    self.wait_for_upload(ticket)?;
+    Ok(texture)
+  }
+
+  pub fn queue_texture_upload(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    texture: &image::RgbaImage,
+    options: TextureUploadOptions,
+    name: &str,
+  ) -> Result<(AdAllocatedImage, UploadTicket), String> {
+    self.queue_rgba8_image(
+      allocator,
+      texture,
+      vk::ImageUsageFlags::SAMPLED,
+      vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+      options,
+      name,
+    )
+  }
+
+  // TRANSFER_DST is added to the usage flags passed in
+  fn queue_rgba8_image(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    image_rgba8: &image::RgbaImage,
+    usage: vk::ImageUsageFlags,
+    final_layout: vk::ImageLayout,
+    options: TextureUploadOptions,
+    name: &str,
+  ) -> Result<(AdAllocatedImage, UploadTicket), String> {
+    let format = match options.color_space {
+      ColorSpace::Srgb => vk::Format::R8G8B8A8_SRGB,
+      ColorSpace::Linear => vk::Format::R8G8B8A8_UNORM,
+    };
+    let mip_levels = if options.generate_mipmaps {
+      mip_level_count(image_rgba8.width(), image_rgba8.height())
+    } else {
+      1
+    };
+    let blit_mips = mip_levels > 1 && self.supports_linear_blit(format);
+    // without linear blits every mip is made on the cpu and uploaded
+    let cpu_mips = if mip_levels > 1 && !blit_mips {
+      downsample_mip_chain(image_rgba8, options.color_space)
+    } else {
+      vec![]
+    };
+    let blit_usage = if blit_mips {
+      vk::ImageUsageFlags::TRANSFER_SRC
+    } else {
+      vk::ImageUsageFlags::empty()
+    };
 
     let image = AdAllocatedImage::new(
       Arc::clone(&self.vk_context.device),
@@ -37,138 +376,163 @@ impl TransferManager {
       name,
       vk::ImageCreateInfo::default()
         .image_type(vk::ImageType::TYPE_2D)
-        .format(vk::Format::R8G8B8A8_UNORM)
-        .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC)
+        .format(format)
+        .usage(usage | blit_usage | vk::ImageUsageFlags::TRANSFER_DST)
         .initial_layout(vk::ImageLayout::UNDEFINED)
         .sharing_mode(vk::SharingMode::EXCLUSIVE)
         .samples(vk::SampleCountFlags::TYPE_1)
         .tiling(vk::ImageTiling::OPTIMAL)
-        .mip_levels(1)
+        .mip_levels(mip_levels)
         .array_layers(1)
         .extent(
           vk::Extent3D::default()
-            .width(image_info.width())
-            .height(image_info.height())
+            .width(image_rgba8.width())
+            .height(image_rgba8.height())
             .depth(1),
         ),
         MemoryLocation::GpuOnly
     )
       .map_err(|e| format!("at creating tex ad image: {e}"))?;
 
-    let mut stage_buffer = AdAllocatedBuffer::new(
-      Arc::clone(&self.vk_context.device),
-      Arc::clone(&allocator),
-      name,
-      vk::BufferCreateInfo::default()
-        .usage(vk::BufferUsageFlags::TRANSFER_SRC)
-        .size(image_rgba8.len() as vk::DeviceSize),
-      MemoryLocation::CpuToGpu
-    )
-      .map_err(|e| format!("at creating staging buffer: {e}"))?;
+    let data: Cow<[u8]> = if cpu_mips.is_empty() {
+      Cow::Borrowed(image_rgba8.as_raw())
+    } else {
+      let levels = std::iter::once(image_rgba8).chain(&cpu_mips);
+      Cow::Owned(levels.map(|level| level.as_raw().as_slice()).collect::<Vec<_>>().concat())
+    };
+    let level_sizes = std::iter::once(image_rgba8).chain(&cpu_mips).map(|level| level.as_raw().len());
+    let mip_chain = MipChain { image: image.inner, extent: image.resolution, mip_levels, final_layout };
+    let release_families = self.release_families(self.vk_context.transfer_q_idx);
+    let ticket = self.record_upload(allocator, name, &data, |cmd_buffer, stage_buffer, offset| {
+      let regions: Vec<_> = level_sizes
+        .scan(offset, |level_offset, size| {
+          let this_offset = *level_offset;
+          *level_offset += size as vk::DeviceSize;
+          Some(this_offset)
+        })
+        .zip(0..)
+        .map(|(level_offset, level)| buffer_image_copy(level_offset, level, 0, 1, mip_chain.extent))
+        .collect();
+      record_image_copy(cmd_buffer, stage_buffer, &regions, 1, mip_chain, release_families)
+    })?;
+    Ok((image, ticket))
+  }
+}
 
-    stage_buffer
-      .allocation
-      .as_mut()
-      .ok_or("stage buffer not allocated, hmmm".to_string())?
-      .mapped_slice_mut()
-      .ok_or("at mapping stage buffer memory to CPU".to_string())?
-      .copy_from_slice(image_rgba8.as_raw().as_slice());
-
-    let cmd_buffer = self
-      .cmd_pool
-      .allocate_command_buffers(vk::CommandBufferLevel::PRIMARY, 1)?
-      .swap_remove(0);
-
-    cmd_buffer.begin(vk::CommandBufferBeginInfo::default())?;
-    cmd_buffer.pipeline_barrier(
-      vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-      vk::PipelineStageFlags::TRANSFER,
-      vk::DependencyFlags::BY_REGION,
-      &[],
-      &[],
-      &[vk::ImageMemoryBarrier::default()
-        .image(image.inner)
-        .subresource_range(
-          vk::ImageSubresourceRange::default()
-            .aspect_mask(vk::ImageAspectFlags::COLOR)
-            .base_mip_level(0)
-            .level_count(1)
-            .base_array_layer(0)
-            .layer_count(1),
-        )
-        .src_access_mask(vk::AccessFlags::NONE)
-        .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-        .old_layout(vk::ImageLayout::UNDEFINED)
-        .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-        .src_queue_family_index(self.vk_context.transfer_q_idx)
-        .dst_queue_family_index(self.vk_context.transfer_q_idx)],
-    );
-    cmd_buffer.copy_buffer_to_image(
-      stage_buffer.inner,
-      image.inner,
-      vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-      &[vk::BufferImageCopy::default()
-        .image_subresource(
-          vk::ImageSubresourceLayers::default()
-            .aspect_mask(vk::ImageAspectFlags::COLOR)
-            .mip_level(0)
-            .base_array_layer(0)
-            .layer_count(1),
-        )
-        .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
-        .image_extent(
-          vk::Extent3D::default().width(image_info.width()).height(image_info.height()).depth(1),
-        )],
-    );
-    cmd_buffer.pipeline_barrier(
-      vk::PipelineStageFlags::TRANSFER,
-      vk::PipelineStageFlags::TRANSFER,
-      vk::DependencyFlags::BY_REGION,
-      &[],
-      &[],
-      &[vk::ImageMemoryBarrier::default()
-        .image(image.inner)
-        .subresource_range(
-          vk::ImageSubresourceRange::default()
-            .aspect_mask(vk::ImageAspectFlags::COLOR)
-            .base_mip_level(0)
-            .level_count(1)
-            .base_array_layer(0)
-            .layer_count(1),
-        )
-        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
-        .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
-        .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-        .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
-        .src_queue_family_index(self.vk_context.transfer_q_idx)
-        .dst_queue_family_index(self.vk_context.transfer_q_idx)],
-    );
-    cmd_buffer.end()?;
+// copy of a level of tightly packed layers from a stage buffer
+fn buffer_image_copy(
+  buffer_offset: vk::DeviceSize,
+  level: u32,
+  base_layer: u32,
+  layer_count: u32,
+  extent: vk::Extent3D,
+) -> vk::BufferImageCopy {
+  vk::BufferImageCopy::default()
+    .buffer_offset(buffer_offset)
+    .image_subresource(
+      vk::ImageSubresourceLayers::default()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(level)
+        .base_array_layer(base_layer)
+        .layer_count(layer_count),
+    )
+    .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+    .image_extent(mip_extent(extent, level))
+}
 
-    unsafe {
-      let upload_fence = self.vk_context.create_ad_fence()?;
+/*
+Copy the leading mips in regions from the stage buffer and blit the rest, then move the image
+to the final layout of mip_chain. The last barrier also releases the image to the graphics
+family if the families differ, returning the matching acquire. Blits then happen after the
+acquire, as the transfer family might not support them. Only single layer images get blits.
+ */
+fn record_image_copy(
+  cmd_buffer: &AdCommandBuffer,
+  stage_buffer: vk::Buffer,
+  regions: &[vk::BufferImageCopy],
+  array_layers: u32,
+  mip_chain: MipChain,
+  (src_family, dst_family): (u32, u32),
+) -> Option<AcquireBarrier> {
+  let all_levels = vk::ImageSubresourceRange::default()
+    .aspect_mask(vk::ImageAspectFlags::COLOR)
+    .base_mip_level(0)
+    .level_count(mip_chain.mip_levels)
+    .base_array_layer(0)
+    .layer_count(array_layers);
+  cmd_buffer.pipeline_barrier(
+    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+    vk::PipelineStageFlags::TRANSFER,
+    vk::DependencyFlags::BY_REGION,
+    &[],
+    &[],
+    &[vk::ImageMemoryBarrier::default()
+      .image(mip_chain.image)
+      .subresource_range(all_levels)
+      .src_access_mask(vk::AccessFlags::NONE)
+      .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+      .old_layout(vk::ImageLayout::UNDEFINED)
+      .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+      .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+      .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)],
+  );
+  cmd_buffer.copy_buffer_to_image(
+    stage_buffer,
+    mip_chain.image,
+    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+    regions,
+  );
 
-      self
-        .vk_context
-        .device
-        .queue_submit(
-          self.vk_context.transfer_q,
-          &[vk::SubmitInfo::default().command_buffers(&[cmd_buffer.inner])],
-          upload_fence.inner,
-        )
-        .map_err(|e| format!("at copying data to image: {e}"))?;
-
-      self
-        .vk_context
-        .device
-        .wait_for_fences(&[upload_fence.inner], true, u64::MAX)
-        .inspect_err(|e| println!("{e}"))
-        .map_err(|e| format!("at waiting for fence: {e}"))?;
-    }
-    Ok(image)
+  let copied_levels =
+    regions.iter().map(|region| region.image_subresource.mip_level + 1).max().unwrap_or(0);
+  let blit_mips = copied_levels < mip_chain.mip_levels;
+  if blit_mips && src_family == dst_family {
+    record_mip_blits(cmd_buffer, &mip_chain);
+    return None;
+  }
+  // mips still to be blitted stay in TRANSFER_DST_OPTIMAL for the graphics queue
+  let (release_layout, acquire_access) = if blit_mips {
+    (
+      vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+      vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE,
+    )
+  } else {
+    (mip_chain.final_layout, vk::AccessFlags::MEMORY_READ)
+  };
+  // the transfer queue might not support later stages, the fence wait makes the copy visible
+  let release = vk::ImageMemoryBarrier::default()
+    .image(mip_chain.image)
+    .subresource_range(all_levels)
+    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+    .dst_access_mask(vk::AccessFlags::NONE)
+    .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+    .new_layout(release_layout)
+    .src_queue_family_index(src_family)
+    .dst_queue_family_index(dst_family);
+  cmd_buffer.pipeline_barrier(
+    vk::PipelineStageFlags::TRANSFER,
+    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+    vk::DependencyFlags::BY_REGION,
+    &[],
+    &[],
+    &[release],
+  );
+  if src_family == dst_family {
+    return None;
+  }
+  let acquire = release.src_access_mask(vk::AccessFlags::NONE).dst_access_mask(acquire_access);
+  if blit_mips {
+    Some(AcquireBarrier::ImageWithMips(acquire, mip_chain))
+  } else {
+    Some(AcquireBarrier::Image(acquire))
   }
 }
 
 impl Drop for TransferManager {
-  fn drop(&mut self) {}
+  // staging memory and command buffers of batches still executing can't be freed yet
+  fn drop(&mut self) {
+    let _ = self
+      .wait_for_all_uploads()
+      .inspect_err(|e| eprintln!("at waiting for uploads while transfer manager drop: {e}"));
+  }
 }
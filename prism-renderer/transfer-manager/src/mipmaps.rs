@@ -0,0 +1,218 @@
+use crate::{ColorSpace, TransferManager};
+use vk_context::ash::vk;
+use vk_context::auto_drop_wrappers::AdCommandBuffer;
+
+// mips down to 1x1 for the largest side
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+  width.max(height).max(1).ilog2() + 1
+}
+
+pub(crate) fn mip_extent(extent: vk::Extent3D, level: u32) -> vk::Extent3D {
+  vk::Extent3D {
+    width: (extent.width >> level).max(1),
+    height: (extent.height >> level).max(1),
+    depth: 1,
+  }
+}
+
+fn extent_to_offset(extent: vk::Extent3D) -> vk::Offset3D {
+  vk::Offset3D { x: extent.width as i32, y: extent.height as i32, z: extent.depth as i32 }
+}
+
+// mips of image left to be blitted from mip 0, recorded once the image is on the graphics queue
+pub(crate) struct MipChain {
+  pub(crate) image: vk::Image,
+  pub(crate) extent: vk::Extent3D,
+  pub(crate) mip_levels: u32,
+  pub(crate) final_layout: vk::ImageLayout,
+}
+
+fn mip_barrier(
+  image: vk::Image,
+  level: u32,
+  old_layout: vk::ImageLayout,
+  new_layout: vk::ImageLayout,
+  src_access_mask: vk::AccessFlags,
+  dst_access_mask: vk::AccessFlags,
+) -> vk::ImageMemoryBarrier<'static> {
+  vk::ImageMemoryBarrier::default()
+    .image(image)
+    .subresource_range(
+      vk::ImageSubresourceRange::default()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(level)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1),
+    )
+    .src_access_mask(src_access_mask)
+    .dst_access_mask(dst_access_mask)
+    .old_layout(old_layout)
+    .new_layout(new_layout)
+    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+}
+
+/*
+Fill every mip after 0 by blitting down from the previous one, then move all mips to the final
+layout. Expects all mips in TRANSFER_DST_OPTIMAL and a queue supporting blits.
+ */
+pub(crate) fn record_mip_blits(cmd_buffer: &AdCommandBuffer, mip_chain: &MipChain) {
+  let image = mip_chain.image;
+  for level in 1..mip_chain.mip_levels {
+    cmd_buffer.pipeline_barrier(
+      vk::PipelineStageFlags::TRANSFER,
+      vk::PipelineStageFlags::TRANSFER,
+      vk::DependencyFlags::empty(),
+      &[],
+      &[],
+      &[mip_barrier(
+        image,
+        level - 1,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        vk::AccessFlags::TRANSFER_WRITE,
+        vk::AccessFlags::TRANSFER_READ,
+      )],
+    );
+    let subresource = |mip_level| {
+      vk::ImageSubresourceLayers::default()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(mip_level)
+        .base_array_layer(0)
+        .layer_count(1)
+    };
+    cmd_buffer.blit_image(
+      image,
+      vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+      image,
+      vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+      &[vk::ImageBlit::default()
+        .src_subresource(subresource(level - 1))
+        .src_offsets([
+          vk::Offset3D::default(),
+          extent_to_offset(mip_extent(mip_chain.extent, level - 1)),
+        ])
+        .dst_subresource(subresource(level))
+        .dst_offsets([
+          vk::Offset3D::default(),
+          extent_to_offset(mip_extent(mip_chain.extent, level)),
+        ])],
+      vk::Filter::LINEAR,
+    );
+  }
+
+  // the fence wait of the upload makes the mips visible
+  let final_barriers: Vec<_> = (0..mip_chain.mip_levels)
+    .map(|level| {
+      let (old_layout, src_access_mask) = if level + 1 == mip_chain.mip_levels {
+        (vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::AccessFlags::TRANSFER_WRITE)
+      } else {
+        (vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::AccessFlags::TRANSFER_READ)
+      };
+      mip_barrier(
+        image,
+        level,
+        old_layout,
+        mip_chain.final_layout,
+        src_access_mask,
+        vk::AccessFlags::NONE,
+      )
+    })
+    .collect();
+  cmd_buffer.pipeline_barrier(
+    vk::PipelineStageFlags::TRANSFER,
+    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+    vk::DependencyFlags::empty(),
+    &[],
+    &[],
+    &final_barriers,
+  );
+}
+
+fn downsample<P: image::Pixel + 'static>(
+  first: &image::ImageBuffer<P, Vec<P::Subpixel>>,
+) -> Vec<image::ImageBuffer<P, Vec<P::Subpixel>>> {
+  let mip_levels = mip_level_count(first.width(), first.height());
+  let mut mips: Vec<image::ImageBuffer<P, Vec<P::Subpixel>>> =
+    Vec::with_capacity(mip_levels as usize - 1);
+  for _ in 1..mip_levels {
+    let previous = mips.last().unwrap_or(first);
+    let mip = image::imageops::resize(
+      previous,
+      (previous.width() / 2).max(1),
+      (previous.height() / 2).max(1),
+      image::imageops::FilterType::Triangle,
+    );
+    mips.push(mip);
+  }
+  mips
+}
+
+fn srgb_to_linear(x: u8) -> f32 {
+  let x = x as f32 / 255.0;
+  if x <= 0.04045 {
+    x / 12.92
+  } else {
+    ((x + 0.055) / 1.055).powf(2.4)
+  }
+}
+
+fn linear_to_srgb(x: f32) -> u8 {
+  let x = if x <= 0.0031308 {
+    x * 12.92
+  } else {
+    1.055 * x.powf(1.0 / 2.4) - 0.055
+  };
+  (x.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/*
+The cpu fallback, each mip is a triangle filtered half of the previous one.
+Srgb images are filtered in linear space, else their mips come out too dark.
+ */
+pub(crate) fn downsample_mip_chain(
+  image_rgba8: &image::RgbaImage,
+  color_space: ColorSpace,
+) -> Vec<image::RgbaImage> {
+  if color_space == ColorSpace::Linear {
+    return downsample(image_rgba8);
+  }
+  // alpha is linear either way
+  let linear = image::Rgba32FImage::from_fn(image_rgba8.width(), image_rgba8.height(), |x, y| {
+    let [r, g, b, a] = image_rgba8.get_pixel(x, y).0;
+    image::Rgba([srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a as f32 / 255.0])
+  });
+  downsample(&linear)
+    .iter()
+    .map(|mip| {
+      image::RgbaImage::from_fn(mip.width(), mip.height(), |x, y| {
+        let [r, g, b, a] = mip.get_pixel(x, y).0;
+        image::Rgba([
+          linear_to_srgb(r),
+          linear_to_srgb(g),
+          linear_to_srgb(b),
+          (a.clamp(0.0, 1.0) * 255.0).round() as u8,
+        ])
+      })
+    })
+    .collect()
+}
+
+impl TransferManager {
+  // blit based mip generation needs linear filtered blits of the format on the graphics queue
+  pub(crate) fn supports_linear_blit(&self, format: vk::Format) -> bool {
+    let format_properties = unsafe {
+      self
+        .vk_context
+        .vk_loaders
+        .vk_driver
+        .get_physical_device_format_properties(self.vk_context.gpu, format)
+    };
+    format_properties.optimal_tiling_features.contains(
+      vk::FormatFeatureFlags::BLIT_SRC
+        | vk::FormatFeatureFlags::BLIT_DST
+        | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+    )
+  }
+}
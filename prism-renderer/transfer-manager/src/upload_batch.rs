@@ -0,0 +1,385 @@
+use crate::mipmaps::{record_mip_blits, MipChain};
+use crate::TransferManager;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use vk_context::ash::vk;
+use vk_context::auto_drop_wrappers::{AdAllocatedBuffer, AdCommandBuffer, AdFence, AdSemaphore};
+use vk_context::gpu_allocator::vulkan::Allocator;
+use vk_context::gpu_allocator::MemoryLocation;
+
+// staging memory is handed out from chunks of this size, bigger uploads get their own buffer
+pub const STAGING_CHUNK_SIZE: vk::DeviceSize = 16 * 1024 * 1024;
+// finished chunks kept around for later batches, the rest is freed
+const MAX_FREE_STAGING_CHUNKS: usize = 4;
+// covers the 4 and 16 byte texels of the uncompressed uploads and 8 and 16 byte compressed
+// blocks, formats with 3, 6 or 12 byte texels would need lcm(texel size, 4) instead
+const STAGING_ALIGNMENT: vk::DeviceSize = 16;
+
+/*
+Handle to a queued upload, shared by every upload of the same batch.
+Poll it with TransferManager::is_upload_done or block on it with wait_for_upload.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct UploadTicket {
+  batch_id: u64,
+}
+
+/*
+Acquire half of a queue family ownership transfer to the graphics family, recorded on the
+graphics queue after the release barrier with the same layouts and families ran.
+ImageWithMips also blits the mip chain once acquired.
+ */
+pub(crate) enum AcquireBarrier {
+  Image(vk::ImageMemoryBarrier<'static>),
+  ImageWithMips(vk::ImageMemoryBarrier<'static>, MipChain),
+  Buffer(vk::BufferMemoryBarrier<'static>),
+}
+
+struct StagingChunk {
+  buffer: AdAllocatedBuffer,
+  used: vk::DeviceSize,
+}
+
+struct RecordingBatch {
+  id: u64,
+  cmd_buffer: AdCommandBuffer,
+  staging: Vec<StagingChunk>,
+  acquire_barriers: Vec<AcquireBarrier>,
+}
+
+// graphics queue side of a batch, only there when the transfer family isn't the graphics family
+pub(crate) struct Acquire {
+  pub(crate) cmd_buffer: AdCommandBuffer,
+  semaphore: AdSemaphore,
+}
+
+struct SubmittedBatch {
+  id: u64,
+  fence: AdFence,
+  cmd_buffer: AdCommandBuffer,
+  staging: Vec<StagingChunk>,
+  acquire: Option<Acquire>,
+}
+
+/*
+Uploads get recorded into the open batch till it is submitted. Command buffers, fences and
+staging chunks of finished batches are recycled.
+ */
+#[derive(Default)]
+pub(crate) struct UploadBatches {
+  next_batch_id: u64,
+  recording: Option<RecordingBatch>,
+  in_flight: VecDeque<SubmittedBatch>,
+  free_cmd_buffers: Vec<AdCommandBuffer>,
+  free_fences: Vec<AdFence>,
+  free_staging: Vec<AdAllocatedBuffer>,
+  free_acquire_cmd_buffers: Vec<AdCommandBuffer>,
+  free_semaphores: Vec<AdSemaphore>,
+}
+
+impl TransferManager {
+  /*
+  Queue family indices for barriers releasing resources written on the src_q_idx family to
+  the graphics family. Both are ignored when no ownership transfer is needed.
+   */
+  pub(crate) fn release_families(&self, src_q_idx: u32) -> (u32, u32) {
+    if src_q_idx == self.vk_context.graphics_q_idx {
+      (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
+    } else {
+      (src_q_idx, self.vk_context.graphics_q_idx)
+    }
+  }
+
+  /*
+  Record the acquire barriers into a graphics command buffer and submit it, waiting on the
+  semaphore signaled by the submission holding the release barriers.
+  The command buffer has to be given back with recycle_acquire once the fence signaled.
+   */
+  pub(crate) fn submit_acquire(
+    &self,
+    batches: &mut UploadBatches,
+    barriers: &[AcquireBarrier],
+    wait_semaphore: AdSemaphore,
+    fence: vk::Fence,
+  ) -> Result<Acquire, String> {
+    let cmd_buffer = match batches.free_acquire_cmd_buffers.pop() {
+      Some(x) => x,
+      None => self
+        .acquire_cmd_pool
+        .allocate_command_buffers(vk::CommandBufferLevel::PRIMARY, 1)?
+        .swap_remove(0),
+    };
+    let image_barriers: Vec<_> = barriers
+      .iter()
+      .filter_map(|barrier| match barrier {
+        AcquireBarrier::Image(x) | AcquireBarrier::ImageWithMips(x, _) => Some(*x),
+        AcquireBarrier::Buffer(_) => None,
+      })
+      .collect();
+    let buffer_barriers: Vec<_> = barriers
+      .iter()
+      .filter_map(|barrier| match barrier {
+        AcquireBarrier::Buffer(x) => Some(*x),
+        AcquireBarrier::Image(_) | AcquireBarrier::ImageWithMips(..) => None,
+      })
+      .collect();
+    cmd_buffer.begin(
+      vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+    )?;
+    // the semaphore wait already orders this after the release
+    cmd_buffer.pipeline_barrier(
+      vk::PipelineStageFlags::TOP_OF_PIPE,
+      vk::PipelineStageFlags::ALL_COMMANDS,
+      vk::DependencyFlags::empty(),
+      &[],
+      &buffer_barriers,
+      &image_barriers,
+    );
+    for barrier in barriers {
+      if let AcquireBarrier::ImageWithMips(_, mip_chain) = barrier {
+        record_mip_blits(&cmd_buffer, mip_chain);
+      }
+    }
+    cmd_buffer.end()?;
+    self
+      .vk_context
+      .queue_submit(
+        self.vk_context.graphics_q,
+        &[vk::SubmitInfo::default()
+          .wait_semaphores(&[wait_semaphore.inner])
+          .wait_dst_stage_mask(&[vk::PipelineStageFlags::ALL_COMMANDS])
+          .command_buffers(&[cmd_buffer.inner])],
+        fence,
+      )
+      .map_err(|e| format!("at acquiring uploads on graphics queue: {e}"))?;
+    Ok(Acquire { cmd_buffer, semaphore: wait_semaphore })
+  }
+
+  pub(crate) fn take_semaphore(&self, batches: &mut UploadBatches) -> Result<AdSemaphore, String> {
+    match batches.free_semaphores.pop() {
+      Some(x) => Ok(x),
+      None => self.vk_context.create_ad_semaphore(),
+    }
+  }
+
+  pub(crate) fn recycle_acquire(&self, batches: &mut UploadBatches, acquire: Acquire) {
+    batches.free_acquire_cmd_buffers.push(acquire.cmd_buffer);
+    batches.free_semaphores.push(acquire.semaphore);
+  }
+
+  /*
+  Run f with the command buffer of the open batch, starting a new batch if there is none.
+  f gets the staging buffer and offset where data was copied to. It returns the acquire
+  barrier matching the release it recorded, if the resource changes queue family.
+   */
+  pub(crate) fn record_upload(
+    &self,
+    allocator: Arc<Mutex<Allocator>>,
+    name: &str,
+    data: &[u8],
+    f: impl FnOnce(&AdCommandBuffer, vk::Buffer, vk::DeviceSize) -> Option<AcquireBarrier>,
+  ) -> Result<UploadTicket, String> {
+    let mut batches =
+      self.upload_batches.lock().map_err(|e| format!("at getting upload batches lock: {e}"))?;
+    let batches = &mut *batches;
+    if batches.recording.is_none() {
+      let cmd_buffer = match batches.free_cmd_buffers.pop() {
+        Some(x) => x,
+        None => {
+          self.cmd_pool.allocate_command_buffers(vk::CommandBufferLevel::PRIMARY, 1)?.swap_remove(0)
+        }
+      };
+      cmd_buffer.begin(
+        vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+      )?;
+      batches.recording = Some(RecordingBatch {
+        id: batches.next_batch_id,
+        cmd_buffer,
+        staging: vec![],
+        acquire_barriers: vec![],
+      });
+    }
+    let batch = batches.recording.as_mut().ok_or("no upload batch recording".to_string())?;
+
+    let data_size = data.len() as vk::DeviceSize;
+    let chunk_idx = batch.staging.iter().position(|chunk| {
+      chunk.used.next_multiple_of(STAGING_ALIGNMENT) + data_size <= chunk.buffer.size
+    });
+    let chunk_idx = match chunk_idx {
+      Some(x) => x,
+      None => {
+        let buffer = match batches.free_staging.pop() {
+          Some(x) if data_size <= STAGING_CHUNK_SIZE => x,
+          free_chunk => {
+            batches.free_staging.extend(free_chunk);
+            AdAllocatedBuffer::new(
+              Arc::clone(&self.vk_context.device),
+              allocator,
+              &format!("{name}_staging"),
+              vk::BufferCreateInfo::default()
+                .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+                .size(data_size.max(STAGING_CHUNK_SIZE)),
+              MemoryLocation::CpuToGpu,
+            )
+            .map_err(|e| format!("at creating staging buffer: {e}"))?
+          }
+        };
+        batch.staging.push(StagingChunk { buffer, used: 0 });
+        batch.staging.len() - 1
+      }
+    };
+    let chunk = &mut batch.staging[chunk_idx];
+    let offset = chunk.used.next_multiple_of(STAGING_ALIGNMENT);
+    chunk
+      .buffer
+      .allocation
+      .as_mut()
+      .ok_or("stage buffer not allocated, hmmm".to_string())?
+      .mapped_slice_mut()
+      .ok_or("at mapping stage buffer memory to CPU".to_string())?
+      [offset as usize..(offset + data_size) as usize]
+      .copy_from_slice(data);
+    chunk.used = offset + data_size;
+
+    let acquire_barrier = f(&batch.cmd_buffer, chunk.buffer.inner, offset);
+    batch.acquire_barriers.extend(acquire_barrier);
+    Ok(UploadTicket { batch_id: batch.id })
+  }
+
+  /*
+  Submit the uploads queued so far as one batch to the transfer queue, without waiting.
+  When the transfer family isn't the graphics family the batch is then acquired on the
+  graphics queue. Does nothing if nothing was queued since the last submit.
+   */
+  pub fn submit_uploads(&self) -> Result<(), String> {
+    let mut batches =
+      self.upload_batches.lock().map_err(|e| format!("at getting upload batches lock: {e}"))?;
+    self.submit_recording_batch(&mut batches)
+  }
+
+  fn submit_recording_batch(&self, batches: &mut UploadBatches) -> Result<(), String> {
+    let Some(batch) = batches.recording.take() else {
+      return Ok(());
+    };
+    batch.cmd_buffer.end()?;
+    let fence = match batches.free_fences.pop() {
+      Some(x) => x,
+      None => self.vk_context.create_ad_fence()?,
+    };
+    let semaphore =
+      if batch.acquire_barriers.is_empty() { None } else { Some(self.take_semaphore(batches)?) };
+    // with an acquire the fence moves to the graphics submission that finishes the batch
+    let (signal_semaphores, transfer_fence) = match &semaphore {
+      Some(x) => (vec![x.inner], vk::Fence::null()),
+      None => (vec![], fence.inner),
+    };
+    self
+      .vk_context
+      .queue_submit(
+        self.vk_context.transfer_q,
+        &[vk::SubmitInfo::default()
+          .command_buffers(&[batch.cmd_buffer.inner])
+          .signal_semaphores(&signal_semaphores)],
+        transfer_fence,
+      )
+      .map_err(|e| format!("at copying data to gpu: {e}"))?;
+    let acquire = match semaphore {
+      Some(x) => Some(self.submit_acquire(batches, &batch.acquire_barriers, x, fence.inner)?),
+      None => None,
+    };
+    batches.next_batch_id += 1;
+    batches.in_flight.push_back(SubmittedBatch {
+      id: batch.id,
+      fence,
+      cmd_buffer: batch.cmd_buffer,
+      staging: batch.staging,
+      acquire,
+    });
+    Ok(())
+  }
+
+  // recycle everything used by batches whose fence has signaled
+  fn retire_finished_batches(&self, batches: &mut UploadBatches) -> Result<(), String> {
+    let device = &self.vk_context.device;
+    let submitted = std::mem::take(&mut batches.in_flight);
+    let mut still_in_flight = VecDeque::with_capacity(submitted.len());
+    for batch in submitted {
+      let finished = unsafe {
+        device
+          .get_fence_status(batch.fence.inner)
+          .map_err(|e| format!("at getting upload fence status: {e}"))?
+      };
+      if !finished {
+        still_in_flight.push_back(batch);
+        continue;
+      }
+      unsafe {
+        device
+          .reset_fences(&[batch.fence.inner])
+          .map_err(|e| format!("at resetting upload fence: {e}"))?;
+      }
+      batches.free_fences.push(batch.fence);
+      // the pool resets command buffers implicitly on begin
+      batches.free_cmd_buffers.push(batch.cmd_buffer);
+      if let Some(acquire) = batch.acquire {
+        self.recycle_acquire(batches, acquire);
+      }
+      for chunk in batch.staging {
+        let is_chunk = chunk.buffer.size == STAGING_CHUNK_SIZE;
+        if is_chunk && batches.free_staging.len() < MAX_FREE_STAGING_CHUNKS {
+          batches.free_staging.push(chunk.buffer);
+        }
+      }
+    }
+    batches.in_flight = still_in_flight;
+    Ok(())
+  }
+
+  // false while the batch of the ticket is still queued or executing
+  pub fn is_upload_done(&self, ticket: UploadTicket) -> Result<bool, String> {
+    let mut batches =
+      self.upload_batches.lock().map_err(|e| format!("at getting upload batches lock: {e}"))?;
+    self.retire_finished_batches(&mut batches)?;
+    Ok(
+      ticket.batch_id < batches.next_batch_id
+        && batches.in_flight.iter().all(|batch| batch.id != ticket.batch_id),
+    )
+  }
+
+  // blocks till the batch of the ticket is done, submitting it first if it is still open
+  pub fn wait_for_upload(&self, ticket: UploadTicket) -> Result<(), String> {
+    let mut batches =
+      self.upload_batches.lock().map_err(|e| format!("at getting upload batches lock: {e}"))?;
+    if batches.recording.as_ref().is_some_and(|batch| batch.id == ticket.batch_id) {
+      self.submit_recording_batch(&mut batches)?;
+    }
+    if let Some(batch) = batches.in_flight.iter().find(|batch| batch.id == ticket.batch_id) {
+      unsafe {
+        self
+          .vk_context
+          .device
+          .wait_for_fences(&[batch.fence.inner], true, u64::MAX)
+          .map_err(|e| format!("at waiting for fence: {e}"))?;
+      }
+    }
+    self.retire_finished_batches(&mut batches)
+  }
+
+  // submits the open batch and blocks till every upload is done
+  pub fn wait_for_all_uploads(&self) -> Result<(), String> {
+    let mut batches =
+      self.upload_batches.lock().map_err(|e| format!("at getting upload batches lock: {e}"))?;
+    self.submit_recording_batch(&mut batches)?;
+    let fences: Vec<_> = batches.in_flight.iter().map(|batch| batch.fence.inner).collect();
+    if !fences.is_empty() {
+      unsafe {
+        self
+          .vk_context
+          .device
+          .wait_for_fences(&fences, true, u64::MAX)
+          .map_err(|e| format!("at waiting for fences: {e}"))?;
+      }
+    }
+    self.retire_finished_batches(&mut batches)
+  }
+}
@@ -0,0 +1,158 @@
+#version 450
+
+layout(location = 0) in vec2 frag_uv;
+
+layout(location = 0) out vec4 out_hdr;
+
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput g_albedo;
+layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput g_normal;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput g_metallic_roughness;
+layout(input_attachment_index = 3, set = 0, binding = 3) uniform subpassInput g_depth;
+layout(input_attachment_index = 4, set = 0, binding = 4) uniform subpassInput g_emissive;
+
+const uint LIGHT_DIRECTIONAL = 0;
+const uint LIGHT_POINT = 1;
+const uint LIGHT_SPOT = 2;
+const uint MAX_LIGHTS = 64;
+const float PI = 3.14159265359;
+
+struct Light {
+    vec3 position;
+    // 0 means no range limit
+    float range;
+    vec3 direction;
+    uint kind;
+    vec3 color;
+    float intensity;
+    float spot_inner_cos;
+    float spot_outer_cos;
+    vec2 padding;
+};
+
+layout(set = 0, binding = 5) uniform LightData {
+    mat4 inv_view_proj;
+    vec4 camera_position;
+    // rgb constant ambient light, scaled by the material occlusion
+    vec4 ambient;
+    // x image based light intensity, y highest mip level of the prefiltered map
+    vec4 environment;
+    uvec4 light_count;
+    Light lights[MAX_LIGHTS];
+} light_data;
+
+// baked from the environment map, see TransferManager::create_environment_map
+layout(set = 0, binding = 6) uniform samplerCube irradiance_map;
+layout(set = 0, binding = 7) uniform samplerCube prefiltered_map;
+layout(set = 0, binding = 8) uniform sampler2D brdf_lut;
+
+vec3 world_position(float depth) {
+    vec4 world = light_data.inv_view_proj * vec4(frag_uv * 2.0 - 1.0, depth, 1.0);
+    return world.xyz / world.w;
+}
+
+float range_falloff(float dist, float range) {
+    if (range <= 0.0) {
+        return 1.0;
+    }
+    float ratio = dist / range;
+    float window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    return window * window;
+}
+
+// trowbridge-reitz ggx normal distribution
+float distribution_ggx(float n_dot_h, float alpha) {
+    float alpha_2 = alpha * alpha;
+    float denom = n_dot_h * n_dot_h * (alpha_2 - 1.0) + 1.0;
+    return alpha_2 / (PI * denom * denom);
+}
+
+float geometry_schlick_ggx(float n_dot_x, float k) {
+    return n_dot_x / (n_dot_x * (1.0 - k) + k);
+}
+
+// smith shadowing masking with the schlick ggx approximation, k remapped for direct lights
+float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+    float r = roughness + 1.0;
+    float k = r * r / 8.0;
+    return geometry_schlick_ggx(n_dot_v, k) * geometry_schlick_ggx(n_dot_l, k);
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+    return f0 + (vec3(1.0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+vec3 fresnel_schlick_roughness(float cos_theta, vec3 f0, float roughness) {
+    return f0 + (max(vec3(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// split sum image based lighting, explicit lods as this runs after the non uniform early return
+vec3 environment_light(vec3 normal, vec3 view_dir, float n_dot_v, vec3 albedo, float metallic, float roughness, vec3 f0) {
+    vec3 fresnel = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    vec3 diffuse = (vec3(1.0) - fresnel) * (1.0 - metallic) * albedo
+        * textureLod(irradiance_map, normal, 0.0).rgb;
+    vec3 reflect_dir = reflect(-view_dir, normal);
+    vec3 prefiltered = textureLod(prefiltered_map, reflect_dir, roughness * light_data.environment.y).rgb;
+    vec2 brdf = textureLod(brdf_lut, vec2(n_dot_v, roughness), 0.0).rg;
+    return diffuse + prefiltered * (fresnel * brdf.x + brdf.y);
+}
+
+void main() {
+    float depth = subpassLoad(g_depth).r;
+    // nothing was drawn here
+    if (depth >= 1.0) {
+        out_hdr = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+
+    vec3 albedo = subpassLoad(g_albedo).rgb;
+    vec3 normal = normalize(subpassLoad(g_normal).xyz);
+    vec4 metallic_roughness = subpassLoad(g_metallic_roughness);
+    float occlusion = metallic_roughness.r;
+    // fully smooth surfaces make the ggx peak degenerate
+    float roughness = clamp(metallic_roughness.g, 0.04, 1.0);
+    float metallic = metallic_roughness.b;
+    vec3 emissive = subpassLoad(g_emissive).rgb;
+
+    vec3 position = world_position(depth);
+    vec3 view_dir = normalize(light_data.camera_position.xyz - position);
+    float n_dot_v = max(dot(normal, view_dir), 0.0001);
+    float alpha = roughness * roughness;
+    // dielectrics reflect about 4% at normal incidence, metals tint the reflection with albedo
+    vec3 f0 = mix(vec3(0.04), albedo, metallic);
+
+    vec3 color = vec3(0.0);
+    for (uint i = 0; i < min(light_data.light_count.x, MAX_LIGHTS); i++) {
+        Light light = light_data.lights[i];
+        vec3 light_dir;
+        float attenuation = 1.0;
+        if (light.kind == LIGHT_DIRECTIONAL) {
+            light_dir = normalize(-light.direction);
+        } else {
+            vec3 to_light = light.position - position;
+            float dist = length(to_light);
+            light_dir = to_light / dist;
+            attenuation = range_falloff(dist, light.range) / max(dist * dist, 0.0001);
+            if (light.kind == LIGHT_SPOT) {
+                float cos_angle = dot(-light_dir, normalize(light.direction));
+                attenuation *= smoothstep(light.spot_outer_cos, light.spot_inner_cos, cos_angle);
+            }
+        }
+
+        float n_dot_l = max(dot(normal, light_dir), 0.0);
+        vec3 half_dir = normalize(light_dir + view_dir);
+        float n_dot_h = max(dot(normal, half_dir), 0.0);
+        float h_dot_v = max(dot(half_dir, view_dir), 0.0);
+
+        vec3 fresnel = fresnel_schlick(h_dot_v, f0);
+        vec3 specular = distribution_ggx(n_dot_h, alpha) * geometry_smith(n_dot_v, n_dot_l, roughness)
+            * fresnel / max(4.0 * n_dot_v * n_dot_l, 0.0001);
+        vec3 diffuse = (vec3(1.0) - fresnel) * (1.0 - metallic) * albedo / PI;
+        vec3 radiance = light.color * light.intensity * attenuation;
+        color += (diffuse + specular) * radiance * n_dot_l;
+    }
+    color += light_data.ambient.rgb * albedo * occlusion;
+    color += environment_light(normal, view_dir, n_dot_v, albedo, metallic, roughness, f0)
+        * occlusion * light_data.environment.x;
+    color += emissive;
+    out_hdr = vec4(color, 1.0);
+}
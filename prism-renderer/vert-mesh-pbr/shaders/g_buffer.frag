@@ -1,12 +1,56 @@
 #version 450
 
-layout(location = 0) in vec2 tex_coords;
+layout(location = 0) in vec3 frag_normal;
+layout(location = 1) in vec4 frag_tangent;
+layout(location = 2) in vec2 frag_uv;
 
-layout(location = 0) out vec4 out_color;
+layout(location = 0) out vec4 out_albedo;
+layout(location = 1) out vec4 out_normal;
+// r occlusion, g roughness, b metallic, same channels as gltf metallic roughness textures
+layout(location = 2) out vec4 out_metallic_roughness;
+layout(location = 3) out vec4 out_emissive;
 
-layout(set = 1, binding = 0) uniform sampler2D albedo;
+// factors multiply the matching texture, missing textures are bound to white (or a flat normal)
+layout(set = 1, binding = 0) uniform MaterialFactors {
+    vec4 base_color;
+    vec3 emissive;
+    float emissive_strength;
+    float metallic;
+    float roughness;
+    float normal_scale;
+    float occlusion_strength;
+} material;
+
+layout(set = 1, binding = 1) uniform sampler2D albedo_tex;
+layout(set = 1, binding = 2) uniform sampler2D normal_tex;
+layout(set = 1, binding = 3) uniform sampler2D metallic_roughness_tex;
+layout(set = 1, binding = 4) uniform sampler2D occlusion_tex;
+layout(set = 1, binding = 5) uniform sampler2D emissive_tex;
 
 void main() {
-    vec3 albedo_color = texture(albedo, tex_coords).xyz;
-    out_color = vec4(albedo_color, 1.0f);
-}
\ No newline at end of file
+    vec4 albedo = texture(albedo_tex, frag_uv);
+    vec3 tangent_normal = texture(normal_tex, frag_uv).xyz * 2.0 - 1.0;
+    vec4 metallic_roughness = texture(metallic_roughness_tex, frag_uv);
+    float occlusion = texture(occlusion_tex, frag_uv).r;
+    vec3 emissive = texture(emissive_tex, frag_uv).rgb;
+
+    vec3 normal = normalize(frag_normal);
+    vec3 tangent = frag_tangent.xyz - normal * dot(normal, frag_tangent.xyz);
+    // meshes without tangents can't be normal mapped
+    if (dot(tangent, tangent) > 0.000001) {
+        tangent = normalize(tangent);
+        vec3 bitangent = cross(normal, tangent) * (frag_tangent.w < 0.0 ? -1.0 : 1.0);
+        tangent_normal = vec3(tangent_normal.xy * material.normal_scale, tangent_normal.z);
+        normal = normalize(mat3(tangent, bitangent, normal) * tangent_normal);
+    }
+
+    out_albedo = vec4(albedo.rgb * material.base_color.rgb, 1.0);
+    out_normal = vec4(normal, 0.0);
+    out_metallic_roughness = vec4(
+        mix(1.0, occlusion, material.occlusion_strength),
+        metallic_roughness.g * material.roughness,
+        metallic_roughness.b * material.metallic,
+        0.0
+    );
+    out_emissive = vec4(emissive * material.emissive * material.emissive_strength, 0.0);
+}
@@ -5,15 +5,25 @@ layout(location = 1) in vec4 in_normal;
 layout(location = 2) in vec4 in_tangent;
 layout(location = 3) in vec4 in_uv_coordinates;
 
-layout(location = 0) out vec4 frag_color;
+layout(location = 0) out vec3 frag_normal;
+// w is the bitangent sign
+layout(location = 1) out vec4 frag_tangent;
+layout(location = 2) out vec2 frag_uv;
 
 layout(set = 0, binding = 0) uniform CameraTransform{
     mat4 view;
     mat4 proj;
 } cam_transform;
 
+layout(set = 0, binding = 1) uniform ObjectTransform{
+    mat4 model;
+} object_transform;
+
 void main() {
-    vec4 lol = cam_transform.view * in_position;
-    gl_Position = vec4(lol.x, lol.y, 1.0, 1.0);
-    frag_color = vec4(1.0, 1.0, 1.0, 1.0);
-}
\ No newline at end of file
+    gl_Position = cam_transform.proj * cam_transform.view * object_transform.model * in_position;
+    // assumes uniform scale, otherwise normals would need the inverse transpose
+    mat3 model_rotation = mat3(object_transform.model);
+    frag_normal = model_rotation * in_normal.xyz;
+    frag_tangent = vec4(model_rotation * in_tangent.xyz, in_tangent.w);
+    frag_uv = in_uv_coordinates.xy;
+}
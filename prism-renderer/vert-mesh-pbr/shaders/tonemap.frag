@@ -0,0 +1,13 @@
+#version 450
+
+layout(location = 0) in vec2 frag_uv;
+
+layout(location = 0) out vec4 out_color;
+
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput hdr;
+
+void main() {
+    // reinhard
+    vec3 color = subpassLoad(hdr).rgb;
+    out_color = vec4(color / (color + vec3(1.0)), 1.0);
+}
@@ -1,60 +1,123 @@
-use std::sync::Arc;
+use mesh_structs::Vertex;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
 use vk_context::ash;
 use vk_context::ash::vk;
+use vk_context::auto_drop_wrappers::AdDescriptorSetLayout;
+use vk_context::descriptors::DescriptorAllocator;
 
+pub mod lighting;
 pub mod structs;
 
+static G_BUFFER_VERT_SPV: &[u8] = include_bytes!("../shaders/g_buffer.vert.spv");
+static G_BUFFER_FRAG_SPV: &[u8] = include_bytes!("../shaders/g_buffer.frag.spv");
+
+// descriptor set indices in the pipeline layout
+pub const CAMERA_SET: u32 = 0;
+pub const MATERIAL_SET: u32 = 1;
+
 pub struct VertMeshPbrPipeline {
-  set_layouts: Vec<vk::DescriptorSetLayout>,
-  pipeline_layout: vk::PipelineLayout,
-  pipeline: vk::Pipeline,
+  pub(crate) device: Arc<ash::Device>,
+  pub set_layouts: Vec<AdDescriptorSetLayout>,
+  // materials of this pipeline allocate their sets from here
+  pub descriptor_allocator: Arc<Mutex<DescriptorAllocator>>,
+  pub pipeline_layout: vk::PipelineLayout,
+  pub pipeline: vk::Pipeline,
+}
+
+impl Drop for VertMeshPbrPipeline {
+  fn drop(&mut self) {
+    unsafe {
+      self.device.destroy_pipeline(self.pipeline, None);
+      self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+    }
+  }
+}
+
+pub(crate) fn create_shader_module(device: &ash::Device, spv_bytes: &[u8]) -> Result<vk::ShaderModule, String> {
+  let spv_code = ash::util::read_spv(&mut Cursor::new(spv_bytes))
+    .map_err(|e| format!("at reading spv code: {e}"))?;
+  unsafe {
+    device
+      .create_shader_module(&vk::ShaderModuleCreateInfo::default().code(&spv_code), None)
+      .map_err(|e| format!("at shader module create: {e}"))
+  }
 }
 
 pub fn make_vert_mesh_pbr_pipeline(
   device: Arc<ash::Device>,
+  descriptor_allocator: Arc<Mutex<DescriptorAllocator>>,
   render_pass: vk::RenderPass,
   subpass_idx: u32,
 ) -> Result<VertMeshPbrPipeline, String> {
-  let descriptor_set_layout_0 = unsafe {
-    device
-      .create_descriptor_set_layout(
-        &vk::DescriptorSetLayoutCreateInfo::default()
-          .bindings(&[
-            vk::DescriptorSetLayoutBinding::default()
-              .stage_flags(vk::ShaderStageFlags::VERTEX)
-              .binding(0)
-              .descriptor_count(1)
-              .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER),
-          ]),
-        None
-      )
-      .map_err(|e| format!("at descriptor set layout 0 create: {e}"))?
+  let per_frame_binding = |binding: u32| {
+    vk::DescriptorSetLayoutBinding::default()
+      .stage_flags(vk::ShaderStageFlags::VERTEX)
+      .binding(binding)
+      .descriptor_count(1)
+      .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
   };
-  let descriptor_set_layout_1 = unsafe {
-    device
-      .create_descriptor_set_layout(
-        &vk::DescriptorSetLayoutCreateInfo::default()
-          .bindings(&[
-            vk::DescriptorSetLayoutBinding::default()
-              .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-              .binding(0)
-              .descriptor_count(1)
-              .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
-          ]),
-        None
-      )
-      .map_err(|e| format!("at descriptor set layout 1 create: {e}"))?
+  // camera transforms, then the model matrix of the drawn object, both at dynamic offsets
+  let descriptor_set_layout_0 = AdDescriptorSetLayout::new(
+    Arc::clone(&device),
+    &[per_frame_binding(0), per_frame_binding(1)],
+  )
+  .map_err(|e| format!("at camera set: {e}"))?;
+  let material_texture_binding = |binding: u32| {
+    vk::DescriptorSetLayoutBinding::default()
+      .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+      .binding(binding)
+      .descriptor_count(1)
+      .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
   };
+  // material factors, then albedo, normal, metallic roughness, occlusion and emissive textures
+  let descriptor_set_layout_1 = AdDescriptorSetLayout::new(
+    Arc::clone(&device),
+    &[
+      vk::DescriptorSetLayoutBinding::default()
+        .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+        .binding(0)
+        .descriptor_count(1)
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER),
+      material_texture_binding(1),
+      material_texture_binding(2),
+      material_texture_binding(3),
+      material_texture_binding(4),
+      material_texture_binding(5),
+    ],
+  )
+  .map_err(|e| format!("at material set: {e}"))?;
   let pipeline_layout = unsafe {
     device
       .create_pipeline_layout(
         &vk::PipelineLayoutCreateInfo::default()
-        .set_layouts(&[descriptor_set_layout_0, descriptor_set_layout_1]),
+        .set_layouts(&[descriptor_set_layout_0.inner, descriptor_set_layout_1.inner]),
         None
       )
       .map_err(|e| format!("at pipeline layout create: {e}"))?
   };
-  let pipeline = unsafe {
+  // owns everything created so far, so early returns below clean up on drop
+  let mut vert_mesh_pbr_pipeline = VertMeshPbrPipeline {
+    device: Arc::clone(&device),
+    set_layouts: vec![descriptor_set_layout_0, descriptor_set_layout_1],
+    descriptor_allocator,
+    pipeline_layout,
+    pipeline: vk::Pipeline::null(),
+  };
+  let vert_shader = create_shader_module(&device, G_BUFFER_VERT_SPV)?;
+  let frag_shader = match create_shader_module(&device, G_BUFFER_FRAG_SPV) {
+    Ok(x) => x,
+    Err(e) => {
+      unsafe { device.destroy_shader_module(vert_shader, None) };
+      return Err(e);
+    }
+  };
+  let binding_descriptions = Vertex::get_binding_descriptions();
+  let g_buffer_blend_attachment = vk::PipelineColorBlendAttachmentState::default()
+    .blend_enable(false)
+    .color_write_mask(vk::ColorComponentFlags::RGBA);
+  let attribute_descriptions = Vertex::get_attribute_descriptions();
+  let pipeline_result = unsafe {
     device
       .create_graphics_pipelines(
         vk::PipelineCache::null(),
@@ -66,34 +129,65 @@ pub fn make_vert_mesh_pbr_pipeline(
             .stages(&[
               vk::PipelineShaderStageCreateInfo::default()
                 .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert_shader)
                 .name(c"main"),
               vk::PipelineShaderStageCreateInfo::default()
                 .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag_shader)
                 .name(c"main"),
             ])
-            .vertex_input_state(&vk::PipelineVertexInputStateCreateInfo::default())
+            .vertex_input_state(
+              &vk::PipelineVertexInputStateCreateInfo::default()
+                .vertex_binding_descriptions(&binding_descriptions)
+                .vertex_attribute_descriptions(&attribute_descriptions)
+            )
             .input_assembly_state(
               &vk::PipelineInputAssemblyStateCreateInfo::default()
                 .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
             )
+            .viewport_state(
+              &vk::PipelineViewportStateCreateInfo::default()
+                .viewport_count(1)
+                .scissor_count(1)
+            )
+            .dynamic_state(
+              &vk::PipelineDynamicStateCreateInfo::default()
+                .dynamic_states(&[vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR])
+            )
             .rasterization_state(
               &vk::PipelineRasterizationStateCreateInfo::default()
                 .polygon_mode(vk::PolygonMode::FILL)
                 .cull_mode(vk::CullModeFlags::BACK)
                 .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+                .line_width(1.0)
+            )
+            .multisample_state(
+              &vk::PipelineMultisampleStateCreateInfo::default()
+                .rasterization_samples(vk::SampleCountFlags::TYPE_1)
+            )
+            .depth_stencil_state(
+              &vk::PipelineDepthStencilStateCreateInfo::default()
+                .depth_test_enable(true)
+                .depth_write_enable(true)
+                .depth_compare_op(vk::CompareOp::LESS)
+                .depth_bounds_test_enable(false)
+                .stencil_test_enable(false)
             )
             .color_blend_state(
               &vk::PipelineColorBlendStateCreateInfo::default()
                 .logic_op_enable(false)
+                // albedo, normal, metallic roughness and emissive g-buffer targets
+                .attachments(&[g_buffer_blend_attachment; 4])
             )
         ],
         None
       )
-      .map_err(|e| format!("at creating pipeline: {}", e.1))?[0]
   };
-  Ok(VertMeshPbrPipeline {
-    set_layouts: vec![descriptor_set_layout_0, descriptor_set_layout_1],
-    pipeline_layout,
-    pipeline,
-  })
+  unsafe {
+    device.destroy_shader_module(vert_shader, None);
+    device.destroy_shader_module(frag_shader, None);
+  }
+  vert_mesh_pbr_pipeline.pipeline =
+    pipeline_result.map_err(|e| format!("at creating pipeline: {}", e.1))?[0];
+  Ok(vert_mesh_pbr_pipeline)
 }
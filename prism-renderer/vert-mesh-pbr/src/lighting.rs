@@ -0,0 +1,454 @@
+use crate::create_shader_module;
+use mesh_structs::glam;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+use transfer_manager::EnvironmentMap;
+use vk_context::ash;
+use vk_context::ash::vk;
+use vk_context::auto_drop_wrappers::{
+  AdAllocatedBuffer, AdCommandBuffer, AdDescriptorSet, AdDescriptorSetLayout,
+};
+use vk_context::descriptors::DescriptorAllocator;
+use vk_context::gpu_allocator::vulkan::Allocator;
+use vk_context::gpu_allocator::MemoryLocation;
+
+static FULLSCREEN_VERT_SPV: &[u8] = include_bytes!("../shaders/fullscreen.vert.spv");
+static DEFERRED_LIGHTING_FRAG_SPV: &[u8] = include_bytes!("../shaders/deferred_lighting.frag.spv");
+static TONEMAP_FRAG_SPV: &[u8] = include_bytes!("../shaders/tonemap.frag.spv");
+
+// has to match MAX_LIGHTS in deferred_lighting.frag
+pub const MAX_LIGHTS: usize = 64;
+
+#[repr(u32)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum LightKind {
+  #[default]
+  Directional = 0,
+  Point = 1,
+  Spot = 2,
+}
+
+/*
+Std140 layout of the Light struct in deferred_lighting.frag.
+Point and spot lights fall off with inverse square distance, windowed to 0 at range.
+ */
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Light {
+  pub position: glam::Vec3,
+  // 0 for no range limit
+  pub range: f32,
+  pub direction: glam::Vec3,
+  pub kind: LightKind,
+  pub color: glam::Vec3,
+  pub intensity: f32,
+  pub spot_inner_cos: f32,
+  pub spot_outer_cos: f32,
+  _padding: [f32; 2],
+}
+
+impl Light {
+  pub fn directional(direction: glam::Vec3, color: glam::Vec3, intensity: f32) -> Self {
+    Self { direction, kind: LightKind::Directional, color, intensity, ..Default::default() }
+  }
+
+  pub fn point(position: glam::Vec3, range: f32, color: glam::Vec3, intensity: f32) -> Self {
+    Self { position, range, kind: LightKind::Point, color, intensity, ..Default::default() }
+  }
+
+  /*
+  Cone angles are in radians from the direction, full intensity inside the inner angle
+  smoothly going to 0 at the outer angle.
+   */
+  pub fn spot(
+    position: glam::Vec3,
+    direction: glam::Vec3,
+    range: f32,
+    inner_angle: f32,
+    outer_angle: f32,
+    color: glam::Vec3,
+    intensity: f32,
+  ) -> Self {
+    Self {
+      position,
+      range,
+      direction,
+      kind: LightKind::Spot,
+      color,
+      intensity,
+      spot_inner_cos: inner_angle.cos(),
+      spot_outer_cos: outer_angle.cos(),
+      _padding: [0.0; 2],
+    }
+  }
+}
+
+// std140 layout of the Lights uniform block in deferred_lighting.frag
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LightsUniform {
+  inv_view_proj: glam::Mat4,
+  camera_position: glam::Vec4,
+  ambient: glam::Vec4,
+  // x image based light intensity, y highest mip level of the prefiltered map
+  environment: glam::Vec4,
+  light_count: [u32; 4],
+  lights: [Light; MAX_LIGHTS],
+}
+
+/*
+Pipeline drawing a single screen covering triangle with its own descriptor set at set 0.
+Used for the subpasses that only read the previous subpass outputs as input attachments.
+Viewport and scissor are dynamic and expected to be set for the whole render pass.
+ */
+pub struct FullscreenPass {
+  device: Arc<ash::Device>,
+  pub descriptor_set: AdDescriptorSet,
+  pub set_layout: AdDescriptorSetLayout,
+  pub pipeline_layout: vk::PipelineLayout,
+  pub pipeline: vk::Pipeline,
+}
+
+impl FullscreenPass {
+  fn new(
+    device: Arc<ash::Device>,
+    descriptor_allocator: Arc<Mutex<DescriptorAllocator>>,
+    render_pass: vk::RenderPass,
+    subpass_idx: u32,
+    bindings: &[vk::DescriptorSetLayoutBinding],
+    frag_spv: &[u8],
+  ) -> Result<Self, String> {
+    let set_layout = AdDescriptorSetLayout::new(Arc::clone(&device), bindings)?;
+    let descriptor_set = AdDescriptorSet::new(descriptor_allocator, &set_layout)?;
+    let pipeline_layout = unsafe {
+      device
+        .create_pipeline_layout(
+          &vk::PipelineLayoutCreateInfo::default().set_layouts(&[set_layout.inner]),
+          None,
+        )
+        .map_err(|e| format!("at pipeline layout create: {e}"))?
+    };
+    // the pass owns everything created so far, so early returns below clean up on drop
+    let mut pass =
+      Self { device, descriptor_set, set_layout, pipeline_layout, pipeline: vk::Pipeline::null() };
+
+    let vert_shader = create_shader_module(&pass.device, FULLSCREEN_VERT_SPV)?;
+    let frag_shader = match create_shader_module(&pass.device, frag_spv) {
+      Ok(x) => x,
+      Err(e) => {
+        unsafe { pass.device.destroy_shader_module(vert_shader, None) };
+        return Err(e);
+      }
+    };
+    let pipeline_result = unsafe {
+      pass.device.create_graphics_pipelines(
+        vk::PipelineCache::null(),
+        &[vk::GraphicsPipelineCreateInfo::default()
+          .render_pass(render_pass)
+          .subpass(subpass_idx)
+          .layout(pipeline_layout)
+          .stages(&[
+            vk::PipelineShaderStageCreateInfo::default()
+              .stage(vk::ShaderStageFlags::VERTEX)
+              .module(vert_shader)
+              .name(c"main"),
+            vk::PipelineShaderStageCreateInfo::default()
+              .stage(vk::ShaderStageFlags::FRAGMENT)
+              .module(frag_shader)
+              .name(c"main"),
+          ])
+          .vertex_input_state(&vk::PipelineVertexInputStateCreateInfo::default())
+          .input_assembly_state(
+            &vk::PipelineInputAssemblyStateCreateInfo::default()
+              .topology(vk::PrimitiveTopology::TRIANGLE_LIST),
+          )
+          .viewport_state(
+            &vk::PipelineViewportStateCreateInfo::default().viewport_count(1).scissor_count(1),
+          )
+          .dynamic_state(
+            &vk::PipelineDynamicStateCreateInfo::default()
+              .dynamic_states(&[vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR]),
+          )
+          .rasterization_state(
+            &vk::PipelineRasterizationStateCreateInfo::default()
+              .polygon_mode(vk::PolygonMode::FILL)
+              .cull_mode(vk::CullModeFlags::NONE)
+              .line_width(1.0),
+          )
+          .multisample_state(
+            &vk::PipelineMultisampleStateCreateInfo::default()
+              .rasterization_samples(vk::SampleCountFlags::TYPE_1),
+          )
+          .color_blend_state(
+            &vk::PipelineColorBlendStateCreateInfo::default().attachments(&[
+              vk::PipelineColorBlendAttachmentState::default()
+                .blend_enable(false)
+                .color_write_mask(vk::ColorComponentFlags::RGBA),
+            ]),
+          )],
+        None,
+      )
+    };
+    unsafe {
+      pass.device.destroy_shader_module(vert_shader, None);
+      pass.device.destroy_shader_module(frag_shader, None);
+    }
+    pass.pipeline = pipeline_result.map_err(|e| format!("at creating pipeline: {}", e.1))?[0];
+    Ok(pass)
+  }
+
+  /*
+  Point the input attachment bindings starting at first_binding to the given views.
+  Has to be redone whenever the render targets get recreated.
+   */
+  pub fn write_input_attachments(
+    &self,
+    first_binding: u32,
+    views: &[(vk::ImageView, vk::ImageLayout)],
+  ) {
+    let mut writes = self.descriptor_set.write_builder();
+    for ((view, layout), binding) in views.iter().zip(first_binding..) {
+      writes = writes.add_input_attachment(binding, *view, *layout);
+    }
+    writes.write();
+  }
+
+  // dynamic_offsets go to the set's dynamic buffers in binding order
+  pub fn draw(&self, cmd_buffer: &AdCommandBuffer, dynamic_offsets: &[u32]) {
+    cmd_buffer.bind_pipeline(vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+    cmd_buffer.bind_descriptor_sets(
+      vk::PipelineBindPoint::GRAPHICS,
+      self.pipeline_layout,
+      0,
+      &[self.descriptor_set.inner],
+      dynamic_offsets,
+    );
+    cmd_buffer.draw(3, 1, 0, 0);
+  }
+}
+
+impl Drop for FullscreenPass {
+  fn drop(&mut self) {
+    unsafe {
+      self.device.destroy_pipeline(self.pipeline, None);
+      self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+    }
+  }
+}
+
+fn input_attachment_binding(binding: u32) -> vk::DescriptorSetLayoutBinding<'static> {
+  vk::DescriptorSetLayoutBinding::default()
+    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+    .binding(binding)
+    .descriptor_count(1)
+    .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+}
+
+fn combined_image_sampler_binding(binding: u32) -> vk::DescriptorSetLayoutBinding<'static> {
+  vk::DescriptorSetLayoutBinding::default()
+    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+    .binding(binding)
+    .descriptor_count(1)
+    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+}
+
+/*
+Resolves the g-buffer into hdr color with the cook-torrance brdf. Reads albedo, normal,
+metallic roughness, depth and emissive as input attachments 0 to 4, the lights from
+a dynamic uniform buffer at binding 5 and the irradiance, prefiltered and brdf lut maps of
+an EnvironmentMap at bindings 6 to 8.
+The lights are kept on the CPU and copied to the frame's part of the buffer each draw.
+ */
+pub struct DeferredLightingPass {
+  pub pass: FullscreenPass,
+  uniform: Box<LightsUniform>,
+  // a LightsUniform per frame in flight, frame_stride apart
+  lights_buffer: AdAllocatedBuffer,
+  frame_stride: vk::DeviceSize,
+}
+
+impl DeferredLightingPass {
+  pub fn new(
+    device: Arc<ash::Device>,
+    allocator: Arc<Mutex<Allocator>>,
+    descriptor_allocator: Arc<Mutex<DescriptorAllocator>>,
+    render_pass: vk::RenderPass,
+    subpass_idx: u32,
+    frames_in_flight: usize,
+    min_uniform_buffer_offset_alignment: vk::DeviceSize,
+  ) -> Result<Self, String> {
+    let pass = FullscreenPass::new(
+      Arc::clone(&device),
+      descriptor_allocator,
+      render_pass,
+      subpass_idx,
+      &[
+        input_attachment_binding(0),
+        input_attachment_binding(1),
+        input_attachment_binding(2),
+        input_attachment_binding(3),
+        input_attachment_binding(4),
+        vk::DescriptorSetLayoutBinding::default()
+          .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+          .binding(5)
+          .descriptor_count(1)
+          .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC),
+        combined_image_sampler_binding(6),
+        combined_image_sampler_binding(7),
+        combined_image_sampler_binding(8),
+      ],
+      DEFERRED_LIGHTING_FRAG_SPV,
+    )
+    .map_err(|e| format!("at lighting pass: {e}"))?;
+
+    let frame_stride = (size_of::<LightsUniform>() as vk::DeviceSize)
+      .next_multiple_of(min_uniform_buffer_offset_alignment.max(1));
+    let lights_buffer = AdAllocatedBuffer::new(
+      device,
+      allocator,
+      "lights_buffer",
+      vk::BufferCreateInfo::default()
+        .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+        .size(frames_in_flight as vk::DeviceSize * frame_stride),
+      MemoryLocation::CpuToGpu,
+    )?;
+    pass
+      .descriptor_set
+      .write_builder()
+      .add_uniform_buffer_dynamic(
+        5,
+        lights_buffer.inner,
+        0,
+        size_of::<LightsUniform>() as vk::DeviceSize,
+      )
+      .write();
+
+    Ok(Self {
+      pass,
+      uniform: Box::new(LightsUniform {
+        inv_view_proj: glam::Mat4::IDENTITY,
+        camera_position: glam::Vec4::W,
+        ambient: glam::Vec4::ZERO,
+        environment: glam::Vec4::ZERO,
+        light_count: [0; 4],
+        lights: [Light::default(); MAX_LIGHTS],
+      }),
+      lights_buffer,
+      frame_stride,
+    })
+  }
+
+  pub fn write_input_attachments(
+    &self,
+    albedo: vk::ImageView,
+    normal: vk::ImageView,
+    metallic_roughness: vk::ImageView,
+    depth: vk::ImageView,
+    emissive: vk::ImageView,
+  ) {
+    self.pass.write_input_attachments(
+      0,
+      &[
+        (albedo, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        (normal, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        (metallic_roughness, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        (depth, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+        (emissive, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+      ],
+    );
+  }
+
+  // replace the lights used from the next write_frame_uniform, more than MAX_LIGHTS is an error
+  pub fn set_lights(&mut self, lights: &[Light]) -> Result<(), String> {
+    if lights.len() > MAX_LIGHTS {
+      return Err(format!("{} lights given, at most {MAX_LIGHTS} are supported", lights.len()));
+    }
+    self.uniform.lights[..lights.len()].copy_from_slice(lights);
+    self.uniform.light_count[0] = lights.len() as u32;
+    Ok(())
+  }
+
+  // the camera is needed to rebuild world positions from depth and for specular
+  pub fn set_camera(&mut self, view_proj: glam::Mat4, camera_position: glam::Vec3) {
+    self.uniform.inv_view_proj = view_proj.inverse();
+    self.uniform.camera_position = camera_position.extend(1.0);
+  }
+
+  // constant light added to every surface, scaled by the material ambient occlusion
+  pub fn set_ambient(&mut self, color: glam::Vec3) {
+    self.uniform.ambient = color.extend(0.0);
+  }
+
+  /*
+  Sample the environment map for ambient light, scaled by intensity and the material
+  ambient occlusion. Has to be set before the first draw, and the map has to outlive
+  its use by any frame in flight.
+   */
+  pub fn set_environment_map(&mut self, environment_map: &EnvironmentMap, intensity: f32) {
+    let views = [
+      environment_map.irradiance_view,
+      environment_map.prefiltered_view,
+      environment_map.brdf_lut_view,
+    ];
+    let mut writes = self.pass.descriptor_set.write_builder();
+    for (view, binding) in views.into_iter().zip(6..) {
+      writes = writes.add_combined_image_sampler(
+        binding,
+        environment_map.sampler.inner,
+        view,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+      );
+    }
+    writes.write();
+    self.uniform.environment =
+      glam::vec4(intensity, (environment_map.prefiltered_mip_levels - 1) as f32, 0.0, 0.0);
+  }
+
+  /*
+  Copy the lights into frame's part of the buffer and return the dynamic offset draw takes.
+  The GPU has to be done with the frame's last draw before calling.
+   */
+  pub fn write_frame_uniform(&mut self, frame: usize) -> Result<u32, String> {
+    let offset = frame as vk::DeviceSize * self.frame_stride;
+    let uniform_bytes = unsafe {
+      std::slice::from_raw_parts(
+        self.uniform.as_ref() as *const LightsUniform as *const u8,
+        size_of::<LightsUniform>(),
+      )
+    };
+    self
+      .lights_buffer
+      .allocation
+      .as_mut()
+      .ok_or("lights buffer not allocated".to_string())?
+      .mapped_slice_mut()
+      .ok_or("at mapping lights buffer memory to CPU".to_string())?
+      .get_mut(offset as usize..offset as usize + uniform_bytes.len())
+      .ok_or(format!("no lights buffer space for frame {frame}"))?
+      .copy_from_slice(uniform_bytes);
+    Ok(offset as u32)
+  }
+
+  // lights_offset is the one write_frame_uniform returned for this frame
+  pub fn draw(&self, cmd_buffer: &AdCommandBuffer, lights_offset: u32) {
+    self.pass.draw(cmd_buffer, &[lights_offset]);
+  }
+}
+
+// reinhard tonemap of the hdr input attachment at binding 0 into the output color
+pub fn make_tonemap_pass(
+  device: Arc<ash::Device>,
+  descriptor_allocator: Arc<Mutex<DescriptorAllocator>>,
+  render_pass: vk::RenderPass,
+  subpass_idx: u32,
+) -> Result<FullscreenPass, String> {
+  FullscreenPass::new(
+    device,
+    descriptor_allocator,
+    render_pass,
+    subpass_idx,
+    &[input_attachment_binding(0)],
+    TONEMAP_FRAG_SPV,
+  )
+  .map_err(|e| format!("at tonemap pass: {e}"))
+}
@@ -1,34 +1,260 @@
+use crate::{VertMeshPbrPipeline, MATERIAL_SET};
+use mesh_structs::{glam, Mesh};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use transfer_manager::TransferManager;
-use vk_context::auto_drop_wrappers::{AdAllocatedBuffer, AdAllocatedImage};
+use transfer_manager::{image, ColorSpace, TextureUploadOptions, TransferManager};
+use vk_context::ash;
+use vk_context::ash::vk;
+use vk_context::auto_drop_wrappers::{
+  AdAllocatedBuffer, AdAllocatedImage, AdCommandBuffer, AdDescriptorSet, AdSampler,
+};
 use vk_context::gpu_allocator::vulkan::Allocator;
+use vk_context::{SamplerDesc, VkContext};
 
 pub struct VertMesh {
   vert_buffer: AdAllocatedBuffer,
   idx_buffer: AdAllocatedBuffer,
+  idx_count: u32,
 }
 
+impl VertMesh {
+  pub fn from_mesh(
+    mesh: &Mesh,
+    transfer_manager: &TransferManager,
+    allocator: Arc<Mutex<Allocator>>,
+    name: &str,
+  ) -> Result<Self, String> {
+    let indices = mesh.get_draw_index_list();
+    let vert_buffer = transfer_manager
+      .upload_buffer(
+        Arc::clone(&allocator),
+        &mesh.vertices,
+        vk::BufferUsageFlags::VERTEX_BUFFER,
+        &format!("{name}_vert_buffer"),
+      )
+      .map_err(|e| format!("at vertex buffer upload: {e}"))?;
+    let idx_buffer = transfer_manager
+      .upload_buffer(
+        Arc::clone(&allocator),
+        &indices,
+        vk::BufferUsageFlags::INDEX_BUFFER,
+        &format!("{name}_idx_buffer"),
+      )
+      .map_err(|e| format!("at index buffer upload: {e}"))?;
+    Ok(Self { vert_buffer, idx_buffer, idx_count: indices.len() as u32 })
+  }
+
+  pub fn idx_count(&self) -> u32 {
+    self.idx_count
+  }
+
+  /*
+  Binds the vertex and index buffers and records the indexed draw.
+  Pipeline and descriptor sets should be bound before calling.
+   */
+  pub fn draw(&self, cmd_buffer: &AdCommandBuffer) {
+    cmd_buffer.bind_vertex_buffer(0, &[self.vert_buffer.inner], &[0]);
+    cmd_buffer.bind_index_buffer(self.idx_buffer.inner, 0, vk::IndexType::UINT32);
+    cmd_buffer.draw_indexed(self.idx_count, 1, 0, 0, 0);
+  }
+}
+
+// std140 layout of the MaterialFactors uniform block in g_buffer.frag
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PbrMaterialFactors {
+  pub base_color: glam::Vec4,
+  pub emissive: glam::Vec3,
+  pub emissive_strength: f32,
+  pub metallic: f32,
+  pub roughness: f32,
+  pub normal_scale: f32,
+  pub occlusion_strength: f32,
+}
+
+// same defaults as gltf, so factors alone fully describe a material without textures
+impl Default for PbrMaterialFactors {
+  fn default() -> Self {
+    Self {
+      base_color: glam::Vec4::ONE,
+      emissive: glam::Vec3::ZERO,
+      emissive_strength: 1.0,
+      metallic: 1.0,
+      roughness: 1.0,
+      normal_scale: 1.0,
+      occlusion_strength: 1.0,
+    }
+  }
+}
+
+/*
+Texture files of a material, gltf style: metallic in blue and roughness in green of the
+metallic roughness texture, occlusion in red of the occlusion texture.
+Missing textures fall back to 1x1 textures that leave the factors as they are.
+Textures from files get a full mip chain, albedo and emissive are read as sRGB.
+ */
+#[derive(Clone, Copy, Default, Debug)]
+pub struct PbrTextures<'a> {
+  pub albedo: Option<&'a Path>,
+  pub normal: Option<&'a Path>,
+  pub metallic_roughness: Option<&'a Path>,
+  pub occlusion: Option<&'a Path>,
+  pub emissive: Option<&'a Path>,
+}
+
+const WHITE_PIXEL: [u8; 4] = [255, 255, 255, 255];
+// tangent space normal pointing straight out of the surface
+const FLAT_NORMAL_PIXEL: [u8; 4] = [128, 128, 255, 255];
+
+/*
+Material factors and textures bound as descriptor set 1 of the vert mesh pbr pipeline:
+factors uniform at binding 0, then albedo, normal, metallic roughness, occlusion and
+emissive combined image samplers at bindings 1 to 5.
+ */
 pub struct PbrMaterial {
-  image: AdAllocatedImage,
+  device: Arc<ash::Device>,
+  pub descriptor_set: AdDescriptorSet,
+  sampler: Arc<AdSampler>,
+  texture_views: Vec<vk::ImageView>,
+  _textures: Vec<AdAllocatedImage>,
+  _factors_buffer: AdAllocatedBuffer,
 }
 
 impl PbrMaterial {
   pub fn new(
+    pipeline: &VertMeshPbrPipeline,
+    vk_context: &VkContext,
     transfer_manager: &TransferManager,
     allocator: Arc<Mutex<Allocator>>,
     name: &str,
-    path: &Path,
+    textures: &PbrTextures,
+    factors: PbrMaterialFactors,
   ) -> Result<Self, String> {
-    let image = transfer_manager
-      .load_image_from_file(Arc::clone(&allocator), path, name)
-      .map_err(|e| format!("at image upload: {e}"))?;
-    Ok(Self {
-      image,
-    })
+    let device = Arc::clone(&pipeline.device);
+    // only albedo and emissive hold colors, the rest is data read as is
+    let texture_slots = [
+      ("albedo", textures.albedo, WHITE_PIXEL, ColorSpace::Srgb),
+      ("normal", textures.normal, FLAT_NORMAL_PIXEL, ColorSpace::Linear),
+      ("metallic_roughness", textures.metallic_roughness, WHITE_PIXEL, ColorSpace::Linear),
+      ("occlusion", textures.occlusion, WHITE_PIXEL, ColorSpace::Linear),
+      ("emissive", textures.emissive, WHITE_PIXEL, ColorSpace::Srgb),
+    ];
+    // everything is queued into one batch and waited on once
+    let mut loaded_textures = Vec::with_capacity(texture_slots.len());
+    let mut tickets = Vec::with_capacity(texture_slots.len() + 1);
+    for (slot, path, fallback_pixel, color_space) in texture_slots {
+      let texture_name = format!("{name}_{slot}");
+      let (texture, ticket) = match path {
+        Some(path) => {
+          transfer_manager.queue_texture_from_file(
+            Arc::clone(&allocator),
+            path,
+            TextureUploadOptions { generate_mipmaps: true, color_space },
+            &texture_name,
+          )
+        }
+        None => transfer_manager.queue_texture_upload(
+          Arc::clone(&allocator),
+          &image::RgbaImage::from_pixel(1, 1, image::Rgba(fallback_pixel)),
+          TextureUploadOptions { color_space, ..Default::default() },
+          &texture_name,
+        ),
+      }
+      .map_err(|e| format!("at {slot} texture upload: {e}"))?;
+      loaded_textures.push(texture);
+      tickets.push(ticket);
+    }
+
+    let (factors_buffer, factors_ticket) = transfer_manager
+      .queue_buffer_upload(
+        Arc::clone(&allocator),
+        &[factors],
+        vk::BufferUsageFlags::UNIFORM_BUFFER,
+        &format!("{name}_factors"),
+      )
+      .map_err(|e| format!("at material factors upload: {e}"))?;
+    tickets.push(factors_ticket);
+    for ticket in tickets {
+      transfer_manager.wait_for_upload(ticket).map_err(|e| format!("at material upload: {e}"))?;
+    }
+
+    let sampler = vk_context.get_sampler(SamplerDesc::default())?;
+    let descriptor_set = AdDescriptorSet::new(
+      Arc::clone(&pipeline.descriptor_allocator),
+      &pipeline.set_layouts[MATERIAL_SET as usize],
+    )
+    .map_err(|e| format!("at material descriptor set: {e}"))?;
+    // owns the views from here on, early returns clean up on drop
+    let mut material = Self {
+      device,
+      descriptor_set,
+      sampler,
+      texture_views: Vec::with_capacity(loaded_textures.len()),
+      _textures: vec![],
+      _factors_buffer: factors_buffer,
+    };
+    for texture in &loaded_textures {
+      let view = unsafe {
+        material
+          .device
+          .create_image_view(
+            &vk::ImageViewCreateInfo::default()
+              .format(texture.format)
+              .image(texture.inner)
+              .view_type(vk::ImageViewType::TYPE_2D)
+              .components(vk::ComponentMapping::default())
+              .subresource_range(
+                vk::ImageSubresourceRange::default()
+                  .aspect_mask(vk::ImageAspectFlags::COLOR)
+                  .level_count(vk::REMAINING_MIP_LEVELS)
+                  .layer_count(1)
+                  .base_mip_level(0)
+                  .base_array_layer(0),
+              ),
+            None,
+          )
+          .map_err(|e| format!("at {} view create: {e}", texture.name))?
+      };
+      material.texture_views.push(view);
+    }
+    material._textures = loaded_textures;
+
+    let mut writes = material.descriptor_set.write_builder().add_uniform_buffer(
+      0,
+      material._factors_buffer.inner,
+      0,
+      vk::WHOLE_SIZE,
+    );
+    for (view, binding) in material.texture_views.iter().zip(1..) {
+      writes = writes.add_combined_image_sampler(
+        binding,
+        material.sampler.inner,
+        *view,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+      );
+    }
+    writes.write();
+    Ok(material)
+  }
+
+  // binds the material set, the pipeline should be bound before calling
+  pub fn bind(&self, cmd_buffer: &AdCommandBuffer, pipeline: &VertMeshPbrPipeline) {
+    cmd_buffer.bind_descriptor_sets(
+      vk::PipelineBindPoint::GRAPHICS,
+      pipeline.pipeline_layout,
+      MATERIAL_SET,
+      &[self.descriptor_set.inner],
+      &[],
+    );
   }
 }
 
 impl Drop for PbrMaterial {
-  fn drop(&mut self) {}
+  fn drop(&mut self) {
+    unsafe {
+      for view in &self.texture_views {
+        self.device.destroy_image_view(*view, None);
+      }
+    }
+  }
 }
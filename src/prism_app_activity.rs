@@ -1,5 +1,7 @@
 mod window_manager;
 
+use std::collections::HashMap;
+use prism_input::InputManager;
 use window_manager::WindowManager;
 use winit::application::ApplicationHandler;
 use winit::event::WindowEvent;
@@ -7,57 +9,86 @@ use winit::event_loop::ActiveEventLoop;
 use winit::window::{WindowAttributes, WindowId};
 
 pub struct PrismAppActivity {
-  window_manager: Option<WindowManager>,
+  windows: HashMap<WindowId, WindowManager>,
+  // closing it exits the app, other windows just close
+  main_window: Option<WindowId>,
+  // fed the events of every window, only the focused one gets input
+  input: InputManager,
 }
 
 impl PrismAppActivity {
   pub fn new() -> Result<Self, String> {
-    Ok(Self { window_manager: None })
+    Ok(Self { windows: HashMap::new(), main_window: None, input: InputManager::new() })
+  }
+
+  // windows after the first share its render context, each gets its own swapchain
+  pub fn open_window(
+    &mut self,
+    event_loop: &ActiveEventLoop,
+    attributes: WindowAttributes,
+  ) -> Result<WindowId, String> {
+    let window =
+      event_loop.create_window(attributes).map_err(|e| format!("at creating window: {e}"))?;
+    let window_id = window.id();
+    let shared = self.windows.values().next().map(|wm| wm.render_context());
+    let wm = WindowManager::new(window, shared.as_ref())?;
+    self.windows.insert(window_id, wm);
+    Ok(window_id)
   }
 }
 
 impl ApplicationHandler for PrismAppActivity {
+  // windows outlive a suspend, only their surfaces are rebuilt
   fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-    match event_loop.create_window(WindowAttributes::default()) {
-      Ok(w) => {
-        if let Some(mut wm) = self.window_manager.take() {
-          if let Err(e) = wm.refresh_surface() {
-            eprintln!("can't refresh surface: {e}");
-            event_loop.exit()
-          }
-        } else {
-          match WindowManager::new(w) {
-            Ok(wm) => {
-              self.window_manager = Some(wm);
-            }
-            Err(e) => {
-              eprintln!("can't start window mgr: {e}");
-              event_loop.exit()
-            }
-          }
+    if self.main_window.is_some() {
+      for wm in self.windows.values_mut() {
+        if let Err(e) = wm.resume() {
+          eprintln!("can't resume surface: {e}");
+          event_loop.exit()
         }
       }
+      return;
+    }
+    match self.open_window(event_loop, WindowAttributes::default()) {
+      Ok(window_id) => self.main_window = Some(window_id),
       Err(e) => {
-        eprintln!("error creating window: {e}");
+        eprintln!("can't start window mgr: {e}");
         event_loop.exit()
       }
     }
   }
 
+  // surfaces can't be used after this returns on Android
+  fn suspended(&mut self, _event_loop: &ActiveEventLoop) {
+    for wm in self.windows.values_mut() {
+      wm.suspend()
+    }
+  }
+
   fn window_event(
     &mut self,
     event_loop: &ActiveEventLoop,
-    _window_id: WindowId,
+    window_id: WindowId,
     event: WindowEvent,
   ) {
+    self.input.process_event(&event);
     match event {
       WindowEvent::ActivationTokenDone { .. } => {}
-      WindowEvent::Resized(_) => {}
+      WindowEvent::Resized(size) => {
+        if let Some(wm) = self.windows.get_mut(&window_id) {
+          let _ = wm
+            .resize(size.width, size.height)
+            .inspect_err(|e| eprintln!("at window resize: {e}"));
+        }
+      }
       WindowEvent::Moved(_) => {}
+      WindowEvent::CloseRequested if self.main_window != Some(window_id) => {
+        self.windows.remove(&window_id);
+      }
       WindowEvent::CloseRequested => {
         // https://github.com/rust-windowing/winit/issues/3668
         #[cfg(target_os = "macos")]
-        let _ = self.window_manager.take();
+        self.windows.clear();
         event_loop.exit();
       },
       WindowEvent::Destroyed => {}
@@ -80,6 +111,7 @@ impl ApplicationHandler for PrismAppActivity {
       WindowEvent::TouchpadPressure { .. } => {}
       WindowEvent::AxisMotion { .. } => {}
       WindowEvent::Touch(_) => {}
+      // the new inner size isn't applied yet, the Resized that follows carries it
       WindowEvent::ScaleFactorChanged { .. } => {}
       WindowEvent::ThemeChanged(_) => {}
       WindowEvent::Occluded(_) => {}
@@ -88,8 +120,9 @@ impl ApplicationHandler for PrismAppActivity {
   }
 
   fn about_to_wait(&mut self, _event_loop: &ActiveEventLoop) {
-    if let Some(wm) = self.window_manager.as_mut() {
+    for wm in self.windows.values_mut() {
       wm.redraw()
     }
+    self.input.end_frame();
   }
 }
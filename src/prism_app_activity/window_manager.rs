@@ -1,5 +1,5 @@
 use std::sync::Arc;
-use prism_renderer::Renderer;
+use prism_renderer::{RenderContext, Renderer, RendererConfig};
 use winit::window::Window;
 
 pub struct WindowManager {
@@ -8,29 +8,51 @@ pub struct WindowManager {
 }
 
 impl WindowManager {
-  pub fn new(window: Window) -> Result<Self, String> {
+  // shared is the render context of an open window, the first window makes a new one
+  pub fn new(window: Window, shared: Option<&RenderContext>) -> Result<Self, String> {
     let window_size = window.inner_size();
-    let renderer = Renderer::new(&window, window_size.width, window_size.height)?;
+    let renderer = match shared {
+      Some(context) => Renderer::new_sharing(
+        context,
+        &window,
+        window_size.width,
+        window_size.height,
+        RendererConfig::default(),
+      )?,
+      None => Renderer::new(&window, window_size.width, window_size.height)?,
+    };
 
     Ok(Self { window: Arc::new(window), renderer })
   }
 
-  pub fn refresh_surface(&mut self) -> Result<(), String> {
+  pub fn render_context(&self) -> RenderContext {
+    self.renderer.render_context()
+  }
+
+  pub fn suspend(&mut self) {
+    self.renderer.suspend()
+  }
+
+  pub fn resume(&mut self) -> Result<(), String> {
     let window_size = self.window.inner_size();
-    self.renderer.refresh_surface(&self.window, window_size.width, window_size.height)
+    self.renderer.resume(&self.window, window_size.width, window_size.height)
+  }
+
+  pub fn resize(&mut self, width: u32, height: u32) -> Result<(), String> {
+    self.renderer.resize(width, height)
   }
 
   pub fn update_resolution(&mut self) -> Result<(), String> {
     let window_size = self.window.inner_size();
-    self.renderer.resize_swapchain(window_size.width, window_size.height)
+    self.resize(window_size.width, window_size.height)
   }
 
+  // an out of date swapchain is resized here and drawn to from the next redraw on
   pub fn redraw(&mut self) {
     let _ = self.renderer.draw()
-      .map(|x| {
-        if x {
+      .map(|refresh_needed| {
+        if refresh_needed {
           let _ = self.update_resolution().inspect_err(|e| println!("{e}"));
-          self.redraw();
         }
       })
       .inspect_err(|e| println!("at redraw: {e}"));